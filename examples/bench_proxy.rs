@@ -0,0 +1,196 @@
+//! Measures the throughput of watchdawg in reverse proxy mode.
+//!
+//! Start the upstream, then watchdawg with `proxy_address` pointing to it, then the load:
+//! ```shell
+//! cargo run --release --example bench_proxy -- upstream --listen 127.0.0.1:9000
+//! cargo run --release --example bench_proxy -- load --url http://127.0.0.1:8080/ --auth alice:secret
+//! ```
+//! With `upstream --close`, the upstream closes every connection after its response, so watchdawg
+//! makes a new connection for each request as it did without the pool.
+
+use argh::FromArgs;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderName, AUTHORIZATION, COOKIE, HOST, SET_COOKIE},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, Uri,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(FromArgs)]
+#[argh(description = "reverse proxy benchmark")]
+struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Upstream(Upstream),
+    Load(Load),
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "upstream",
+    description = "serve a small response to every request"
+)]
+struct Upstream {
+    #[argh(option, description = "the address to listen on")]
+    listen: String,
+    #[argh(
+        switch,
+        description = "close every connection after its response, so watchdawg has to connect for each request"
+    )]
+    close: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "load",
+    description = "send requests over keep-alive connections and report the throughput"
+)]
+struct Load {
+    #[argh(option, description = "the URL of watchdawg")]
+    url: Uri,
+    #[argh(option, description = "credentials as user:password to log in with")]
+    auth: String,
+    #[argh(option, default = "64", description = "the number of connections")]
+    connections: usize,
+    #[argh(
+        option,
+        default = "10",
+        description = "how long to send requests, in second"
+    )]
+    duration: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match argh::from_env::<Args>().command {
+        Command::Upstream(upstream) => serve(upstream).await,
+        Command::Load(load) => self::load(load).await,
+    }
+}
+
+async fn serve(upstream: Upstream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(&upstream.listen).await?;
+    let accepted = Arc::new(AtomicU64::new(0));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let close = upstream.close;
+        let accepted = accepted.fetch_add(1, Ordering::Relaxed) + 1;
+        if accepted.is_multiple_of(1000) {
+            println!("Accepted {} connections", accepted);
+        }
+        tokio::spawn(async move {
+            let service = service_fn(|_req: Request<Incoming>| async {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"hello"))))
+            });
+            let _ = http1::Builder::new()
+                .keep_alive(!close)
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+async fn load(load: Load) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let authority = load.url.authority().ok_or("URL without host")?.clone();
+    let basic = format!("Basic {}", BASE64_STANDARD.encode(&load.auth));
+
+    // log in once, so that the requests measured carry a session rather than credentials
+    let mut sender = connect(authority.as_str()).await?;
+    let response = sender
+        .send_request(request(
+            &load.url,
+            authority.as_str(),
+            AUTHORIZATION,
+            &basic,
+        ))
+        .await?;
+    let cookie = response
+        .headers()
+        .get(SET_COOKIE)
+        .and_then(|cookie| cookie.to_str().ok())
+        .and_then(|cookie| cookie.split(';').next())
+        .ok_or("Failed to log in")?
+        .to_string();
+
+    let succeeded = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + Duration::from_secs(load.duration);
+    let mut workers = Vec::with_capacity(load.connections);
+    for _ in 0..load.connections {
+        let (url, authority, cookie) = (load.url.clone(), authority.clone(), cookie.clone());
+        let (succeeded, failed) = (succeeded.clone(), failed.clone());
+        workers.push(tokio::spawn(async move {
+            let mut sender = connect(authority.as_str()).await?;
+            while Instant::now() < deadline {
+                let req = request(&url, authority.as_str(), COOKIE, &cookie);
+                match sender.send_request(req).await {
+                    Ok(response) if response.status().is_success() => {
+                        response.into_body().collect().await?;
+                        succeeded.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(response) => {
+                        response.into_body().collect().await?;
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        sender = connect(authority.as_str()).await?;
+                    }
+                }
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        }));
+    }
+    for worker in workers {
+        worker.await??;
+    }
+
+    let succeeded = succeeded.load(Ordering::Relaxed);
+    println!(
+        "{} requests succeeded, {} failed, {:.0} requests/s",
+        succeeded,
+        failed.load(Ordering::Relaxed),
+        succeeded as f64 / load.duration as f64
+    );
+    Ok(())
+}
+
+async fn connect(
+    authority: &str,
+) -> Result<
+    hyper::client::conn::http1::SendRequest<Empty<Bytes>>,
+    Box<dyn std::error::Error + Send + Sync>,
+> {
+    let stream = TcpStream::connect(authority).await?;
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    Ok(sender)
+}
+
+fn request(url: &Uri, authority: &str, name: HeaderName, value: &str) -> Request<Empty<Bytes>> {
+    Request::get(url.path_and_query().map_or("/", |path| path.as_str()))
+        .header(HOST, authority)
+        .header(name, value)
+        .body(Empty::new())
+        .expect("valid request")
+}
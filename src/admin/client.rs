@@ -0,0 +1,104 @@
+use super::{percent_encode, RevokeResult, SessionInfo};
+use crate::session::unix_now;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    body::Bytes,
+    header::{AUTHORIZATION, HOST},
+    Method, Request, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::path::Path;
+use thiserror::Error;
+use tokio::net::UnixStream;
+use tracing::error;
+
+/// Talks to the admin API of a running watchdawg over its Unix socket
+pub struct AdminClient<'a> {
+    socket: &'a Path,
+    token: &'a str,
+}
+
+impl<'a> AdminClient<'a> {
+    pub fn new(socket: &'a Path, token: &'a str) -> Self {
+        Self { socket, token }
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<SessionInfo>, AdminClientError> {
+        let body = self.request(Method::GET, "/sessions").await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    pub async fn revoke_session(&self, session_id: &str) -> Result<usize, AdminClientError> {
+        let path = concat_string::concat_string!("/sessions/", percent_encode(session_id));
+        let body = self.request(Method::DELETE, &path).await?;
+        Ok(serde_json::from_slice::<RevokeResult>(&body)?.revoked)
+    }
+
+    pub async fn revoke_user(&self, user: &str) -> Result<usize, AdminClientError> {
+        let path = concat_string::concat_string!("/users/", percent_encode(user), "/sessions");
+        let body = self.request(Method::DELETE, &path).await?;
+        Ok(serde_json::from_slice::<RevokeResult>(&body)?.revoked)
+    }
+
+    async fn request(&self, method: Method, path: &str) -> Result<Bytes, AdminClientError> {
+        let stream = UnixStream::connect(self.socket).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                error!("Connection failed: {}", err);
+            }
+        });
+
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, "localhost")
+            .header(
+                AUTHORIZATION,
+                concat_string::concat_string!("Bearer ", self.token),
+            )
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(AdminClientError::Status(status));
+        }
+        Ok(resp.into_body().collect().await?.to_bytes())
+    }
+}
+
+/// Print the sessions as a table
+pub fn print_sessions(sessions: &[SessionInfo]) {
+    let now = unix_now();
+    println!(
+        "{:<36}  {:<16}  {:>8}  {:>9}  IP",
+        "ID", "USER", "AGE", "LAST SEEN"
+    );
+    for session in sessions {
+        println!(
+            "{:<36}  {:<16}  {:>7}s  {:>8}s  {}",
+            session.id,
+            session.user,
+            session.age,
+            now.saturating_sub(session.last_seen),
+            session
+                .ip
+                .map(|ip| ip.to_string())
+                .unwrap_or("-".to_string()),
+        );
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AdminClientError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Admin API responded with `{0}`")]
+    Status(StatusCode),
+}
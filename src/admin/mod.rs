@@ -0,0 +1,355 @@
+use crate::{
+    service::auth_reverse_proxy::upstream::{Balance, ServerStats, Upstream},
+    session::{unix_now, SessionError, SessionManager},
+    utils::{empty, full},
+};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::Service,
+    Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{future::Future, net::IpAddr, pin::Pin, sync::Arc};
+use tracing::{error, warn};
+
+pub mod client;
+pub mod server;
+
+/// A session as listed by the admin API
+#[derive(Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user: String,
+    pub created_at: u64,
+    pub age: u64,
+    pub last_seen: u64,
+    pub ip: Option<IpAddr>,
+}
+
+/// The reply of the revoke endpoints
+#[derive(Serialize, Deserialize)]
+pub struct RevokeResult {
+    pub revoked: usize,
+}
+
+/// The health of the session store as reported by the admin API
+#[derive(Serialize, Deserialize)]
+pub struct HealthInfo {
+    pub healthy: bool,
+    /// When the health last changed
+    pub since: u64,
+    /// Number of failed store operations since startup
+    pub failures: u64,
+}
+
+/// The servers of an upstream as reported by the admin API
+#[derive(Serialize, Deserialize)]
+pub struct UpstreamInfo {
+    pub balance: Balance,
+    pub servers: Vec<ServerStats>,
+}
+
+/// Session administration API, every request must carry `Authorization: Bearer <token>`
+///
+/// - `GET /sessions` list all live sessions
+/// - `DELETE /sessions/{id}` revoke a session
+/// - `DELETE /users/{user}/sessions` revoke all sessions of a user
+/// - `GET /health` the health of the session store
+/// - `GET /upstreams` the health and request counts of the upstream servers
+#[derive(Clone)]
+pub struct AdminSvc {
+    inner: Arc<AdminSvcImpl>,
+}
+
+struct AdminSvcImpl {
+    session_manager: Arc<SessionManager>,
+    upstreams: Vec<Arc<Upstream>>,
+    token: String,
+}
+
+impl AdminSvc {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        upstreams: Vec<Arc<Upstream>>,
+        token: impl Into<String>,
+    ) -> Self {
+        let inner = AdminSvcImpl {
+            session_manager,
+            upstreams,
+            token: token.into(),
+        };
+        Self {
+            inner: inner.into(),
+        }
+    }
+
+    fn is_authorized<B>(&self, req: &Request<B>) -> bool {
+        let Some(token) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.as_bytes().strip_prefix(b"Bearer "))
+        else {
+            return false;
+        };
+        constant_time_eq(token, self.inner.token.as_bytes())
+    }
+
+    fn handle(&self, method: &Method, path: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let session_manager = &self.inner.session_manager;
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .map(percent_decode)
+            .collect::<Vec<_>>();
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["sessions"]) => {
+                let sessions = match session_manager.list_sessions() {
+                    Ok(sessions) => sessions,
+                    Err(err) => return session_error(err),
+                };
+                let now = unix_now();
+                let sessions = sessions
+                    .into_iter()
+                    .map(|(id, session)| SessionInfo {
+                        id,
+                        age: now.saturating_sub(session.created_at),
+                        user: session.user,
+                        created_at: session.created_at,
+                        last_seen: session.last_seen,
+                        ip: session.ip,
+                    })
+                    .collect::<Vec<_>>();
+                json(&sessions)
+            }
+            (&Method::DELETE, ["sessions", session_id]) => {
+                match session_manager.revoke_session(session_id) {
+                    Ok(true) => json(&RevokeResult { revoked: 1 }),
+                    Ok(false) => status(StatusCode::NOT_FOUND),
+                    Err(err) => session_error(err.into()),
+                }
+            }
+            (&Method::DELETE, ["users", user, "sessions"]) => {
+                match session_manager.revoke_user(user) {
+                    Ok(revoked) => json(&RevokeResult { revoked }),
+                    Err(err) => session_error(err),
+                }
+            }
+            (&Method::GET, ["health"]) => {
+                let Some(health) = &session_manager.health else {
+                    return status(StatusCode::NOT_IMPLEMENTED);
+                };
+                json(&HealthInfo {
+                    healthy: health.is_healthy(),
+                    since: health.since(),
+                    failures: health.failures(),
+                })
+            }
+            (&Method::GET, ["upstreams"]) => {
+                let upstreams = self
+                    .inner
+                    .upstreams
+                    .iter()
+                    .map(|upstream| UpstreamInfo {
+                        balance: upstream.balance(),
+                        servers: upstream
+                            .servers()
+                            .iter()
+                            .map(|server| server.stats())
+                            .collect(),
+                    })
+                    .collect::<Vec<_>>();
+                json(&upstreams)
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+impl Service<Request<Incoming>> for AdminSvc {
+    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let resp = match self.is_authorized(&req) {
+            true => self.handle(req.method(), req.uri().path()),
+            false => status(StatusCode::UNAUTHORIZED),
+        };
+        Box::pin(async move { Ok(resp) })
+    }
+}
+
+fn status(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder().status(status).body(empty()).unwrap()
+}
+
+fn session_error(err: SessionError) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match err {
+        SessionError::Unsupported => status(StatusCode::NOT_IMPLEMENTED),
+        SessionError::Store(err) if err.is_unavailable() => {
+            warn!("{}", err);
+            status(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        SessionError::Store(err) => {
+            error!("{}", err);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        SessionError::TooManySessions => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn json(value: &impl Serialize) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(full(body))
+            .unwrap(),
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Decode `%XX` escapes in a path segment
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encode a path segment, the inverse of [`percent_decode`]
+pub fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::set_cookie::SessionCookie,
+        session::{memory::MemoryStore, Session, SessionStore},
+    };
+    use http_body_util::BodyExt;
+
+    fn admin(store: Arc<MemoryStore>) -> AdminSvc {
+        let cookie = SessionCookie::new("session_id", Default::default(), 3600).unwrap();
+        let session_manager = SessionManager::new(cookie, store, None, 3600);
+        AdminSvc::new(session_manager.into(), Vec::new(), "secret")
+    }
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri("/sessions");
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+        req.body(()).unwrap()
+    }
+
+    async fn body(resp: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        resp.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        let admin = admin(Arc::new(MemoryStore::new()));
+        assert!(admin.is_authorized(&request(Some("Bearer secret"))));
+        assert!(!admin.is_authorized(&request(None)));
+        assert!(!admin.is_authorized(&request(Some("Bearer other"))));
+        assert!(!admin.is_authorized(&request(Some("Bearer secret2"))));
+        assert!(!admin.is_authorized(&request(Some("Bearer "))));
+        assert!(!admin.is_authorized(&request(Some("bearer secret"))));
+        assert!(!admin.is_authorized(&request(Some("Basic c2VjcmV0"))));
+    }
+
+    #[tokio::test]
+    async fn lists_and_revokes_sessions() {
+        let store = Arc::new(MemoryStore::new());
+        let admin = admin(store.clone());
+        let now = unix_now();
+        store
+            .save("first", &Session::new("a b", None, now))
+            .unwrap();
+        store
+            .save("second", &Session::new("a b", None, now))
+            .unwrap();
+        store
+            .save("third", &Session::new("carol", None, now))
+            .unwrap();
+
+        let resp = admin.handle(&Method::GET, "/sessions");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let sessions: Vec<SessionInfo> = serde_json::from_slice(&body(resp).await).unwrap();
+        assert_eq!(sessions.len(), 3);
+
+        let resp = admin.handle(&Method::DELETE, "/sessions/third");
+        let revoked: RevokeResult = serde_json::from_slice(&body(resp).await).unwrap();
+        assert_eq!(revoked.revoked, 1);
+        assert!(store.load("third").unwrap().is_none());
+        let resp = admin.handle(&Method::DELETE, "/sessions/third");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = admin.handle(
+            &Method::DELETE,
+            &format!("/users/{}/sessions", percent_encode("a b")),
+        );
+        let revoked: RevokeResult = serde_json::from_slice(&body(resp).await).unwrap();
+        assert_eq!(revoked.revoked, 2);
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_routes_are_not_found() {
+        let admin = admin(Arc::new(MemoryStore::new()));
+        let status = |method, path| admin.handle(&method, path).status();
+        assert_eq!(status(Method::GET, "/"), StatusCode::NOT_FOUND);
+        assert_eq!(status(Method::POST, "/sessions"), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(Method::GET, "/sessions/first"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(Method::DELETE, "/users/alice"),
+            StatusCode::NOT_FOUND
+        );
+        // the store is not monitored
+        assert_eq!(status(Method::GET, "/health"), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(status(Method::GET, "/upstreams"), StatusCode::OK);
+    }
+
+    #[test]
+    fn decodes_segments() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        let user = "ünïcode user/1";
+        assert_eq!(percent_decode(&percent_encode(user)), user);
+    }
+}
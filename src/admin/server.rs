@@ -0,0 +1,59 @@
+use super::AdminSvc;
+use hyper_util::rt::TokioIo;
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tokio::net::UnixListener;
+use tracing::{error, info};
+
+/// Serves the admin API on a Unix socket, which is only accessible to the owner
+pub struct AdminServer {
+    socket: PathBuf,
+    service: AdminSvc,
+}
+
+impl AdminServer {
+    pub fn new(socket: impl Into<PathBuf>, service: AdminSvc) -> Self {
+        Self {
+            socket: socket.into(),
+            service,
+        }
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Bind the socket, a stale socket file left by a previous run is removed
+    pub fn bind(&self) -> std::io::Result<UnixListener> {
+        if self.socket.exists() {
+            std::fs::remove_file(&self.socket)?;
+        }
+        let listener = UnixListener::bind(&self.socket)?;
+        std::fs::set_permissions(&self.socket, std::fs::Permissions::from_mode(0o600))?;
+        info!("Admin API listening on {}", self.socket.display());
+        Ok(listener)
+    }
+
+    pub async fn serve(&self, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _addr)) => stream,
+                Err(err) => {
+                    error!("Failed to accept admin connection: {}", err);
+                    continue;
+                }
+            };
+            let service = self.service.clone();
+            tokio::spawn(async move {
+                if let Err(err) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    error!("Failed to handle admin connection: {}", err);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+/// Group membership of users
+#[derive(Default)]
+pub struct Groups {
+    by_user: HashMap<String, Vec<String>>,
+}
+
+impl Groups {
+    /// Create from a map of group name to its members
+    pub fn new(groups: &HashMap<String, Vec<String>>) -> Self {
+        let mut by_user: HashMap<String, Vec<String>> = HashMap::new();
+        for (group, members) in groups {
+            for member in members {
+                by_user
+                    .entry(member.clone())
+                    .or_default()
+                    .push(group.clone());
+            }
+        }
+        Self { by_user }
+    }
+
+    /// The groups the user belongs to
+    pub fn of(&self, user: &str) -> &[String] {
+        self.by_user.get(user).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
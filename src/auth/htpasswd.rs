@@ -1,59 +1,257 @@
+use super::{Authenticator, CredentialEvent, CredentialListener};
 use base64::{prelude::BASE64_STANDARD, Engine};
 use bcrypt::verify;
 use dashmap::DashMap;
-use std::{io::BufRead, path::Path};
-use super::Authenticator;
+use std::{
+    collections::HashMap,
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
 
 pub struct HtpasswdAuth {
+    path: PathBuf,
     credentials: DashMap<String, String>,
+    modified: Mutex<Option<SystemTime>>,
+    listeners: Mutex<Vec<CredentialListener>>,
 }
 
 impl HtpasswdAuth {
     pub fn new(htpasswd_path: impl AsRef<Path>) -> std::io::Result<Self> {
-        let file = std::fs::File::open(htpasswd_path)?;
-        let reader = std::io::BufReader::new(file);
-        let credentials = DashMap::new();
+        let path = htpasswd_path.as_ref().to_path_buf();
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        let credentials = read_htpasswd(&path)?.into_iter().collect();
+        Ok(Self {
+            path,
+            credentials,
+            modified: Mutex::new(modified),
+            listeners: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Read the htpasswd file again, listeners are notified of every user removed or whose hash
+    /// changed
+    pub fn reload(&self) -> std::io::Result<()> {
+        let new_credentials = read_htpasswd(&self.path)?;
+
+        let mut events = Vec::new();
+        self.credentials
+            .retain(|user, hash| match new_credentials.get(user) {
+                Some(new_hash) if new_hash == hash => true,
+                Some(_) => {
+                    events.push(CredentialEvent::Changed(user.clone()));
+                    true
+                }
+                None => {
+                    events.push(CredentialEvent::Removed(user.clone()));
+                    false
+                }
+            });
+        for (user, hash) in new_credentials {
+            self.credentials.insert(user, hash);
+        }
 
-        for line in reader.lines() {
-            let line = line?;
-            let mut parts = line.split(':');
+        info!("Reloaded {}", self.path.display());
+        let listeners = self.listeners.lock().unwrap();
+        for event in events {
+            info!("Credentials changed: {:?}", event);
+            for listener in listeners.iter() {
+                listener(&event);
+            }
+        }
+        Ok(())
+    }
 
-            let name = parts.next();
-            let password = parts.next();
+    /// Reload the htpasswd file if it was modified since last time
+    fn reload_if_modified(&self) -> std::io::Result<()> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return Ok(());
+        }
+        *last_modified = modified;
+        drop(last_modified);
+        self.reload()
+    }
 
-            if let (Some(name), Some(password)) = (name, password) {
-                credentials.insert(name.to_string(), password.to_string());
+    /// Check the htpasswd file for modification every `interval` in a background thread, the
+    /// thread stops once the authenticator is dropped
+    pub fn spawn_reload_task(self: &Arc<Self>, interval: Duration) {
+        let auth: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(auth) = auth.upgrade() else {
+                break;
+            };
+            if let Err(err) = auth.reload_if_modified() {
+                error!("Failed to reload {}: {}", auth.path.display(), err);
             }
+        });
+    }
+}
+
+fn read_htpasswd(path: &Path) -> std::io::Result<HashMap<String, String>> {
+    let file = std::fs::File::open(path)?;
+    let reader = std::io::BufReader::new(file);
+    let mut credentials = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut parts = line.split(':');
+
+        let name = parts.next();
+        let password = parts.next();
+
+        if let (Some(name), Some(password)) = (name, password) {
+            credentials.insert(name.to_string(), password.to_string());
         }
-        Ok(Self { credentials })
     }
+    Ok(credentials)
 }
 
 impl Authenticator for HtpasswdAuth {
-    fn is_auth_header_valid(&self, auth_header: &[u8]) -> bool {
-        let base64_credentials = if auth_header.starts_with(b"Basic ") {
-            &auth_header[6..]
-        } else {
-            return false;
-        };
-        let Ok(credentials) = BASE64_STANDARD.decode(base64_credentials) else {
-            return false;
-        };
+    fn authenticate(&self, auth_header: &[u8]) -> Option<String> {
+        let base64_credentials = auth_header.strip_prefix(b"Basic ")?;
+        let credentials = BASE64_STANDARD.decode(base64_credentials).ok()?;
 
         let mut parts = credentials.split(|&byte| byte == b':');
 
-        let username = parts.next();
-        let password = parts.next();
+        let username = parts
+            .next()
+            .and_then(|username| std::str::from_utf8(username).ok())?;
+        let password = parts.next()?;
 
-        if let Some(password) = password {
-            if let Some(res) = username
-                .and_then(|username| std::str::from_utf8(username).ok())
-                .and_then(|username| self.credentials.get(username))
-                .and_then(|hash| verify(password, hash.as_str()).ok())
-            {
-                return res;
-            }
+        let hash = self.credentials.get(username)?;
+        match verify(password, hash.as_str()) {
+            Ok(true) => Some(username.to_string()),
+            _ => None,
         }
-        false
+    }
+
+    fn subscribe(&self, listener: CredentialListener) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// An htpasswd file which is removed once dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(users: &[(&str, &str)]) -> Self {
+            let file = Self(std::env::temp_dir().join(format!("watchdawg-{}", Uuid::new_v4())));
+            file.write(users);
+            file
+        }
+
+        fn write(&self, users: &[(&str, &str)]) {
+            let lines = users
+                .iter()
+                .map(|(user, password)| {
+                    format!("{}:{}\n", user, bcrypt::hash(password, 4).unwrap())
+                })
+                .collect::<String>();
+            std::fs::write(&self.0, lines).unwrap();
+        }
+
+        fn touch(&self, modified: SystemTime) {
+            let file = std::fs::File::options().write(true).open(&self.0).unwrap();
+            file.set_modified(modified).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn basic(user: &str, password: &str) -> Vec<u8> {
+        let credentials = BASE64_STANDARD.encode(format!("{}:{}", user, password));
+        format!("Basic {}", credentials).into_bytes()
+    }
+
+    fn subscribe(auth: &HtpasswdAuth) -> Arc<Mutex<Vec<(&'static str, String)>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        auth.subscribe(Box::new(move |event| {
+            let kind = match event {
+                CredentialEvent::Removed(_) => "removed",
+                CredentialEvent::Changed(_) => "changed",
+            };
+            seen.lock().unwrap().push((kind, event.user().to_string()));
+        }));
+        events
+    }
+
+    #[test]
+    fn authenticates() {
+        let file = TempFile::new(&[("alice", "secret")]);
+        let auth = HtpasswdAuth::new(&file.0).unwrap();
+        assert_eq!(
+            auth.authenticate(&basic("alice", "secret")).as_deref(),
+            Some("alice")
+        );
+        assert!(auth.authenticate(&basic("alice", "wrong")).is_none());
+        assert!(auth.authenticate(&basic("bob", "secret")).is_none());
+        assert!(auth.authenticate(b"Bearer secret").is_none());
+    }
+
+    #[test]
+    fn reload_notifies_changed_and_removed_users() {
+        let file = TempFile::new(&[("alice", "secret"), ("bob", "secret"), ("carol", "secret")]);
+        let auth = HtpasswdAuth::new(&file.0).unwrap();
+        let events = subscribe(&auth);
+
+        let alice = std::fs::read_to_string(&file.0)
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with("alice:"))
+            .unwrap()
+            .to_string();
+        let bob = format!("bob:{}\n", bcrypt::hash("changed", 4).unwrap());
+        let dave = format!("dave:{}\n", bcrypt::hash("secret", 4).unwrap());
+        std::fs::write(&file.0, format!("{}\n{}{}", alice, bob, dave)).unwrap();
+        auth.reload().unwrap();
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(
+            events,
+            [
+                ("changed", "bob".to_string()),
+                ("removed", "carol".to_string())
+            ]
+        );
+        assert!(auth.authenticate(&basic("alice", "secret")).is_some());
+        assert!(auth.authenticate(&basic("bob", "secret")).is_none());
+        assert!(auth.authenticate(&basic("bob", "changed")).is_some());
+        assert!(auth.authenticate(&basic("carol", "secret")).is_none());
+        assert!(auth.authenticate(&basic("dave", "secret")).is_some());
+    }
+
+    #[test]
+    fn reloads_only_when_modified() {
+        let file = TempFile::new(&[("alice", "secret")]);
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        file.touch(modified);
+        let auth = HtpasswdAuth::new(&file.0).unwrap();
+        let events = subscribe(&auth);
+
+        file.write(&[]);
+        file.touch(modified);
+        auth.reload_if_modified().unwrap();
+        assert!(auth.authenticate(&basic("alice", "secret")).is_some());
+
+        file.touch(modified + Duration::from_secs(1));
+        auth.reload_if_modified().unwrap();
+        assert!(auth.authenticate(&basic("alice", "secret")).is_none());
+        assert_eq!(*events.lock().unwrap(), [("removed", "alice".to_string())]);
     }
 }
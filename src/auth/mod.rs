@@ -1,5 +1,28 @@
+pub mod groups;
 pub mod htpasswd;
 
 pub trait Authenticator {
-    fn is_auth_header_valid(&self, header: &[u8]) -> bool;
+    /// Return the user name if the `Authorization` header carries valid credentials
+    fn authenticate(&self, header: &[u8]) -> Option<String>;
+    /// Register a listener to be notified when credentials of a user are changed or removed
+    fn subscribe(&self, _listener: CredentialListener) {}
+}
+
+pub type CredentialListener = Box<dyn Fn(&CredentialEvent) + Send + Sync>;
+
+/// The credentials of a user were changed
+#[derive(Debug)]
+pub enum CredentialEvent {
+    /// The user no longer exists
+    Removed(String),
+    /// The password of the user was changed
+    Changed(String),
+}
+
+impl CredentialEvent {
+    pub fn user(&self) -> &str {
+        match self {
+            CredentialEvent::Removed(user) | CredentialEvent::Changed(user) => user,
+        }
+    }
 }
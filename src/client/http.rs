@@ -1,41 +1,68 @@
-use crate::client::{ProxyClient, ProxyClientError};
+use crate::client::{
+    get_status, pool::ConnectionPool, resolve::Destination, send_upgrade, to_http1, ProxyClient,
+    ProxyClientError,
+};
 use async_trait::async_trait;
-use http_body_util::{combinators::BoxBody, BodyExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
 use hyper::{
     body::{Bytes, Incoming},
-    Request, Response,
+    header::HeaderValue,
+    Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
 use rustls_pki_types::ServerName;
-use std::net::SocketAddr;
-use tokio::net::TcpStream;
-use tracing::error;
+use std::sync::Arc;
 
 const HTTP_DEFAULT_PORT: u16 = 80;
 
-pub struct HttpClient;
+pub struct HttpClient {
+    pool: Arc<ConnectionPool>,
+}
+
+impl HttpClient {
+    pub fn new(pool: Arc<ConnectionPool>) -> Self {
+        Self { pool }
+    }
+}
 
 #[async_trait]
 impl ProxyClient for HttpClient {
     async fn proxy_request(
         &self,
-        dest: SocketAddr,
-        _domain: ServerName<'static>,
-        req: Request<Incoming>,
+        dest: &Destination,
+        domain: ServerName<'static>,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError> {
-        let stream = TcpStream::connect(dest).await?;
-
-        let io = TokioIo::new(stream);
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                error!("Connection failed: {}", err);
-            }
-        });
-
-        let (parts, body) = sender.send_request(req).await?.into_parts();
+        to_http1(&mut req);
+        let connect = || async move { Ok(TokioIo::new(dest.connect().await?)) };
+        let (parts, body) = self
+            .pool
+            .send_http1(dest.authority().clone(), domain, req, connect)
+            .await?
+            .into_parts();
         Ok(Response::from_parts(parts, body.boxed()))
     }
+    async fn health_check(
+        &self,
+        dest: &Destination,
+        _domain: ServerName<'static>,
+        host: HeaderValue,
+        path: &str,
+    ) -> Result<StatusCode, ProxyClientError> {
+        let io = TokioIo::new(dest.connect().await?);
+        get_status(io, false, "http", host, path).await
+    }
+    async fn upgrade(
+        &self,
+        dest: &Destination,
+        _domain: ServerName<'static>,
+        req: Request<Empty<Bytes>>,
+    ) -> Result<Response<Incoming>, ProxyClientError> {
+        let socket = self.pool.connect(|| dest.connect()).await?;
+        self.pool
+            .read(send_upgrade(TokioIo::new(socket), req))
+            .await
+    }
     fn default_port(&self) -> u16 {
         HTTP_DEFAULT_PORT
     }
@@ -1,36 +1,49 @@
-use crate::client::ProxyClientError;
+use crate::client::{
+    get_status, pool::ConnectionPool, resolve::Destination, send_upgrade, ProxyClientError,
+};
 use async_trait::async_trait;
-use http_body_util::{combinators::BoxBody, BodyExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
 use hyper::{
     body::{Bytes, Incoming},
-    Request, Response, Version,
+    header::HeaderValue,
+    Request, Response, StatusCode,
 };
 use hyper_rustls::ConfigBuilderExt;
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::rt::TokioIo;
 use rustls_pki_types::ServerName;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::net::TcpStream;
-use tracing::{debug, error};
+use std::sync::Arc;
+use tracing::debug;
 
 const HTTPS_DEFAULT_PORT: u16 = 443;
 
 pub struct HttpsClient {
     tls_connector: tokio_rustls::TlsConnector,
+    /// Only offers HTTP/1.1, which upgrade requests are sent with
+    upgrade_connector: tokio_rustls::TlsConnector,
+    pool: Arc<ConnectionPool>,
 }
 
 impl HttpsClient {
-    pub fn new() -> std::io::Result<Self> {
+    pub fn new(pool: Arc<ConnectionPool>) -> std::io::Result<Self> {
         let mut client_config = rustls::client::ClientConfig::builder()
             .with_native_roots()?
             .with_no_client_auth();
 
+        let mut upgrade_config = client_config.clone();
+        upgrade_config.alpn_protocols.push(b"http/1.1".to_vec());
+        let upgrade_connector = tokio_rustls::TlsConnector::from(Arc::new(upgrade_config));
+
         client_config.alpn_protocols.push(b"h2".to_vec());
         client_config.alpn_protocols.push(b"http/1.1".to_vec());
         client_config.alpn_protocols.push(b"http/1.0".to_vec());
 
         let tls_connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
 
-        Ok(Self { tls_connector })
+        Ok(Self {
+            tls_connector,
+            upgrade_connector,
+            pool,
+        })
     }
 }
 
@@ -38,47 +51,74 @@ impl HttpsClient {
 impl crate::client::ProxyClient for HttpsClient {
     async fn proxy_request(
         &self,
-        dest: SocketAddr,
+        dest: &Destination,
         domain: ServerName<'static>,
-        req: Request<Incoming>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError> {
         debug!("Forwarding request: {:#?}", req);
 
-        let socket = TcpStream::connect(dest).await?;
-        let stream = self.tls_connector.connect(domain, socket).await?;
-        let io = TokioIo::new(stream);
+        let tls_connector = self.tls_connector.clone();
+        let server_name = domain.clone();
+        // the protocol follows what the upstream chose by ALPN, not the version of the client
+        let connect = || async move {
+            let socket = dest.connect().await?;
+            let stream = tls_connector
+                .connect(server_name, socket)
+                .await
+                .map_err(ProxyClientError::Tls)?;
+            let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+            Ok((TokioIo::new(stream), http2))
+        };
 
-        let http_version = req.version();
-        let (parts, body) = match http_version {
-            Version::HTTP_10 | Version::HTTP_11 => {
-                let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-                tokio::task::spawn(async move {
-                    if let Err(err) = conn.await {
-                        error!("Connection failed: {}", err);
-                    }
-                });
+        let (parts, body) = self
+            .pool
+            .send_negotiated(dest.authority().clone(), domain, req, connect)
+            .await?
+            .into_parts();
 
-                sender.send_request(req).await?.into_parts()
-            }
-            Version::HTTP_2 => {
-                let (mut sender, conn) =
-                    hyper::client::conn::http2::handshake(TokioExecutor::new(), io).await?;
-                tokio::task::spawn(async move {
-                    if let Err(err) = conn.await {
-                        error!("Connection failed: {}", err);
-                    }
-                });
+        Ok(Response::from_parts(parts, body.boxed()))
+    }
 
-                sender.send_request(req).await?.into_parts()
-            }
-            _ => return Err(ProxyClientError::NotSupportHttpVersion(http_version))
-        };
+    async fn health_check(
+        &self,
+        dest: &Destination,
+        domain: ServerName<'static>,
+        host: HeaderValue,
+        path: &str,
+    ) -> Result<StatusCode, ProxyClientError> {
+        let socket = dest.connect().await?;
+        let stream = self
+            .tls_connector
+            .connect(domain, socket)
+            .await
+            .map_err(ProxyClientError::Tls)?;
+        let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+        get_status(TokioIo::new(stream), http2, "https", host, path).await
+    }
 
-        Ok(Response::from_parts(parts, body.boxed()))
+    async fn upgrade(
+        &self,
+        dest: &Destination,
+        domain: ServerName<'static>,
+        req: Request<Empty<Bytes>>,
+    ) -> Result<Response<Incoming>, ProxyClientError> {
+        let connector = self.upgrade_connector.clone();
+        let stream = self
+            .pool
+            .connect(|| async move {
+                let socket = dest.connect().await?;
+                connector
+                    .connect(domain, socket)
+                    .await
+                    .map_err(ProxyClientError::Tls)
+            })
+            .await?;
+        self.pool
+            .read(send_upgrade(TokioIo::new(stream), req))
+            .await
     }
 
     fn default_port(&self) -> u16 {
         HTTPS_DEFAULT_PORT
     }
 }
-
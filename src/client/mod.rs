@@ -1,37 +1,262 @@
 use async_trait::async_trait;
-use http_body_util::combinators::BoxBody;
+use http_body_util::{combinators::BoxBody, Empty};
 use hyper::{
     body::{Bytes, Incoming},
-    Request, Response, Version,
+    header::{HeaderValue, CONNECTION, HOST},
+    http::uri::{Authority, PathAndQuery, Scheme},
+    Request, Response, StatusCode, Uri, Version,
 };
+use hyper_util::rt::TokioExecutor;
+use pool::ConnectionPool;
+use resolve::{Destination, Resolver};
 use rustls_pki_types::ServerName;
-use std::net::SocketAddr;
+use std::{io::ErrorKind, sync::Arc};
 use thiserror::Error;
+use tracing::error;
 
 pub mod http;
 pub mod https;
+pub mod pool;
+pub mod resolve;
 
 #[async_trait]
 pub trait ProxyClient {
     async fn proxy_request(
         &self,
-        addr: SocketAddr,
+        dest: &Destination,
         domain: ServerName<'static>,
-        req: Request<Incoming>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError>;
+    /// Request `path` from the server over a new connection and return the status of the response
+    async fn health_check(
+        &self,
+        dest: &Destination,
+        domain: ServerName<'static>,
+        host: HeaderValue,
+        path: &str,
+    ) -> Result<StatusCode, ProxyClientError>;
+    /// Send an upgrade request over a new HTTP/1.1 connection, which is handed over to the
+    /// upgraded protocol instead of being pooled
+    async fn upgrade(
+        &self,
+        dest: &Destination,
+        domain: ServerName<'static>,
+        req: Request<Empty<Bytes>>,
+    ) -> Result<Response<Incoming>, ProxyClientError>;
     fn default_port(&self) -> u16;
 }
 
+/// Send `GET path` over `io` and return the status of the response
+async fn get_status<T>(
+    io: T,
+    http2: bool,
+    scheme: &str,
+    host: HeaderValue,
+    path: &str,
+) -> Result<StatusCode, ProxyClientError>
+where
+    T: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let response = match http2 {
+        true => {
+            let uri = format!("{}://{}{}", scheme, host.to_str().unwrap_or_default(), path);
+            let req = Request::get(uri).body(Empty::<Bytes>::new())?;
+            let (mut sender, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), io).await?;
+            tokio::task::spawn(conn);
+            sender.send_request(req).await?
+        }
+        false => {
+            let req = Request::get(path)
+                .header(HOST, host)
+                .header(CONNECTION, "close")
+                .body(Empty::<Bytes>::new())?;
+            let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+            tokio::task::spawn(conn);
+            sender.send_request(req).await?
+        }
+    };
+    Ok(response.status())
+}
+
+/// Prepare a request to send over HTTP/1.1, where the URI is only the path and the host of a
+/// request in HTTP/2 form goes to `Host`
+fn to_http1<B>(req: &mut Request<B>) {
+    *req.version_mut() = Version::HTTP_11;
+    if let Some(authority) = req.uri().authority() {
+        if !req.headers().contains_key(HOST) {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+        let path = req.uri().path_and_query().cloned();
+        *req.uri_mut() = path.map_or_else(|| Uri::from_static("/"), Uri::from);
+    }
+}
+
+/// Prepare a request to send over HTTP/2, where the host is in the URI instead of `Host`
+fn to_http2<B>(req: &mut Request<B>, scheme: Scheme) -> Result<(), ProxyClientError> {
+    *req.version_mut() = Version::HTTP_2;
+    let Some(host) = req.headers_mut().remove(HOST) else {
+        return Ok(());
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.scheme = Some(scheme);
+    parts.authority = Some(Authority::try_from(host.as_bytes()).map_err(hyper::http::Error::from)?);
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some(PathAndQuery::from_static("/"));
+    }
+    *req.uri_mut() = Uri::from_parts(parts).map_err(hyper::http::Error::from)?;
+    Ok(())
+}
+
+/// Send the upgrade request `req` over `io`, the connection is kept for the upgraded protocol
+async fn send_upgrade<T>(
+    io: T,
+    req: Request<Empty<Bytes>>,
+) -> Result<Response<Incoming>, ProxyClientError>
+where
+    T: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.with_upgrades().await {
+            error!("Connection failed: {}", err);
+        }
+    });
+    Ok(sender.send_request(req).await?)
+}
+
+/// Gives the client for each upstream, the clients share one connection pool and resolver
+pub struct ProxyClients {
+    pool: Arc<ConnectionPool>,
+    resolver: Arc<Resolver>,
+    /// Whether an address without scheme uses HTTPS
+    default_https: bool,
+    http: Option<Arc<http::HttpClient>>,
+    https: Option<Arc<https::HttpsClient>>,
+}
+
+impl ProxyClients {
+    pub fn new(pool: Arc<ConnectionPool>, resolver: Arc<Resolver>, default_https: bool) -> Self {
+        Self {
+            pool,
+            resolver,
+            default_https,
+            http: None,
+            https: None,
+        }
+    }
+
+    /// The client for `dest`, which is HTTPS for `https://` and HTTP for `http://`
+    pub fn for_address(
+        &mut self,
+        dest: &str,
+    ) -> std::io::Result<Arc<dyn ProxyClient + Send + Sync>> {
+        let https = match dest.split_once("://") {
+            Some((scheme, _)) => scheme.eq_ignore_ascii_case("https"),
+            None => self.default_https,
+        };
+        let client: Arc<dyn ProxyClient + Send + Sync> = match https {
+            true => match &self.https {
+                Some(client) => client.clone(),
+                None => self
+                    .https
+                    .insert(Arc::new(https::HttpsClient::new(self.pool.clone())?))
+                    .clone(),
+            },
+            false => self
+                .http
+                .get_or_insert_with(|| Arc::new(http::HttpClient::new(self.pool.clone())))
+                .clone(),
+        };
+        Ok(client)
+    }
+
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ProxyClientError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
+    #[error("Failed to connect: {0}")]
+    Connect(#[source] std::io::Error),
+
+    #[error("Timed out connecting")]
+    ConnectTimeout,
+
+    #[error("Failed to resolve: {0}")]
+    Resolve(#[source] hickory_resolver::error::ResolveError),
+
+    #[error("TLS handshake failed: {0}")]
+    Tls(#[source] std::io::Error),
+
+    #[error("Timed out waiting for the response")]
+    ReadTimeout,
+
+    #[error("Timed out waiting for a free connection")]
+    PoolExhausted,
+
+    #[error("Timed out forwarding the request")]
+    Timeout,
+
+    #[error("Timed out waiting for the response body")]
+    BodyTimeout,
+
     // #[error(transparent)]
     // Tls(#[from] native_tls::Error),
     #[error(transparent)]
     Hyper(#[from] hyper::Error),
 
-    #[error("HTTP version `{0:?}` not supported")]
-    NotSupportHttpVersion(Version),
+    #[error(transparent)]
+    Http(#[from] hyper::http::Error),
+}
+
+impl ProxyClientError {
+    /// The request was not sent since no connection could be made, so it can be sent again
+    pub fn is_connect(&self) -> bool {
+        matches!(
+            self,
+            Self::Resolve(_) | Self::Connect(_) | Self::ConnectTimeout | Self::Tls(_)
+        )
+    }
+
+    /// The connection was reset or closed by the server before the whole response arrived
+    pub fn is_reset(&self) -> bool {
+        let Self::Hyper(err) = self else {
+            return false;
+        };
+        if err.is_incomplete_message() || err.is_canceled() {
+            return true;
+        }
+        let mut source = std::error::Error::source(err);
+        while let Some(err) = source {
+            if let Some(err) = err.downcast_ref::<std::io::Error>() {
+                return matches!(
+                    err.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::UnexpectedEof
+                );
+            }
+            source = err.source();
+        }
+        false
+    }
+
+    /// The status to answer the client with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::ConnectTimeout | Self::ReadTimeout | Self::Timeout | Self::BodyTimeout => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Self::PoolExhausted => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
 }
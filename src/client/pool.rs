@@ -0,0 +1,507 @@
+use crate::client::{to_http1, to_http2, ProxyClientError};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    client::conn::{http1, http2},
+    http::uri::Scheme,
+    rt::{Read, Write},
+    Request, Response,
+};
+use hyper_util::rt::TokioExecutor;
+use rustls_pki_types::ServerName;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error};
+
+type ProxyRequest = Request<BoxBody<Bytes, hyper::Error>>;
+type Http1Sender = http1::SendRequest<BoxBody<Bytes, hyper::Error>>;
+type Http2Sender = http2::SendRequest<BoxBody<Bytes, hyper::Error>>;
+
+/// Connections are shared by the requests to the same host and port with the same server name,
+/// whichever address of the host they are made to
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    authority: Arc<str>,
+    domain: ServerName<'static>,
+}
+
+struct Idle<S> {
+    sender: S,
+    /// When the connection was last used
+    since: Instant,
+}
+
+#[derive(Default)]
+struct Host {
+    /// HTTP/1 connections waiting for a request, the most recently used last
+    http1: Vec<Idle<Http1Sender>>,
+    /// The HTTP/2 connection, which all requests are multiplexed over
+    http2: Option<Idle<Http2Sender>>,
+}
+
+/// Keeps the connections to upstreams open for later requests. HTTP/1 connections are reused once
+/// the previous response has been read, HTTP/2 connections are shared by concurrent requests.
+/// Connections are closed after `idle_timeout` without requests, and those found closed by the
+/// upstream are discarded.
+pub struct ConnectionPool {
+    hosts: Mutex<HashMap<PoolKey, Host>>,
+    /// A permit for each HTTP/1 connection busy with a request, per upstream
+    busy: Mutex<HashMap<PoolKey, Arc<Semaphore>>>,
+    idle_timeout: Duration,
+    /// The maximum number of idle HTTP/1 connections kept for each upstream
+    max_idle_per_host: usize,
+    /// The maximum number of HTTP/1 connections open to each upstream, requests beyond it wait
+    /// for a connection to be free
+    max_per_host: usize,
+    /// How long to wait for a new connection, including the TLS handshake
+    connect_timeout: Duration,
+    /// How long to wait for the response once the request is sent
+    read_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(
+        idle_timeout: Duration,
+        max_idle_per_host: usize,
+        max_per_host: usize,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            hosts: Mutex::new(HashMap::new()),
+            busy: Mutex::new(HashMap::new()),
+            idle_timeout,
+            max_idle_per_host,
+            max_per_host: max_per_host.max(1),
+            connect_timeout,
+            read_timeout,
+        });
+        pool.spawn_reaper();
+        pool
+    }
+
+    /// Make a new connection with `connect` within the connect timeout
+    pub async fn connect<F, Fut, T>(&self, connect: F) -> Result<T, ProxyClientError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, ProxyClientError>>,
+    {
+        tokio::time::timeout(self.connect_timeout, connect())
+            .await
+            .unwrap_or(Err(ProxyClientError::ConnectTimeout))
+    }
+
+    /// Wait for the response within the read timeout
+    pub async fn read<Fut, E>(&self, response: Fut) -> Result<Response<Incoming>, ProxyClientError>
+    where
+        Fut: Future<Output = Result<Response<Incoming>, E>>,
+        ProxyClientError: From<E>,
+    {
+        match tokio::time::timeout(self.read_timeout, response).await {
+            Ok(response) => Ok(response?),
+            Err(_) => Err(ProxyClientError::ReadTimeout),
+        }
+    }
+
+    /// Wait until fewer than `max_per_host` HTTP/1 connections to the upstream are busy, for at
+    /// most the connect timeout. The permit is held while a connection is busy with a request, so
+    /// a new connection is only made while there are fewer connections than the maximum.
+    async fn acquire(&self, key: &PoolKey) -> Result<OwnedSemaphorePermit, ProxyClientError> {
+        let busy = self
+            .busy
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host)))
+            .clone();
+        match tokio::time::timeout(self.connect_timeout, busy.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(ProxyClientError::PoolExhausted),
+        }
+    }
+
+    /// Send `req` over an idle HTTP/1 connection to `authority`, or over a new connection made with
+    /// `connect` if there is none. Once `max_per_host` connections are busy, the request waits for
+    /// one of them.
+    pub async fn send_http1<F, Fut, T>(
+        self: &Arc<Self>,
+        authority: Arc<str>,
+        domain: ServerName<'static>,
+        req: ProxyRequest,
+        connect: F,
+    ) -> Result<Response<Incoming>, ProxyClientError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, ProxyClientError>>,
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let key = PoolKey { authority, domain };
+        let permit = self.acquire(&key).await?;
+        let req = match self.send_idle_http1(&key, req).await? {
+            Ok((response, sender)) => {
+                self.checkin_http1(key, sender, permit);
+                return Ok(response);
+            }
+            Err(req) => req,
+        };
+        let io = self.connect(connect).await?;
+        self.handshake_http1(key, io, req, permit).await
+    }
+
+    /// Send `req` in the protocol the upstream at `authority` chose by ALPN: over its HTTP/2
+    /// connection, an idle HTTP/1 connection, or else a new connection made with `connect`, which
+    /// also tells whether HTTP/2 was negotiated on it. The request is brought into the form of the
+    /// protocol used, whichever version the client sent it with.
+    pub async fn send_negotiated<F, Fut, T>(
+        self: &Arc<Self>,
+        authority: Arc<str>,
+        domain: ServerName<'static>,
+        mut req: ProxyRequest,
+        connect: F,
+    ) -> Result<Response<Incoming>, ProxyClientError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(T, bool), ProxyClientError>>,
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let key = PoolKey { authority, domain };
+        if let Some(sender) = self.checkout_http2(&key) {
+            to_http2(&mut req, Scheme::HTTPS)?;
+            req = match self.send_shared_http2(&key, sender, req).await? {
+                Ok(response) => return Ok(response),
+                Err(req) => req,
+            };
+        }
+        let permit = self.acquire(&key).await?;
+        to_http1(&mut req);
+        let mut req = match self.send_idle_http1(&key, req).await? {
+            Ok((response, sender)) => {
+                self.checkin_http1(key, sender, permit);
+                return Ok(response);
+            }
+            Err(req) => req,
+        };
+        match self.connect(connect).await? {
+            (io, true) => {
+                drop(permit);
+                to_http2(&mut req, Scheme::HTTPS)?;
+                self.handshake_http2(key, io, req).await
+            }
+            (io, false) => self.handshake_http1(key, io, req, permit).await,
+        }
+    }
+
+    /// Send `req` over the idle HTTP/1 connections until one takes it, the request is given back
+    /// once there is none left
+    async fn send_idle_http1(
+        &self,
+        key: &PoolKey,
+        mut req: ProxyRequest,
+    ) -> Result<Result<(Response<Incoming>, Http1Sender), ProxyRequest>, ProxyClientError> {
+        while let Some(mut sender) = self.checkout_http1(key) {
+            let sent = tokio::time::timeout(self.read_timeout, sender.try_send_request(req)).await;
+            let Ok(sent) = sent else {
+                return Err(ProxyClientError::ReadTimeout);
+            };
+            match sent {
+                Ok(response) => return Ok(Ok((response, sender))),
+                // the connection was closed before the request was sent, most likely by the
+                // upstream after being idle, so the request can be sent again
+                Err(mut err) => match err.take_message() {
+                    Some(message) => {
+                        debug!(
+                            "Discarding stale connection to {}: {}",
+                            key.authority,
+                            err.into_error()
+                        );
+                        req = message;
+                    }
+                    None => return Err(err.into_error().into()),
+                },
+            }
+        }
+        Ok(Err(req))
+    }
+
+    /// Send `req` over the HTTP/2 connection of the upstream, the request is given back if the
+    /// connection turns out to be closed
+    async fn send_shared_http2(
+        &self,
+        key: &PoolKey,
+        mut sender: Http2Sender,
+        req: ProxyRequest,
+    ) -> Result<Result<Response<Incoming>, ProxyRequest>, ProxyClientError> {
+        let sent = tokio::time::timeout(self.read_timeout, sender.try_send_request(req)).await;
+        let Ok(sent) = sent else {
+            return Err(ProxyClientError::ReadTimeout);
+        };
+        match sent {
+            Ok(response) => Ok(Ok(response)),
+            Err(mut err) => match err.take_message() {
+                Some(message) => {
+                    debug!(
+                        "Discarding stale connection to {}: {}",
+                        key.authority,
+                        err.into_error()
+                    );
+                    Ok(Err(message))
+                }
+                None => Err(err.into_error().into()),
+            },
+        }
+    }
+
+    /// Send `req` over the new HTTP/1 connection `io`, which is pooled once the response is read
+    async fn handshake_http1<T>(
+        self: &Arc<Self>,
+        key: PoolKey,
+        io: T,
+        req: ProxyRequest,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<Response<Incoming>, ProxyClientError>
+    where
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let (mut sender, conn) = http1::handshake(io).await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                error!("Connection failed: {}", err);
+            }
+        });
+        let response = self.read(sender.send_request(req)).await?;
+        self.checkin_http1(key, sender, permit);
+        Ok(response)
+    }
+
+    /// Send `req` over the new HTTP/2 connection `io`, which becomes the connection of the
+    /// upstream the following requests share
+    async fn handshake_http2<T>(
+        &self,
+        key: PoolKey,
+        io: T,
+        req: ProxyRequest,
+    ) -> Result<Response<Incoming>, ProxyClientError>
+    where
+        T: Read + Write + Unpin + Send + 'static,
+    {
+        let (mut sender, conn) = http2::handshake(TokioExecutor::new(), io).await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                error!("Connection failed: {}", err);
+            }
+        });
+        self.lock().entry(key).or_default().http2 = Some(Idle {
+            sender: sender.clone(),
+            since: Instant::now(),
+        });
+        self.read(sender.send_request(req)).await
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PoolKey, Host>> {
+        self.hosts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn is_expired(&self, since: Instant) -> bool {
+        since.elapsed() >= self.idle_timeout
+    }
+
+    fn checkout_http1(&self, key: &PoolKey) -> Option<Http1Sender> {
+        let mut hosts = self.lock();
+        let idle = &mut hosts.get_mut(key)?.http1;
+        while let Some(conn) = idle.pop() {
+            if conn.sender.is_ready() && !self.is_expired(conn.since) {
+                return Some(conn.sender);
+            }
+        }
+        None
+    }
+
+    /// Put the connection back once the response has been read, unless the upstream closes it.
+    /// The connection stops counting as busy then.
+    fn checkin_http1(
+        self: &Arc<Self>,
+        key: PoolKey,
+        mut sender: Http1Sender,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let pool = Arc::downgrade(self);
+        tokio::task::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            let mut hosts = pool.lock();
+            let idle = &mut hosts.entry(key).or_default().http1;
+            if idle.len() < pool.max_idle_per_host {
+                idle.push(Idle {
+                    sender,
+                    since: Instant::now(),
+                });
+            }
+            drop(permit);
+        });
+    }
+
+    fn checkout_http2(&self, key: &PoolKey) -> Option<Http2Sender> {
+        let mut hosts = self.lock();
+        let host = hosts.get_mut(key)?;
+        let conn = host.http2.as_mut()?;
+        if conn.sender.is_closed() || self.is_expired(conn.since) {
+            host.http2 = None;
+            return None;
+        }
+        conn.since = Instant::now();
+        Some(conn.sender.clone())
+    }
+
+    /// Close the connections idle for too long in a background thread, the thread stops once the
+    /// pool is dropped
+    fn spawn_reaper(self: &Arc<Self>) {
+        let pool: Weak<Self> = Arc::downgrade(self);
+        let interval = self.idle_timeout.max(Duration::from_secs(1));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(pool) = pool.upgrade() else {
+                break;
+            };
+            pool.lock().retain(|_, host| {
+                host.http1
+                    .retain(|conn| !conn.sender.is_closed() && !pool.is_expired(conn.since));
+                if host
+                    .http2
+                    .as_ref()
+                    .is_some_and(|conn| conn.sender.is_closed() || pool.is_expired(conn.since))
+                {
+                    host.http2 = None;
+                }
+                !host.http1.is_empty() || host.http2.is_some()
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::{header::HOST, server::conn, service::service_fn, Version};
+    use hyper_util::rt::TokioIo;
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// An upstream speaking HTTP/2 or HTTP/1.1, which answers with the version and host of each
+    /// request, and counts the connections made to it
+    async fn upstream(http2: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let host = match req.headers().get(HOST) {
+                        Some(host) => host.to_str().unwrap().to_owned(),
+                        None => req.uri().authority().unwrap().to_string(),
+                    };
+                    let body = format!("{:?} {}", req.version(), host);
+                    Ok::<_, Infallible>(Response::new(body))
+                });
+                let io = TokioIo::new(stream);
+                tokio::spawn(async move {
+                    let _ = match http2 {
+                        true => {
+                            conn::http2::Builder::new(TokioExecutor::new())
+                                .serve_connection(io, service)
+                                .await
+                        }
+                        false => {
+                            conn::http1::Builder::new()
+                                .serve_connection(io, service)
+                                .await
+                        }
+                    };
+                });
+            }
+        });
+        (addr, connections)
+    }
+
+    fn pool() -> Arc<ConnectionPool> {
+        let timeout = Duration::from_secs(5);
+        ConnectionPool::new(timeout, 8, 8, timeout, timeout)
+    }
+
+    fn request(version: Version, addr: SocketAddr) -> ProxyRequest {
+        let req = Request::builder().version(version);
+        let req = match version {
+            Version::HTTP_2 => req.uri(format!("https://{}/", addr)),
+            _ => req.uri("/").header(HOST, addr.to_string()),
+        };
+        req.body(Empty::new().map_err(|never| match never {}).boxed())
+            .unwrap()
+    }
+
+    async fn send(
+        pool: &Arc<ConnectionPool>,
+        addr: SocketAddr,
+        version: Version,
+        http2: bool,
+    ) -> String {
+        let connect = || async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            Ok((TokioIo::new(stream), http2))
+        };
+        let domain = ServerName::try_from("upstream.test").unwrap();
+        let response = pool
+            .send_negotiated(
+                addr.to_string().into(),
+                domain,
+                request(version, addr),
+                connect,
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_the_negotiated_http2() {
+        let (addr, connections) = upstream(true).await;
+        let pool = pool();
+        for version in [Version::HTTP_11, Version::HTTP_2, Version::HTTP_10] {
+            assert_eq!(
+                send(&pool, addr, version, true).await,
+                format!("HTTP/2.0 {}", addr)
+            );
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn follows_the_negotiated_http1() {
+        let (addr, connections) = upstream(false).await;
+        let pool = pool();
+        for version in [Version::HTTP_2, Version::HTTP_11, Version::HTTP_2] {
+            assert_eq!(
+                send(&pool, addr, version, false).await,
+                format!("HTTP/1.1 {}", addr)
+            );
+            // the connection goes back to the pool once the response is read
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::client::ProxyClientError;
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpStream, task::JoinSet};
+use tracing::{debug, info, warn};
+
+/// How long a connection attempt gets before the next address is tried alongside it, as
+/// recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long to wait for the name servers, so the last addresses can still be used within the
+/// connect timeout when they do not answer
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolves the host names of the upstream servers. Records are cached for their TTL, and the
+/// hosts file is read before asking the name servers.
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+}
+
+impl Resolver {
+    /// Ask `name_servers`, or those of the system configuration if empty
+    pub fn new(name_servers: &[SocketAddr]) -> Result<Self, ProxyClientError> {
+        let (config, mut opts) = match name_servers.is_empty() {
+            true => read_system_conf().map_err(ProxyClientError::Resolve)?,
+            false => {
+                let mut config = ResolverConfig::new();
+                for addr in name_servers {
+                    config.add_name_server(NameServerConfig::new(*addr, Protocol::Udp));
+                    config.add_name_server(NameServerConfig::new(*addr, Protocol::Tcp));
+                }
+                (config, ResolverOpts::default())
+            }
+        };
+        // both families are needed to fall back from one to the other
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok(Self {
+            inner: TokioAsyncResolver::tokio(config, opts),
+        })
+    }
+}
+
+/// The host and port of an upstream server, which is resolved again for each new connection
+pub struct Destination {
+    host: String,
+    port: u16,
+    /// `host:port`, which the connections are pooled by
+    authority: Arc<str>,
+    resolver: Arc<Resolver>,
+    /// The addresses last resolved, used while the host cannot be resolved
+    last: Mutex<Arc<[SocketAddr]>>,
+}
+
+impl Destination {
+    pub fn new(host: &str, port: u16, resolver: Arc<Resolver>) -> Self {
+        Self {
+            // an IPv6 address is enclosed in brackets in a URI
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            port,
+            authority: format!("{}:{}", host, port).into(),
+            resolver,
+            last: Mutex::new(Arc::new([])),
+        }
+    }
+
+    /// The host name or IP address, without brackets
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn authority(&self) -> &Arc<str> {
+        &self.authority
+    }
+
+    /// All addresses of the host, the last ones resolved are kept if resolving fails
+    pub async fn resolve(&self) -> Result<Arc<[SocketAddr]>, ProxyClientError> {
+        let lookup = tokio::time::timeout(
+            RESOLVE_TIMEOUT,
+            self.resolver.inner.lookup_ip(self.host.as_str()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(ResolveErrorKind::Timeout.into()));
+        let mut last = self.last.lock().unwrap_or_else(|err| err.into_inner());
+        match lookup {
+            Ok(lookup) => {
+                let addrs = lookup
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip, self.port))
+                    .collect::<Arc<[_]>>();
+                if addrs != *last {
+                    info!("{} resolved to {:?}", self.authority, addrs);
+                    *last = addrs.clone();
+                }
+                Ok(addrs)
+            }
+            Err(err) if !last.is_empty() => {
+                warn!(
+                    "Failed to resolve {}, using the last addresses: {}",
+                    self.host, err
+                );
+                Ok(last.clone())
+            }
+            Err(err) => Err(ProxyClientError::Resolve(err)),
+        }
+    }
+
+    /// Connect to the first address to answer. The addresses are tried in turn, alternating
+    /// between IPv6 and IPv4 starting with IPv6, each one once the previous attempt has failed or
+    /// taken longer than [`CONNECTION_ATTEMPT_DELAY`] (Happy Eyeballs, RFC 8305).
+    pub async fn connect(&self) -> Result<TcpStream, ProxyClientError> {
+        let addrs = self.resolve().await?;
+        let mut pending = interleave(&addrs).into_iter().peekable();
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+        loop {
+            if let Some(addr) = pending.next() {
+                attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+            }
+            if attempts.is_empty() {
+                return Err(ProxyClientError::Connect(last_err.unwrap_or_else(|| {
+                    std::io::Error::other(format!("No address for {}", self.host))
+                })));
+            }
+            tokio::select! {
+                Some(attempt) = attempts.join_next() => match attempt {
+                    Ok((_, Ok(stream))) => return Ok(stream),
+                    Ok((addr, Err(err))) => {
+                        debug!("Failed to connect to {}: {}", addr, err);
+                        last_err = Some(err);
+                    }
+                    Err(err) => last_err = Some(err.into()),
+                },
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.peek().is_some() => (),
+            }
+        }
+    }
+}
+
+/// Order the addresses alternating between the families, starting with IPv6
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.iter().copied().partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(addrs.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(addr) = preferred.pop() {
+        ordered.push(addr);
+        ordered.extend(other.pop());
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::{
+        op::{Message, MessageType},
+        rr::{rdata, RData, Record, RecordType},
+    };
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::net::{TcpListener, UdpSocket};
+
+    /// A name server answering every name with `answers`, with a TTL of `ttl` seconds
+    struct StubServer {
+        addr: SocketAddr,
+        answers: Arc<Mutex<Vec<IpAddr>>>,
+        /// How many `A` queries were received
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl StubServer {
+        async fn start(ttl: u32, answers: Vec<IpAddr>) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server = Self {
+                addr: socket.local_addr().unwrap(),
+                answers: Arc::new(Mutex::new(answers)),
+                queries: Arc::new(AtomicUsize::new(0)),
+            };
+            let (answers, queries) = (server.answers.clone(), server.queries.clone());
+            tokio::spawn(async move {
+                let mut buf = [0; 512];
+                while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                    let request = Message::from_vec(&buf[..len]).unwrap();
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .set_recursion_desired(request.recursion_desired())
+                        .set_recursion_available(true);
+                    for query in request.queries() {
+                        response.add_query(query.clone());
+                        if query.query_type() == RecordType::A {
+                            queries.fetch_add(1, Ordering::SeqCst);
+                        }
+                        for ip in answers.lock().unwrap().iter() {
+                            let rdata = match (query.query_type(), ip) {
+                                (RecordType::A, IpAddr::V4(ip)) => RData::A(rdata::A(*ip)),
+                                (RecordType::AAAA, IpAddr::V6(ip)) => RData::AAAA(rdata::AAAA(*ip)),
+                                _ => continue,
+                            };
+                            let name = query.name().clone();
+                            response.add_answer(Record::from_rdata(name, ttl, rdata));
+                        }
+                    }
+                    let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+                }
+            });
+            server
+        }
+
+        fn destination(&self, port: u16) -> Destination {
+            let resolver = Resolver::new(&[self.addr]).unwrap();
+            Destination::new("upstream.test", port, Arc::new(resolver))
+        }
+    }
+
+    fn v4(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[tokio::test]
+    async fn caches_for_the_ttl() {
+        let server = StubServer::start(2, vec![v4(1)]).await;
+        let dest = server.destination(80);
+        assert_eq!(*dest.resolve().await.unwrap(), [SocketAddr::new(v4(1), 80)]);
+        *server.answers.lock().unwrap() = vec![v4(2)];
+        assert_eq!(*dest.resolve().await.unwrap(), [SocketAddr::new(v4(1), 80)]);
+        assert_eq!(server.queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn resolves_again_once_expired() {
+        let server = StubServer::start(1, vec![v4(1)]).await;
+        let dest = server.destination(80);
+        assert_eq!(*dest.resolve().await.unwrap(), [SocketAddr::new(v4(1), 80)]);
+        *server.answers.lock().unwrap() = vec![v4(2), v4(3)];
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let addrs = dest.resolve().await.unwrap();
+        assert_eq!(
+            *addrs,
+            [SocketAddr::new(v4(2), 80), SocketAddr::new(v4(3), 80)]
+        );
+        assert_eq!(server.queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // IPv6 is tried first, an address of the documentation range cannot be connected to
+        let unreachable = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let answers = vec![unreachable, IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let server = StubServer::start(60, answers).await;
+        let dest = server.destination(port);
+        let stream = tokio::time::timeout(Duration::from_secs(5), dest.connect())
+            .await
+            .expect("the next address is tried once the first attempt fails or takes too long")
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let a = SocketAddr::new(v4(1), 80);
+        let b = SocketAddr::new(v4(2), 80);
+        let c = SocketAddr::new(v4(3), 80);
+        let x = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 80);
+        let y = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 80);
+        assert_eq!(interleave(&[a, b, c, x, y]), [x, a, y, b, c]);
+        assert_eq!(interleave(&[a, b]), [a, b]);
+        assert_eq!(interleave(&[x, y]), [x, y]);
+    }
+}
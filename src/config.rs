@@ -1,6 +1,15 @@
+use crate::session::{
+    binding::IpBinding,
+    health::OutagePolicy,
+    limit::LimitPolicy,
+    set_cookie::{CookiePrefix, SameSite},
+};
+use crate::{forwarded::ForwardedStyle, service::auth_reverse_proxy::upstream::Balance};
 use serde::Deserialize;
 use std::{
+    collections::HashMap,
     io::{Error, ErrorKind, Result},
+    net::SocketAddr,
     path::Path,
 };
 
@@ -8,25 +17,191 @@ use std::{
 pub struct Config {
     pub listen_address: String,
     pub listen_port: u16,
+    pub htpasswd_path: String,
+    pub htpasswd_reload_interval: Option<u64>,
     pub auth_return_header_name: Option<String>,
     pub debug: bool,
     pub reverse_proxy: ReverseProxyConfig,
     pub https: HttpsConfig,
     pub session: SessionConfig,
+    pub admin: Option<AdminConfig>,
+    pub session_limit: Option<SessionLimitConfig>,
+    pub session_binding: Option<SessionBindingConfig>,
+    pub identity: Option<IdentityConfig>,
+    /// Addresses or networks of proxies whose forwarding headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Group name to its members
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Routes to upstreams in reverse proxy mode, the first matching route is used
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteConfig>,
 }
 
 #[derive(Deserialize)]
 pub struct ReverseProxyConfig {
     pub enabled: bool,
-    pub proxy_address: Option<String>,
+    pub proxy_address: Option<ProxyAddress>,
+    #[serde(flatten)]
+    pub upstream: UpstreamConfig,
+    /// How long an upstream connection is kept open without requests, denoted in second
+    pub pool_idle_timeout: Option<u64>,
+    pub pool_max_idle_per_host: Option<usize>,
+    /// The maximum number of HTTP/1 connections open to each upstream server at once
+    pub pool_max_per_host: Option<usize>,
+    /// How long to keep an upgraded connection, such as a WebSocket, open without data sent
+    /// either way, denoted in second
+    pub upgrade_idle_timeout: Option<u64>,
+    /// Which headers tell the upstream about the client, defaults to `x_forwarded`
+    pub forwarded_headers: Option<ForwardedStyle>,
+    /// Add watchdawg to `Via` of requests and responses, defaults to `true`
+    pub via: Option<bool>,
+    /// Map the URLs of the upstream in `Location` and `Content-Location` of responses back to
+    /// those of watchdawg, defaults to `true`
+    pub rewrite_location: Option<bool>,
+    /// Map the `Path` and `Domain` of cookies set by the upstream back to those of watchdawg,
+    /// defaults to `true`
+    pub rewrite_cookies: Option<bool>,
+    /// How long to wait for a connection to an upstream server, denoted in second
+    pub connect_timeout: Option<u64>,
+    /// How long to wait for the response once the request is sent, denoted in second
+    pub read_timeout: Option<u64>,
+    /// How long to wait for the response including retries, denoted in second
+    pub request_timeout: Option<u64>,
+    /// How long to wait for the next data of a response body, denoted in second
+    pub body_idle_timeout: Option<u64>,
+    /// How many times an idempotent request is sent again when no connection could be made
+    pub retries: Option<u32>,
+    /// The wait before the first retry, doubled for each retry, denoted in millisecond
+    pub retry_backoff: Option<u64>,
+    /// The name servers to resolve upstream hosts with, instead of those of the system
+    #[serde(default)]
+    pub name_servers: Vec<SocketAddr>,
+    /// The HTML page sent with `502`, `503` and `504` responses, `{status}` is replaced with the
+    /// status
+    pub error_page: Option<String>,
+}
+
+/// The address of a server, or the addresses of the servers to balance requests over
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ProxyAddress {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ProxyAddress {
+    pub fn addresses(&self) -> &[String] {
+        match self {
+            Self::One(address) => std::slice::from_ref(address),
+            Self::Many(addresses) => addresses,
+        }
+    }
+}
+
+/// How requests are spread over the servers of an upstream and how their health is checked
+#[derive(Deserialize)]
+pub struct UpstreamConfig {
+    pub balance: Option<Balance>,
+    /// The path to request from each server to check its health, not checked if absent
+    pub health_check_path: Option<String>,
+    pub health_check_interval: Option<u64>,
+    pub health_check_timeout: Option<u64>,
+    /// Consecutive failed checks to take a server out
+    pub health_check_fails: Option<u32>,
+    /// The initial backoff of a server after a failed request, denoted in second
+    pub fail_backoff: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct RouteConfig {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub path_regex: Option<String>,
+    /// Any method if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub proxy_address: ProxyAddress,
+    #[serde(flatten)]
+    pub upstream: UpstreamConfig,
+    /// Remove `path_prefix` from the path before forwarding
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Replace the part of the path matched by `path_prefix` or `path_regex`
+    pub rewrite: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SessionConfig {
     pub cookie_name: String,
+    #[serde(default)]
+    pub cookie_secure: bool,
+    pub cookie_same_site: Option<SameSite>,
+    pub cookie_domain: Option<String>,
+    pub cookie_path: Option<String>,
+    #[serde(default)]
+    pub cookie_partitioned: bool,
+    pub cookie_prefix: Option<CookiePrefix>,
     pub session_expire: u64,
     pub storage: String,
-    pub redis_conn: Option<String>
+    pub redis_conn: Option<String>,
+    pub redis_sentinels: Option<Vec<String>>,
+    pub redis_sentinel_master: Option<String>,
+    pub redis_cluster_nodes: Option<Vec<String>>,
+    pub cache_ttl: Option<u64>,
+    pub cache_capacity: Option<usize>,
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval: Option<u64>,
+    pub sqlite_path: Option<String>,
+    pub postgres_conn: Option<String>,
+    pub cleanup_interval: Option<u64>,
+    pub cookie_keys: Option<Vec<String>>,
+    pub session_secrets: Option<Vec<String>>,
+    pub revocation_storage: Option<String>,
+    pub logout_path: Option<String>,
+    pub reauth_path: Option<String>,
+    pub rotate_interval: Option<u64>,
+    pub rotate_grace: Option<u64>,
+    pub store_outage: Option<OutagePolicy>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionLimitConfig {
+    pub max_sessions: usize,
+    pub policy: LimitPolicy,
+    #[serde(default)]
+    pub users: HashMap<String, usize>,
+    #[serde(default)]
+    pub groups: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionBindingConfig {
+    pub ip: Option<IpBinding>,
+    pub ipv4_prefix: Option<u8>,
+    pub ipv6_prefix: Option<u8>,
+    #[serde(default)]
+    pub user_agent: bool,
+    #[serde(default)]
+    pub client_cert: bool,
+}
+
+/// Headers telling the upstream who the user is in reverse proxy mode
+#[derive(Deserialize)]
+pub struct IdentityConfig {
+    pub user_header: Option<String>,
+    pub groups_header: Option<String>,
+    pub jwt_header: Option<String>,
+    pub jwt_secret: Option<String>,
+    /// How long the JWT assertion is valid, denoted in second
+    pub jwt_ttl: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct AdminConfig {
+    pub socket: String,
+    pub token: String,
 }
 
 #[derive(Deserialize)]
@@ -34,6 +209,8 @@ pub struct HttpsConfig {
     pub enabled: bool,
     pub cert: Option<String>,
     pub key: Option<String>,
+    /// CA certificates to verify TLS client certificates, they are not requested if absent
+    pub client_ca: Option<String>,
 }
 
 impl Config {
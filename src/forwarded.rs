@@ -0,0 +1,312 @@
+use hyper::{
+    header::{HeaderValue, FORWARDED},
+    HeaderMap,
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PORT: &str = "x-forwarded-port";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// Proxies in front of watchdawg whose `X-Forwarded-For` and `X-Real-IP` headers are trusted
+#[derive(Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Each entry is either an address or a network in CIDR notation
+    pub fn new(entries: &[String]) -> Result<Self, ipnet::AddrParseError> {
+        let nets = entries
+            .iter()
+            .map(|entry| match entry.parse::<IpAddr>() {
+                Ok(ip) => Ok(IpNet::from(ip)),
+                Err(_) => entry.parse::<IpNet>(),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { nets })
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Return the address of the client, the forwarding headers are only used if the peer is a
+    /// trusted proxy. In `X-Forwarded-For` the right most address not of a trusted proxy is taken,
+    /// since the addresses on its left are given by the client and can be anything.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let forwarded_for = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        if let Some(ip) = forwarded_for.iter().rev().find(|ip| !self.is_trusted(ip)) {
+            return *ip;
+        }
+        if let Some(ip) = forwarded_for.first() {
+            return *ip;
+        }
+        headers
+            .get(X_REAL_IP)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+}
+
+/// Which headers tell the upstream about the client
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedStyle {
+    None,
+    /// `Forwarded` of RFC 7239
+    Forwarded,
+    /// `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Port`
+    #[default]
+    XForwarded,
+    Both,
+}
+
+/// Adds the forwarding headers to the requests sent to the upstream
+pub struct ForwardedHeaders {
+    style: ForwardedStyle,
+    /// The scheme the clients connect to watchdawg with
+    proto: &'static str,
+    /// The port watchdawg listens on
+    port: u16,
+}
+
+impl ForwardedHeaders {
+    pub fn new(style: ForwardedStyle, https: bool, port: u16) -> Self {
+        Self {
+            style,
+            proto: if https { "https" } else { "http" },
+            port,
+        }
+    }
+
+    /// Add the headers for a request from `peer` to `host`. The forwarding headers received are
+    /// only kept and appended to if `peer` is a trusted proxy, otherwise they are removed so that
+    /// clients cannot pretend to come from elsewhere.
+    pub fn apply(
+        &self,
+        headers: &mut HeaderMap,
+        peer: Option<IpAddr>,
+        host: Option<HeaderValue>,
+        trusted: bool,
+    ) {
+        if !trusted {
+            for name in [
+                FORWARDED.as_str(),
+                X_FORWARDED_FOR,
+                X_FORWARDED_PROTO,
+                X_FORWARDED_HOST,
+                X_FORWARDED_PORT,
+                X_REAL_IP,
+            ] {
+                headers.remove(name);
+            }
+        }
+
+        if matches!(
+            self.style,
+            ForwardedStyle::XForwarded | ForwardedStyle::Both
+        ) {
+            if let Some(peer) = peer {
+                let mut forwarded_for = headers
+                    .get_all(X_FORWARDED_FOR)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if !forwarded_for.is_empty() {
+                    forwarded_for.push_str(", ");
+                }
+                forwarded_for.push_str(&peer.to_string());
+                if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                    headers.insert(X_FORWARDED_FOR, value);
+                }
+            }
+            // a trusted proxy in front knows better where the request was sent to
+            if !headers.contains_key(X_FORWARDED_PROTO) {
+                headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(self.proto));
+            }
+            if let Some(host) = host
+                .as_ref()
+                .filter(|_| !headers.contains_key(X_FORWARDED_HOST))
+            {
+                headers.insert(X_FORWARDED_HOST, host.clone());
+            }
+            if !headers.contains_key(X_FORWARDED_PORT) {
+                headers.insert(X_FORWARDED_PORT, HeaderValue::from(self.port));
+            }
+        }
+
+        if matches!(self.style, ForwardedStyle::Forwarded | ForwardedStyle::Both) {
+            let node = match peer {
+                Some(IpAddr::V4(ip)) => ip.to_string(),
+                Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+                None => "unknown".to_string(),
+            };
+            let mut element = format!("for={};proto={}", node, self.proto);
+            // the host is sent as a quoted string, which cannot hold quotes or backslashes
+            if let Some(host) = host
+                .as_ref()
+                .and_then(|host| host.to_str().ok())
+                .filter(|host| !host.contains(['"', '\\']))
+            {
+                element.push_str(&format!(";host=\"{}\"", host));
+            }
+            if let Ok(value) = HeaderValue::from_str(&element) {
+                headers.append(FORWARDED, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(&["10.0.0.0/8".to_string(), "192.0.2.1".to_string()]).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_entries() {
+        let proxies = proxies();
+        assert!(proxies.is_trusted(&ip("10.1.2.3")));
+        assert!(proxies.is_trusted(&ip("192.0.2.1")));
+        assert!(!proxies.is_trusted(&ip("192.0.2.2")));
+        assert!(TrustedProxies::new(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::new(&["proxy".to_string()]).is_err());
+    }
+
+    #[test]
+    fn ignores_headers_of_untrusted_peers() {
+        let spoofed = headers(&[("x-forwarded-for", "10.0.0.5"), ("x-real-ip", "10.0.0.6")]);
+        let peer = ip("203.0.113.9");
+        assert_eq!(proxies().client_ip(peer, &spoofed), peer);
+        assert_eq!(TrustedProxies::default().client_ip(peer, &spoofed), peer);
+    }
+
+    #[test]
+    fn takes_the_rightmost_untrusted_address() {
+        // the client put 198.51.100.1 in front, 203.0.113.5 connected to the first proxy
+        let chain = headers(&[
+            ("x-forwarded-for", "198.51.100.1, 203.0.113.5"),
+            ("x-forwarded-for", "10.0.0.2,garbage"),
+        ]);
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &chain),
+            ip("203.0.113.5")
+        );
+        let v6 = headers(&[("x-forwarded-for", " 2001:db8::1 , 192.0.2.1")]);
+        assert_eq!(proxies().client_ip(ip("10.0.0.1"), &v6), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn falls_back_without_untrusted_addresses() {
+        let peer = ip("10.0.0.1");
+        // every hop is a trusted proxy, the first one is as far as it goes
+        let trusted = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxies().client_ip(peer, &trusted), ip("10.0.0.3"));
+        let real_ip = headers(&[("x-real-ip", " 203.0.113.7 ")]);
+        assert_eq!(proxies().client_ip(peer, &real_ip), ip("203.0.113.7"));
+        let empty = headers(&[("x-forwarded-for", ""), ("x-real-ip", "unknown")]);
+        assert_eq!(proxies().client_ip(peer, &empty), peer);
+        assert_eq!(proxies().client_ip(peer, &HeaderMap::new()), peer);
+    }
+
+    fn spoofed() -> HeaderMap {
+        headers(&[
+            ("forwarded", "for=10.0.0.5"),
+            ("x-forwarded-for", "10.0.0.5"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "admin.internal"),
+            ("x-forwarded-port", "1"),
+            ("x-real-ip", "10.0.0.5"),
+        ])
+    }
+
+    #[test]
+    fn strips_headers_of_untrusted_peers() {
+        let forwarded = ForwardedHeaders::new(ForwardedStyle::Both, false, 8080);
+        let mut headers = spoofed();
+        let host = HeaderValue::from_static("example.com");
+        forwarded.apply(&mut headers, Some(ip("203.0.113.9")), Some(host), false);
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(headers["x-forwarded-port"], "8080");
+        assert!(!headers.contains_key(X_REAL_IP));
+        let forwarded = headers.get_all(FORWARDED).iter().collect::<Vec<_>>();
+        assert_eq!(
+            forwarded,
+            ["for=203.0.113.9;proto=http;host=\"example.com\""]
+        );
+
+        let mut headers = spoofed();
+        ForwardedHeaders::new(ForwardedStyle::None, false, 8080).apply(
+            &mut headers,
+            Some(ip("203.0.113.9")),
+            None,
+            false,
+        );
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn appends_to_headers_of_trusted_proxies() {
+        let forwarded = ForwardedHeaders::new(ForwardedStyle::Both, true, 443);
+        let mut headers = spoofed();
+        let host = HeaderValue::from_static("example.com");
+        forwarded.apply(&mut headers, Some(ip("2001:db8::2")), Some(host), true);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.5, 2001:db8::2");
+        // the proxy in front knows where the request was sent to
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "admin.internal");
+        assert_eq!(headers["x-forwarded-port"], "1");
+        let forwarded = headers.get_all(FORWARDED).iter().collect::<Vec<_>>();
+        assert_eq!(
+            forwarded,
+            [
+                "for=10.0.0.5",
+                "for=\"[2001:db8::2]\";proto=https;host=\"example.com\""
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_forwarded_values() {
+        let forwarded = ForwardedHeaders::new(ForwardedStyle::Forwarded, false, 80);
+        let mut headers = HeaderMap::new();
+        let host = HeaderValue::from_static("evil\"host");
+        forwarded.apply(&mut headers, None, Some(host), false);
+        assert_eq!(headers[FORWARDED], "for=unknown;proto=http");
+        assert!(!headers.contains_key(X_FORWARDED_FOR));
+    }
+}
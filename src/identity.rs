@@ -0,0 +1,228 @@
+use crate::{auth::groups::Groups, session::unix_now};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use hyper::{
+    header::{HeaderName, HeaderValue, InvalidHeaderName},
+    HeaderMap,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::warn;
+
+/// Secrets shorter than this are too easy to guess, the length of the HMAC-SHA256 key
+const MIN_SECRET_LEN: usize = 32;
+
+/// The claims of the identity assertion
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'static str,
+    sub: &'a str,
+    groups: &'a [String],
+    iat: u64,
+    exp: u64,
+}
+
+/// Signs JWT assertions of the identity with HMAC-SHA256 (`HS256`)
+pub struct JwtSigner {
+    mac: Hmac<Sha256>,
+    /// How long an assertion is valid, denoted in second
+    ttl: u64,
+}
+
+impl JwtSigner {
+    pub fn new(secret: &str, ttl: u64) -> Result<Self, IdentityError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(IdentityError::ShortSecret);
+        }
+        Ok(Self {
+            mac: Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap(),
+            ttl,
+        })
+    }
+
+    fn sign(&self, user: &str, groups: &[String]) -> String {
+        const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+        let iat = unix_now();
+        let claims = Claims {
+            iss: "watchdawg",
+            sub: user,
+            groups,
+            iat,
+            exp: iat + self.ttl,
+        };
+        let claims = serde_json::to_vec(&claims).unwrap_or_default();
+        let mut token = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(HEADER),
+            BASE64_URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = self.mac.clone();
+        mac.update(token.as_bytes());
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        token.push('.');
+        token.push_str(&signature);
+        token
+    }
+}
+
+/// Tells the upstream who the authenticated user is. Copies of the headers sent by the client are
+/// always removed, including those spelled with `_` instead of `-`, so the upstream can trust them.
+pub struct IdentityHeaders {
+    user: Option<HeaderName>,
+    /// The groups of the user, separated by commas
+    groups: Option<HeaderName>,
+    /// A signed assertion of the user and groups
+    jwt: Option<(HeaderName, JwtSigner)>,
+    membership: Arc<Groups>,
+}
+
+impl IdentityHeaders {
+    pub fn new(
+        user: Option<&str>,
+        groups: Option<&str>,
+        jwt: Option<(&str, JwtSigner)>,
+        membership: Arc<Groups>,
+    ) -> Result<Self, IdentityError> {
+        Ok(Self {
+            user: user.map(HeaderName::try_from).transpose()?,
+            groups: groups.map(HeaderName::try_from).transpose()?,
+            jwt: jwt
+                .map(|(name, signer)| HeaderName::try_from(name).map(|name| (name, signer)))
+                .transpose()?,
+            membership,
+        })
+    }
+
+    /// Replace the identity headers of the request with those of `user`
+    pub fn apply(&self, headers: &mut HeaderMap, user: &str) {
+        let groups = self.membership.of(user);
+        if let Some(name) = &self.user {
+            remove(headers, name);
+            insert(headers, name, user);
+        }
+        if let Some(name) = &self.groups {
+            remove(headers, name);
+            if !groups.is_empty() {
+                insert(headers, name, &groups.join(","));
+            }
+        }
+        if let Some((name, signer)) = &self.jwt {
+            remove(headers, name);
+            insert(headers, name, &signer.sign(user, groups));
+        }
+    }
+}
+
+/// Remove the header along with the variants spelled with `_` instead of `-`, which some servers
+/// and frameworks take for the same header. Header names are already lowercase, so variants in
+/// another case are the same name.
+fn remove(headers: &mut HeaderMap, name: &HeaderName) {
+    let is_variant = |other: &HeaderName| {
+        let (other, name) = (other.as_str().as_bytes(), name.as_str().as_bytes());
+        other.len() == name.len()
+            && other
+                .iter()
+                .zip(name)
+                .all(|(a, b)| a == b || (matches!(a, b'-' | b'_') && matches!(b, b'-' | b'_')))
+    };
+    let variants = headers
+        .keys()
+        .filter(|other| is_variant(other))
+        .cloned()
+        .collect::<Vec<_>>();
+    for variant in variants {
+        headers.remove(variant);
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => warn!("`{}` cannot be sent in the `{}` header", value, name),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Invalid identity header name: {0}")]
+    InvalidHeaderName(#[from] InvalidHeaderName),
+    #[error("`identity.jwt_secret` must be at least {MIN_SECRET_LEN} bytes long")]
+    ShortSecret,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn identity() -> IdentityHeaders {
+        let groups = HashMap::from([("admin".to_string(), vec!["alice".to_string()])]);
+        let signer = JwtSigner::new(SECRET, 60).unwrap();
+        IdentityHeaders::new(
+            Some("X-Auth-User"),
+            Some("X-Auth-Groups"),
+            Some(("X-Auth-Assertion", signer)),
+            Arc::new(Groups::new(&groups)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn strips_spoofed_variants() {
+        let mut headers = HeaderMap::new();
+        for name in [
+            "x-auth-user",
+            "X-AUTH-USER",
+            "x_auth_user",
+            "X_Auth-User",
+            "x-auth_groups",
+            "x_auth_assertion",
+        ] {
+            headers.append(
+                HeaderName::try_from(name).unwrap(),
+                HeaderValue::from_static("mallory"),
+            );
+        }
+        headers.insert("x-auth-users", HeaderValue::from_static("kept"));
+        identity().apply(&mut headers, "alice");
+
+        assert_eq!(
+            headers.get_all("x-auth-user").iter().collect::<Vec<_>>(),
+            ["alice"]
+        );
+        assert_eq!(
+            headers.get_all("x-auth-groups").iter().collect::<Vec<_>>(),
+            ["admin"]
+        );
+        assert_eq!(headers.get_all("x-auth-assertion").iter().count(), 1);
+        assert_eq!(headers.get("x-auth-users").unwrap(), "kept");
+        let names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        assert!(!names.iter().any(|name| name.contains('_')), "{:?}", names);
+    }
+
+    #[test]
+    fn signs_assertion() {
+        let token = JwtSigner::new(SECRET, 60).unwrap().sign("alice", &[]);
+        let (signed, signature) = token.rsplit_once('.').unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&BASE64_URL_SAFE_NO_PAD.decode(signature).unwrap())
+            .unwrap();
+        let claims = signed.split_once('.').unwrap().1;
+        let claims: serde_json::Value =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["sub"], "alice");
+    }
+
+    #[test]
+    fn rejects_short_secrets() {
+        assert!(JwtSigner::new("", 60).is_err());
+        assert!(JwtSigner::new(&SECRET[1..], 60).is_err());
+    }
+}
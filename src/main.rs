@@ -1,30 +1,84 @@
-use std::sync::Arc;
+use admin::{
+    client::{print_sessions, AdminClient},
+    server::AdminServer,
+    AdminSvc,
+};
 use argh::FromArgs;
-use auth::htpasswd::HtpasswdAuth;
-use client::{http::HttpClient, https::HttpsClient, ProxyClient};
-use config::Config;
+use auth::{groups::Groups, htpasswd::HtpasswdAuth, Authenticator};
+use client::{pool::ConnectionPool, resolve::Resolver, ProxyClients};
+use config::{Config, IdentityConfig, ProxyAddress, SessionConfig, UpstreamConfig};
+use forwarded::{ForwardedHeaders, TrustedProxies};
+use identity::{IdentityHeaders, JwtSigner};
 use server::ProxyServer;
 use service::{
     auth_only::{http::HttpAuthOnly, https::HttpsAuthOnly, AuthOnlySvc},
-    auth_reverse_proxy::{http::HttpAuthRevPrx, https::HttpsAuthRevPrx, AuthRevPrxSvc},
+    auth_reverse_proxy::{
+        http::HttpAuthRevPrx,
+        https::HttpsAuthRevPrx,
+        router::{Route, Router},
+        upstream::{HealthCheck, Server, Upstream},
+        AuthRevPrxSvc, ProxyOptions,
+    },
+};
+use session::{
+    binding::SessionBinding,
+    cache::CachedStore,
+    cookie::CookieSealer,
+    fallback::FallbackStore,
+    health::{MonitoredStore, OutagePolicy, StoreHealth},
+    limit::SessionLimit,
+    memory::MemoryStore,
+    migrate::migrate,
+    postgres::PostgresStore,
+    redis::{RedisStore, RedisTarget},
+    set_cookie::{CookieOptions, SessionCookie},
+    signer::SessionSigner,
+    sqlite::SqliteStore,
+    SessionManager, SessionStore,
 };
-use session::{memory::MemoryStore, redis::RedisStore, SessionManager, SessionStore};
+use std::{path::Path, sync::Arc, time::Duration};
 use thiserror::Error;
-use tracing::level_filters::LevelFilter;
+use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::util::SubscriberInitExt;
 use utils::{load_certs, load_private_key};
 
+mod admin;
 mod auth;
 mod client;
 mod config;
+mod forwarded;
+mod identity;
 mod server;
 mod service;
 mod session;
 mod utils;
 
+const DEFAULT_SNAPSHOT_INTERVAL: u64 = 300;
+const DEFAULT_IPV4_PREFIX: u8 = 24;
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+const DEFAULT_CACHE_CAPACITY: usize = 10000;
+const DEFAULT_CLEANUP_INTERVAL: u64 = 300;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
+const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 128;
+const DEFAULT_POOL_MAX_PER_HOST: usize = 512;
+const DEFAULT_JWT_TTL: u64 = 60;
+const DEFAULT_UPGRADE_IDLE_TIMEOUT: u64 = 300;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+const DEFAULT_READ_TIMEOUT: u64 = 60;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 120;
+const DEFAULT_BODY_IDLE_TIMEOUT: u64 = 60;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: u64 = 100;
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+const DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 5;
+const DEFAULT_HEALTH_CHECK_FAILS: u32 = 2;
+const DEFAULT_FAIL_BACKOFF: u64 = 10;
+const DEFAULT_ROTATE_GRACE: u64 = 30;
+const DEFAULT_HTPASSWD_RELOAD_INTERVAL: u64 = 5;
+
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = argh::from_env::<Args>();
-    let config = Config::from_file(args.config.unwrap_or("config.toml".into()))?;
+    let config = Config::from_file(args.config.as_deref().unwrap_or("config.toml"))?;
 
     let log_level = match config.debug {
         true => LevelFilter::DEBUG,
@@ -36,37 +90,151 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .finish()
         .init();
 
+    match args.command {
+        Some(Command::Sessions(command)) => return run_sessions_command(command, &config),
+        Some(Command::MigrateSessions(command)) => {
+            return run_migrate_command(command, &config.session)
+        }
+        Some(Command::PurgeLegacySessions(command)) => {
+            return run_purge_legacy_command(command, &config.session)
+        }
+        None => {}
+    }
+
     let addr = (config.listen_address.clone(), config.listen_port);
-    let session_store: Arc<dyn SessionStore + Send + Sync> = match config.session.storage.as_str() {
-        "memory" => Arc::new(MemoryStore::new()),
-        "redis" => Arc::new(RedisStore::new(
-            &config
+    let session_cookie = SessionCookie::new(
+        &config.session.cookie_name,
+        CookieOptions {
+            secure: config.session.cookie_secure,
+            same_site: config.session.cookie_same_site,
+            domain: config.session.cookie_domain.clone(),
+            path: config.session.cookie_path.clone(),
+            partitioned: config.session.cookie_partitioned,
+            prefix: config.session.cookie_prefix,
+        },
+        config.session.session_expire,
+    )?;
+    let outage_policy = config.session.store_outage.unwrap_or_default();
+    let health = Arc::new(StoreHealth::new());
+    let (session_manager, session_store) = match config.session.storage.as_str() {
+        "cookie" => {
+            let sealer = CookieSealer::new(
+                config
+                    .session
+                    .cookie_keys
+                    .as_deref()
+                    .ok_or(ServerError::MissingProperty("session.cookie_keys"))?,
+            )?;
+            let revoked = match config.session.revocation_storage.as_deref() {
+                Some("none") | None => None,
+                Some(storage) => Some(monitor_store(
+                    build_store(storage, &config.session)?,
+                    &config.session,
+                    &health,
+                )),
+            };
+            let session_manager = SessionManager::with_cookie(
+                session_cookie,
+                sealer,
+                revoked.clone(),
+                config.session.session_expire,
+            );
+            (session_manager, revoked)
+        }
+        storage => {
+            let session_store = monitor_store(
+                build_store(storage, &config.session)?,
+                &config.session,
+                &health,
+            );
+            let signer = config
                 .session
-                .redis_conn
-                .ok_or(ServerError::MissingProperty("session.redis_conn"))?,
-        )?),
-        _ => {
-            return Err(
-                std::io::Error::other("Session storage should be `memory` or `redis`").into(),
-            )
+                .session_secrets
+                .as_deref()
+                .map(SessionSigner::new)
+                .transpose()?;
+            let session_manager = SessionManager::new(
+                session_cookie,
+                session_store.clone(),
+                signer,
+                config.session.session_expire,
+            );
+            (session_manager, Some(session_store))
         }
     };
+    if config.session.storage == "cookie" && config.session_limit.is_some() {
+        warn!("`session_limit` has no effect with `cookie` storage");
+    }
+    if config.session.storage == "cookie" && config.session_binding.is_some() {
+        warn!("`session_binding` has no effect with `cookie` storage");
+    }
+    if config
+        .session_binding
+        .as_ref()
+        .is_some_and(|binding| binding.client_cert)
+        && config.https.client_ca.is_none()
+    {
+        warn!("`session_binding.client_cert` requires `https.client_ca` to request certificates");
+    }
+    let groups = Arc::new(Groups::new(&config.groups));
+    let mut session_manager = session_manager;
+    session_manager.logout_path = config.session.logout_path.clone();
+    session_manager.reauth_path = config.session.reauth_path.clone();
+    session_manager.outage_policy = outage_policy;
+    session_manager.health = session_store.as_ref().map(|_| health);
+    session_manager.rotate_interval = config.session.rotate_interval;
+    session_manager.rotate_grace = config.session.rotate_grace.unwrap_or(DEFAULT_ROTATE_GRACE);
+    session_manager.limit = config.session_limit.map(|limit| {
+        SessionLimit::new(
+            limit.policy,
+            limit.max_sessions,
+            limit.users,
+            limit.groups,
+            groups.clone(),
+        )
+    });
+    session_manager.binding = config.session_binding.map(|binding| {
+        SessionBinding::new(
+            binding.ip,
+            binding.ipv4_prefix.unwrap_or(DEFAULT_IPV4_PREFIX),
+            binding.ipv6_prefix.unwrap_or(DEFAULT_IPV6_PREFIX),
+            binding.user_agent,
+            binding.client_cert,
+        )
+    });
+    let session_manager = Arc::new(session_manager);
+    let trusted_proxies = Arc::new(TrustedProxies::new(&config.trusted_proxies)?);
+    let client_ca = config
+        .https
+        .client_ca
+        .as_deref()
+        .map(load_certs)
+        .transpose()?;
+    let authenticator = Arc::new(HtpasswdAuth::new(&config.htpasswd_path)?);
+    authenticator.spawn_reload_task(Duration::from_secs(
+        config
+            .htpasswd_reload_interval
+            .unwrap_or(DEFAULT_HTPASSWD_RELOAD_INTERVAL),
+    ));
+    let revoke_manager = session_manager.clone();
+    authenticator.subscribe(Box::new(move |event| {
+        match revoke_manager.revoke_user(event.user()) {
+            Ok(revoked) => info!("Revoked {} sessions of {}", revoked, event.user()),
+            Err(err) => warn!("Failed to revoke sessions of {}: {}", event.user(), err),
+        }
+    }));
 
-    let session_manager = SessionManager::new(
-        config.session.cookie_name.as_str(),
-        session_store,
-        config.session.session_expire,
-    );
-    let authenticator = HtpasswdAuth::new("htpasswd")?;
-
+    // the upstreams of all routes, whose servers are reported by the admin API
+    let mut upstreams = Vec::new();
     let server = match config.reverse_proxy.enabled {
         false => {
             let service = AuthOnlySvc::new(
-                authenticator,
+                authenticator.clone(),
                 &config
                     .auth_return_header_name
                     .ok_or(ServerError::MissingProperty("auth_return_header_name"))?,
-                session_manager,
+                session_manager.clone(),
+                trusted_proxies,
             )?;
             match config.https.enabled {
                 true => {
@@ -82,26 +250,111 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                             .key
                             .ok_or(ServerError::MissingProperty("https.key"))?,
                     )?;
-                    ProxyServer::new(HttpsAuthOnly::new(certs, key, service)?)
+                    ProxyServer::new(HttpsAuthOnly::new(certs, key, client_ca, service)?)
                 }
                 false => ProxyServer::new(HttpAuthOnly::new(service)),
             }
         }
         true => {
-            let proxy_client: Arc<dyn ProxyClient + Send + Sync> = match config.https.enabled {
-                true => Arc::new(HttpsClient::new()?),
-                false => Arc::new(HttpClient),
-            };
-            let service = AuthRevPrxSvc::new(
+            let pool = ConnectionPool::new(
+                Duration::from_secs(
+                    config
+                        .reverse_proxy
+                        .pool_idle_timeout
+                        .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+                ),
                 config
                     .reverse_proxy
-                    .proxy_address
-                    .ok_or(ServerError::MissingProperty("reverse_proxy.proxy_address"))?
-                    .as_str(),
-                authenticator,
-                session_manager,
-                proxy_client,
-            )?;
+                    .pool_max_idle_per_host
+                    .unwrap_or(DEFAULT_POOL_MAX_IDLE_PER_HOST),
+                config
+                    .reverse_proxy
+                    .pool_max_per_host
+                    .unwrap_or(DEFAULT_POOL_MAX_PER_HOST),
+                Duration::from_secs(
+                    config
+                        .reverse_proxy
+                        .connect_timeout
+                        .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+                ),
+                Duration::from_secs(
+                    config
+                        .reverse_proxy
+                        .read_timeout
+                        .unwrap_or(DEFAULT_READ_TIMEOUT),
+                ),
+            );
+            let resolver = Arc::new(Resolver::new(&config.reverse_proxy.name_servers)?);
+            let mut clients = ProxyClients::new(pool, resolver, config.https.enabled);
+            let mut routes = Vec::with_capacity(config.routes.len() + 1);
+            for route in &config.routes {
+                let upstream = build_upstream(&route.proxy_address, &route.upstream, &mut clients)?;
+                upstreams.push(upstream.clone());
+                routes.push(Route::new(route, upstream)?);
+            }
+            // the upstream of `proxy_address` takes the requests no route matches
+            if let Some(dest) = &config.reverse_proxy.proxy_address {
+                let upstream = build_upstream(dest, &config.reverse_proxy.upstream, &mut clients)?;
+                upstreams.push(upstream.clone());
+                routes.push(Route::catch_all(upstream));
+            }
+            if routes.is_empty() {
+                return Err(ServerError::MissingProperty("reverse_proxy.proxy_address").into());
+            }
+            let identity = config
+                .identity
+                .as_ref()
+                .map(|identity| build_identity(identity, groups.clone()))
+                .transpose()?;
+            let error_page = config
+                .reverse_proxy
+                .error_page
+                .as_ref()
+                .map(std::fs::read_to_string)
+                .transpose()?;
+            let service = AuthRevPrxSvc::new(
+                Router::new(routes)?,
+                authenticator.clone(),
+                session_manager.clone(),
+                trusted_proxies,
+                ProxyOptions {
+                    forwarded_headers: ForwardedHeaders::new(
+                        config.reverse_proxy.forwarded_headers.unwrap_or_default(),
+                        config.https.enabled,
+                        config.listen_port,
+                    ),
+                    identity,
+                    via: config.reverse_proxy.via.unwrap_or(true),
+                    rewrite_location: config.reverse_proxy.rewrite_location.unwrap_or(true),
+                    rewrite_cookies: config.reverse_proxy.rewrite_cookies.unwrap_or(true),
+                    upgrade_idle_timeout: Duration::from_secs(
+                        config
+                            .reverse_proxy
+                            .upgrade_idle_timeout
+                            .unwrap_or(DEFAULT_UPGRADE_IDLE_TIMEOUT),
+                    ),
+                    request_timeout: Duration::from_secs(
+                        config
+                            .reverse_proxy
+                            .request_timeout
+                            .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+                    ),
+                    body_idle_timeout: Duration::from_secs(
+                        config
+                            .reverse_proxy
+                            .body_idle_timeout
+                            .unwrap_or(DEFAULT_BODY_IDLE_TIMEOUT),
+                    ),
+                    retries: config.reverse_proxy.retries.unwrap_or(DEFAULT_RETRIES),
+                    retry_backoff: Duration::from_millis(
+                        config
+                            .reverse_proxy
+                            .retry_backoff
+                            .unwrap_or(DEFAULT_RETRY_BACKOFF),
+                    ),
+                    error_page,
+                },
+            );
 
             match config.https.enabled {
                 true => {
@@ -117,18 +370,294 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                             .key
                             .ok_or(ServerError::MissingProperty("https.cert"))?,
                     )?;
-                    ProxyServer::new(HttpsAuthRevPrx::new(certs, key, service)?)
+                    ProxyServer::new(HttpsAuthRevPrx::new(certs, key, client_ca, service)?)
                 }
                 false => ProxyServer::new(HttpAuthRevPrx::new(service)),
             }
         }
     };
 
+    let server = server.with_upstreams(upstreams.clone());
+    let server = match config.admin {
+        Some(admin) => server.with_admin(AdminServer::new(
+            admin.socket,
+            AdminSvc::new(session_manager, upstreams, admin.token),
+        )),
+        None => server,
+    };
+
     // server.run_workers(addr, config.workers)?;
     server.run(addr)?;
+    if let Some(session_store) = session_store {
+        session_store.flush();
+    }
     Ok(())
 }
 
+fn build_store(
+    storage: &str,
+    config: &SessionConfig,
+) -> Result<Arc<dyn SessionStore + Send + Sync>, Box<dyn std::error::Error + Send + Sync>> {
+    let store: Arc<dyn SessionStore + Send + Sync> = match storage {
+        "memory" => match &config.snapshot_path {
+            Some(path) => {
+                let store = Arc::new(MemoryStore::with_snapshot(path, config.session_expire));
+                let interval = config
+                    .snapshot_interval
+                    .unwrap_or(DEFAULT_SNAPSHOT_INTERVAL);
+                store.spawn_snapshot_task(Duration::from_secs(interval));
+                store.spawn_cleanup_task(cleanup_interval(config), config.session_expire);
+                store
+            }
+            None => {
+                let store = Arc::new(MemoryStore::new());
+                store.spawn_cleanup_task(cleanup_interval(config), config.session_expire);
+                store
+            }
+        },
+        "redis" => {
+            let store = Arc::new(RedisStore::new(
+                redis_target(config)?,
+                config.session_expire,
+            )?);
+            match config.cache_ttl {
+                Some(ttl) if ttl > 0 => CachedStore::new(
+                    store,
+                    Duration::from_secs(ttl),
+                    config.cache_capacity.unwrap_or(DEFAULT_CACHE_CAPACITY),
+                ),
+                _ => store,
+            }
+        }
+        "sqlite" => {
+            let path = config
+                .sqlite_path
+                .as_ref()
+                .ok_or(ServerError::MissingProperty("session.sqlite_path"))?;
+            let store = Arc::new(SqliteStore::new(path, config.session_expire)?);
+            store.spawn_cleanup_task(cleanup_interval(config));
+            store
+        }
+        "postgres" => {
+            let conn = config
+                .postgres_conn
+                .as_ref()
+                .ok_or(ServerError::MissingProperty("session.postgres_conn"))?;
+            let store = Arc::new(PostgresStore::new(conn, config.session_expire)?);
+            store.spawn_cleanup_task(cleanup_interval(config));
+            store
+        }
+        _ => {
+            return Err(std::io::Error::other(
+                "Session storage should be `memory`, `redis`, `sqlite`, `postgres` or `cookie`",
+            )
+            .into())
+        }
+    };
+    Ok(store)
+}
+
+/// Build the upstream of the servers at `addresses`, whose health is checked if
+/// `health_check_path` is set
+fn build_upstream(
+    addresses: &ProxyAddress,
+    config: &UpstreamConfig,
+    clients: &mut ProxyClients,
+) -> Result<Arc<Upstream>, Box<dyn std::error::Error + Send + Sync>> {
+    let servers = addresses
+        .addresses()
+        .iter()
+        .map(|address| {
+            Server::new(
+                address,
+                clients.for_address(address)?,
+                clients.resolver().clone(),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if servers.is_empty() {
+        return Err(ServerError::MissingProperty("proxy_address").into());
+    }
+    let mut upstream = Upstream::new(
+        servers,
+        config.balance.unwrap_or_default(),
+        Duration::from_secs(config.fail_backoff.unwrap_or(DEFAULT_FAIL_BACKOFF)),
+    );
+    if let Some(path) = &config.health_check_path {
+        upstream = upstream.with_health_check(HealthCheck {
+            path: path.clone(),
+            interval: Duration::from_secs(
+                config
+                    .health_check_interval
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+            ),
+            timeout: Duration::from_secs(
+                config
+                    .health_check_timeout
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT),
+            ),
+            fails: config
+                .health_check_fails
+                .unwrap_or(DEFAULT_HEALTH_CHECK_FAILS),
+        });
+    }
+    Ok(Arc::new(upstream))
+}
+
+fn build_identity(
+    config: &IdentityConfig,
+    groups: Arc<Groups>,
+) -> Result<IdentityHeaders, Box<dyn std::error::Error + Send + Sync>> {
+    let jwt = match (&config.jwt_header, &config.jwt_secret) {
+        (Some(header), Some(secret)) => Some((
+            header.as_str(),
+            JwtSigner::new(secret, config.jwt_ttl.unwrap_or(DEFAULT_JWT_TTL))?,
+        )),
+        (Some(_), None) => return Err(ServerError::MissingProperty("identity.jwt_secret").into()),
+        // a secret without header would silently send no assertion
+        (None, Some(_)) => return Err(ServerError::MissingProperty("identity.jwt_header").into()),
+        (None, None) => None,
+    };
+    Ok(IdentityHeaders::new(
+        config.user_header.as_deref(),
+        config.groups_header.as_deref(),
+        jwt,
+        groups,
+    )?)
+}
+
+fn cleanup_interval(config: &SessionConfig) -> Duration {
+    Duration::from_secs(config.cleanup_interval.unwrap_or(DEFAULT_CLEANUP_INTERVAL))
+}
+
+/// The Redis server configured in the `[session]` section
+fn redis_target(config: &SessionConfig) -> Result<RedisTarget, ServerError> {
+    let target =
+        match (&config.redis_cluster_nodes, &config.redis_sentinels) {
+            (Some(nodes), _) => RedisTarget::Cluster(nodes.clone()),
+            (None, Some(sentinels)) => RedisTarget::Sentinel {
+                sentinels: sentinels.clone(),
+                master: config.redis_sentinel_master.clone().ok_or(
+                    ServerError::MissingProperty("session.redis_sentinel_master"),
+                )?,
+                node: config.redis_conn.clone(),
+            },
+            (None, None) => RedisTarget::Single(
+                config
+                    .redis_conn
+                    .clone()
+                    .ok_or(ServerError::MissingProperty("session.redis_conn"))?,
+            ),
+        };
+    Ok(target)
+}
+
+/// Track the health of the store, and keep sessions in memory while it is unavailable if the
+/// outage policy is `memory`
+fn monitor_store(
+    store: Arc<dyn SessionStore + Send + Sync>,
+    config: &SessionConfig,
+    health: &Arc<StoreHealth>,
+) -> Arc<dyn SessionStore + Send + Sync> {
+    let store = Arc::new(MonitoredStore::new(store, health.clone()));
+    match config.store_outage.unwrap_or_default() {
+        OutagePolicy::Memory => {
+            let store = FallbackStore::new(store);
+            store.spawn_cleanup_task(cleanup_interval(config), config.session_expire);
+            Arc::new(store)
+        }
+        _ => store,
+    }
+}
+
+fn run_migrate_command(
+    command: MigrateSessionsCommand,
+    config: &SessionConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let from = migration_store(&command.from, config)?;
+    let to = migration_store(&command.to, config)?;
+    let report = migrate(&*from, &*to, config.session_expire)?;
+    println!(
+        "Migrated {} sessions, left out {} expired and {} unreadable sessions",
+        report.migrated, report.expired, report.skipped
+    );
+    Ok(())
+}
+
+fn run_purge_legacy_command(
+    command: PurgeLegacySessionsCommand,
+    config: &SessionConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let target = match command.redis {
+        Some(conn) => RedisTarget::Single(conn),
+        None => redis_target(config)?,
+    };
+    let purged = RedisStore::new(target, config.session_expire)?.purge_legacy()?;
+    println!("Deleted {} sessions of older watchdawg versions", purged);
+    Ok(())
+}
+
+/// Build the store given to `migrate-sessions`, either a storage name using the `[session]`
+/// section, or a storage with its location
+fn migration_store(
+    spec: &str,
+    config: &SessionConfig,
+) -> Result<Arc<dyn SessionStore + Send + Sync>, Box<dyn std::error::Error + Send + Sync>> {
+    let max_age = config.session_expire;
+    let store: Arc<dyn SessionStore + Send + Sync> = match spec.split_once(':') {
+        Some(("memory", path)) => Arc::new(MemoryStore::with_snapshot(path, max_age)),
+        Some(("sqlite", path)) => Arc::new(SqliteStore::new(path, max_age)?),
+        Some(("postgres" | "postgresql", _)) => Arc::new(PostgresStore::new(spec, max_age)?),
+        Some(("redis" | "rediss" | "redis+unix", _)) => Arc::new(RedisStore::new(
+            RedisTarget::Single(spec.to_string()),
+            max_age,
+        )?),
+        Some(_) => {
+            return Err(std::io::Error::other(format!("Unknown session store `{}`", spec)).into())
+        }
+        // sessions in memory can only be reached through the snapshot
+        None if spec == "memory" && config.snapshot_path.is_none() => {
+            return Err(ServerError::MissingProperty("session.snapshot_path").into())
+        }
+        None if spec == "cookie" => {
+            return Err(
+                std::io::Error::other("Sessions in cookies are not kept on the server").into(),
+            )
+        }
+        None => build_store(spec, config)?,
+    };
+    Ok(store)
+}
+
+fn run_sessions_command(
+    command: SessionsCommand,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let admin = config
+        .admin
+        .as_ref()
+        .ok_or(ServerError::MissingProperty("admin.socket"))?;
+    let client = AdminClient::new(Path::new(&admin.socket), &admin.token);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async move {
+        match command.action {
+            SessionsAction::List(_) => print_sessions(&client.list_sessions().await?),
+            SessionsAction::Revoke(revoke) => {
+                client.revoke_session(&revoke.session_id).await?;
+                println!("Revoked session {}", revoke.session_id);
+            }
+            SessionsAction::RevokeUser(revoke) => {
+                let revoked = client.revoke_user(&revoke.user).await?;
+                println!("Revoked {} sessions of {}", revoked, revoke.user);
+            }
+        }
+        Ok(())
+    })
+}
+
 #[derive(FromArgs)]
 #[argh(
     description = "An authentication server for nginx's \"auth_request\", using HTTP basic authentication and htpasswd, can also work standalone"
@@ -139,6 +668,91 @@ struct Args {
         description = "the path to the config file, \"config.toml\" by default"
     )]
     config: Option<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Sessions(SessionsCommand),
+    MigrateSessions(MigrateSessionsCommand),
+    PurgeLegacySessions(PurgeLegacySessionsCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "sessions",
+    description = "manage the sessions of a running watchdawg through its admin socket"
+)]
+struct SessionsCommand {
+    #[argh(subcommand)]
+    action: SessionsAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum SessionsAction {
+    List(ListSessions),
+    Revoke(RevokeSession),
+    RevokeUser(RevokeUserSessions),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list", description = "list all active sessions")]
+struct ListSessions {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "revoke", description = "revoke a session")]
+struct RevokeSession {
+    #[argh(positional, description = "the session ID")]
+    session_id: String,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "revoke-user",
+    description = "revoke all sessions of a user"
+)]
+struct RevokeUserSessions {
+    #[argh(positional, description = "the user name")]
+    user: String,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "migrate-sessions",
+    description = "copy all live sessions from one session store to another"
+)]
+struct MigrateSessionsCommand {
+    #[argh(
+        option,
+        description = "the store to copy from, a storage configured in the [session] section, or one of `memory:<snapshot path>`, `redis://...`, `sqlite:<path>` and `postgres://...`"
+    )]
+    from: String,
+    #[argh(
+        option,
+        description = "the store to copy to, in the same form as --from"
+    )]
+    to: String,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "purge-legacy-sessions",
+    description = "delete the sessions which watchdawg versions without users left in Redis"
+)]
+struct PurgeLegacySessionsCommand {
+    #[argh(
+        option,
+        description = "the Redis server, `redis://...`, the one in the [session] section by default"
+    )]
+    redis: Option<String>,
 }
 
 #[derive(Error, Debug)]
@@ -1,27 +1,68 @@
-use crate::service::TcpService;
+use crate::{
+    admin::server::AdminServer,
+    service::{auth_reverse_proxy::upstream::Upstream, TcpService},
+};
 use std::sync::Arc;
 use tokio::net::{TcpListener, ToSocketAddrs};
-use tracing::error;
+use tracing::{error, info};
 
 pub struct ProxyServer {
     service: Arc<dyn TcpService + Send + Sync + 'static>,
+    admin: Option<Arc<AdminServer>>,
+    upstreams: Vec<Arc<Upstream>>,
 }
 
 impl ProxyServer {
     pub fn new(service: impl TcpService + Send + Sync + 'static) -> Self {
         let service = Arc::new(service);
-        Self { service }
+        Self {
+            service,
+            admin: None,
+            upstreams: Vec::new(),
+        }
     }
 
+    /// Check the health of the upstreams while running
+    pub fn with_upstreams(mut self, upstreams: Vec<Arc<Upstream>>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+
+    /// Also serve the admin API while running
+    pub fn with_admin(mut self, admin: AdminServer) -> Self {
+        self.admin = Some(Arc::new(admin));
+        self
+    }
+
+    /// Serve until a shutdown signal (Ctrl+C or SIGTERM) is received
     pub fn run(&self, addr: impl ToSocketAddrs) -> Result<(), std::io::Error> {
         let rt = tokio::runtime::Runtime::new()?;
         rt.block_on(async move {
             let listener = TcpListener::bind(addr).await?;
+            for upstream in &self.upstreams {
+                upstream.spawn_health_checks();
+            }
+            if let Some(admin) = &self.admin {
+                let admin_listener = admin.bind()?;
+                let admin = admin.clone();
+                tokio::spawn(async move { admin.serve(admin_listener).await });
+            }
+            let shutdown = shutdown_signal();
+            tokio::pin!(shutdown);
             loop {
-                let (stream, _addr) = listener.accept().await?;
+                let (stream, peer) = tokio::select! {
+                    accepted = listener.accept() => accepted?,
+                    _ = &mut shutdown => {
+                        info!("Shutting down");
+                        if let Some(admin) = &self.admin {
+                            let _ = std::fs::remove_file(admin.socket());
+                        }
+                        return Ok(());
+                    }
+                };
                 let service = self.service.clone();
                 tokio::spawn(async move {
-                    if let Err(err) = service.serve_tcp(stream).await {
+                    if let Err(err) = service.serve_tcp(stream, peer).await {
                         error!("Failed to handle connection from: {}", err);
                     }
                 });
@@ -30,3 +71,31 @@ impl ProxyServer {
     }
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -2,6 +2,7 @@ use super::AuthOnlySvc;
 use crate::service::{TcpService, TcpServiceError};
 use async_trait::async_trait;
 use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
 use tokio::net::TcpStream;
 
 pub struct HttpAuthOnly {
@@ -16,9 +17,13 @@ impl HttpAuthOnly {
 
 #[async_trait]
 impl TcpService for HttpAuthOnly {
-    async fn serve_tcp(&self, incoming: TcpStream) -> Result<(), TcpServiceError> {
+    async fn serve_tcp(
+        &self,
+        incoming: TcpStream,
+        peer: SocketAddr,
+    ) -> Result<(), TcpServiceError> {
         let io = TokioIo::new(incoming);
-        let auth_svc = self.service.clone();
+        let auth_svc = self.service.with_peer(peer);
         hyper::server::conn::http1::Builder::new()
             .serve_connection(io, auth_svc)
             .await
@@ -1,10 +1,12 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use super::AuthOnlySvc;
-use crate::service::{TcpService, TcpServiceError};
+use crate::{
+    service::{TcpService, TcpServiceError},
+    utils::{cert_fingerprint, tls_server_config},
+};
 use async_trait::async_trait;
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use rustls::ServerConfig;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsAcceptor;
@@ -18,16 +20,10 @@ impl HttpsAuthOnly {
     pub fn new(
         certs: Vec<CertificateDer<'static>>,
         key: PrivateKeyDer<'static>,
+        client_ca: Option<Vec<CertificateDer<'static>>>,
         service: AuthOnlySvc,
     ) -> Result<Self, rustls::Error> {
-        let mut server_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
-
-        server_config.alpn_protocols.push(b"h2".to_vec());
-        server_config.alpn_protocols.push(b"http/1.1".to_vec());
-        server_config.alpn_protocols.push(b"http/1.0".to_vec());
-
+        let server_config = tls_server_config(certs, key, client_ca)?;
         let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
 
         Ok(Self {
@@ -39,14 +35,24 @@ impl HttpsAuthOnly {
 
 #[async_trait]
 impl TcpService for HttpsAuthOnly {
-    async fn serve_tcp(&self, incoming: TcpStream) -> Result<(), TcpServiceError> {
+    async fn serve_tcp(
+        &self,
+        incoming: TcpStream,
+        peer: SocketAddr,
+    ) -> Result<(), TcpServiceError> {
         let tls_stream = match self.tls_acceptor.accept(incoming).await {
             Ok(stream) => stream,
             Err(err) => return Err(TcpServiceError::Io(err)),
         };
+        let client_cert = tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(cert_fingerprint);
         let io = TokioIo::new(tls_stream);
 
-        let service = self.service.clone();
+        let service = self.service.with_peer(peer).with_client_cert(client_cert);
 
         hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
             .serve_connection(io, service)
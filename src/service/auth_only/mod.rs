@@ -1,9 +1,9 @@
 use crate::{
     auth::Authenticator,
+    forwarded::TrustedProxies,
     session::SessionManager,
-    utils::{header_has_valid_auth, headers_has_valid_session, ok_empty, req_auth},
+    utils::{authenticate, client_info, ok_empty},
 };
-use concat_string::concat_string;
 use http_body_util::combinators::BoxBody;
 use hyper::{
     body::{Bytes, Incoming},
@@ -11,7 +11,7 @@ use hyper::{
     service::Service,
     Request, Response,
 };
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
 
 pub mod http;
 pub mod https;
@@ -19,37 +19,54 @@ pub mod https;
 #[derive(Clone)]
 pub struct AuthOnlySvc {
     inner: Arc<AuthOnlySvcImpl>,
+    peer: Option<SocketAddr>,
+    /// Fingerprint of the TLS client certificate of the connection
+    client_cert: Option<Arc<str>>,
 }
 
 struct AuthOnlySvcImpl {
     auth: Arc<dyn Authenticator + Send + Sync + 'static>,
     auth_return_header_name: HeaderName,
-    session_manager: SessionManager,
-    cookie_generator: Box<dyn Fn(&str) -> String + Send + Sync>,
+    session_manager: Arc<SessionManager>,
+    trusted_proxies: Arc<TrustedProxies>,
 }
 
 impl AuthOnlySvc {
     pub fn new(
-        authenticator: impl Authenticator + Send + Sync + 'static,
+        authenticator: Arc<dyn Authenticator + Send + Sync + 'static>,
         auth_return_header_name: &str,
-        session_manager: SessionManager,
+        session_manager: Arc<SessionManager>,
+        trusted_proxies: Arc<TrustedProxies>,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let cookie_name_str = session_manager.cookie_name.clone();
-        let cookie_generator =
-            Box::new(move |session_id: &str| concat_string!(cookie_name_str, "=", session_id));
-
         let auth_return_header_name =
             HeaderName::from_lowercase(auth_return_header_name.to_ascii_lowercase().as_bytes())?;
         let inner = AuthOnlySvcImpl {
-            auth: Arc::new(authenticator),
+            auth: authenticator,
             auth_return_header_name,
             session_manager,
-            cookie_generator,
+            trusted_proxies,
         };
         Ok(Self {
             inner: inner.into(),
+            peer: None,
+            client_cert: None,
         })
     }
+
+    /// Clone the service for a connection from `peer`
+    pub fn with_peer(&self, peer: SocketAddr) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            peer: Some(peer),
+            client_cert: None,
+        }
+    }
+
+    /// Set the fingerprint of the TLS client certificate of the connection
+    pub fn with_client_cert(mut self, fingerprint: Option<String>) -> Self {
+        self.client_cert = fingerprint.map(Into::into);
+        self
+    }
 }
 
 impl Service<Request<Incoming>> for AuthOnlySvc {
@@ -57,21 +74,20 @@ impl Service<Request<Incoming>> for AuthOnlySvc {
     type Error = Box<dyn std::error::Error + Send + Sync>;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let headers = req.headers();
-        let mut set_session: Option<String> = None;
-
-        // if there's not valid session
-        if headers_has_valid_session(headers, &self.inner.session_manager).is_none() {
-            // then check if there's valid authentication
-            if header_has_valid_auth(headers, self.inner.auth.as_ref()) {
-                // if yes give new session
-                let session_id = self.inner.session_manager.create_session();
-                set_session = Some((self.inner.cookie_generator)(&session_id));
-            } else {
-                // else return unauthroized and reqest authentication
-                return Box::pin(async move { Ok(req_auth()) });
-            }
-        }
+        let set_session = match authenticate(
+            &req,
+            &self.inner.session_manager,
+            self.inner.auth.as_ref(),
+            &client_info(
+                req.headers(),
+                self.peer,
+                self.client_cert.as_deref(),
+                &self.inner.trusted_proxies,
+            ),
+        ) {
+            Ok(authenticated) => authenticated.set_cookie,
+            Err(resp) => return Box::pin(async move { Ok(*resp) }),
+        };
         let auth_return_header_name = self.inner.auth_return_header_name.clone();
         Box::pin(async move {
             let mut resp = ok_empty();
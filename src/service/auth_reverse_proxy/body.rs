@@ -0,0 +1,127 @@
+use crate::client::ProxyClientError;
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::time::{Instant, Sleep};
+use tracing::warn;
+
+/// A response body which fails once the upstream sends nothing for `timeout`, so a stalled
+/// upstream does not hold the client and the connection forever. Only the time spent waiting for
+/// the upstream counts, not the time the client takes to read what was sent.
+pub struct IdleTimeout {
+    inner: BoxBody<Bytes, hyper::Error>,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    /// Whether the upstream is waited for, since `sleep` was last reset
+    waiting: bool,
+}
+
+impl IdleTimeout {
+    pub fn new(inner: BoxBody<Bytes, hyper::Error>, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            waiting: false,
+        }
+    }
+}
+
+impl Body for IdleTimeout {
+    type Data = Bytes;
+    type Error = ProxyClientError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            self.waiting = false;
+            return Poll::Ready(frame.map(|frame| frame.map_err(Into::into)));
+        }
+        if !self.waiting {
+            self.waiting = true;
+            let deadline = Instant::now() + self.timeout;
+            self.sleep.as_mut().reset(deadline);
+        }
+        ready!(self.sleep.as_mut().poll(cx));
+        warn!(
+            "No response body received for {}s, aborting the response",
+            self.timeout.as_secs()
+        );
+        Poll::Ready(Some(Err(ProxyClientError::BodyTimeout)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::sync::mpsc;
+
+    /// A body of the data sent to the channel
+    struct Channel(mpsc::Receiver<Bytes>);
+
+    impl Body for Channel {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            self.0
+                .poll_recv(cx)
+                .map(|data| data.map(|data| Ok(Frame::data(data))))
+        }
+    }
+
+    fn channel() -> (mpsc::Sender<Bytes>, BoxBody<Bytes, hyper::Error>) {
+        let (tx, rx) = mpsc::channel(1);
+        (tx, Channel(rx).boxed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_when_idle() {
+        let (tx, body) = channel();
+        let mut body = IdleTimeout::new(body, Duration::from_secs(10));
+
+        tokio::time::advance(Duration::from_secs(9)).await;
+        tx.send(Bytes::from_static(b"a")).await.unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "a");
+
+        // the time the client takes to read does not count
+        tokio::time::advance(Duration::from_secs(60)).await;
+        tx.send(Bytes::from_static(b"b")).await.unwrap();
+        assert!(body.frame().await.unwrap().is_ok());
+
+        let started = Instant::now();
+        let err = body.frame().await.unwrap().unwrap_err();
+        assert!(matches!(err, ProxyClientError::BodyTimeout));
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_with_the_body() {
+        let (tx, body) = channel();
+        let mut body = IdleTimeout::new(body, Duration::from_secs(10));
+        tx.send(Bytes::from_static(b"a")).await.unwrap();
+        drop(tx);
+        assert!(body.frame().await.unwrap().is_ok());
+        assert!(body.frame().await.is_none());
+    }
+}
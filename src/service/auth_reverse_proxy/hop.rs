@@ -0,0 +1,240 @@
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+        TRANSFER_ENCODING, UPGRADE, VIA,
+    },
+    HeaderMap, Request, Response, StatusCode, Version,
+};
+
+/// The name watchdawg gives itself in `Via`
+const PSEUDONYM: &str = "watchdawg";
+
+/// Headers only meaningful for a single connection, which are not forwarded (RFC 9110 section
+/// 7.6.1), `Keep-Alive` and `Proxy-Connection` are obsolete but still sent by some clients
+const HOP_BY_HOP: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Remove the hop-by-hop headers and those named in `Connection`
+fn strip(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in named.iter().chain(&HOP_BY_HOP) {
+        headers.remove(name);
+    }
+}
+
+/// Whether a comma separated header has `token`
+pub fn has_token(value: &HeaderValue, token: &str) -> bool {
+    value.to_str().is_ok_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Strip the hop-by-hop headers of a request to forward. The headers of the next hop are
+/// generated again: `Upgrade` of an HTTP/1.1 upgrade request, and `TE: trailers` over HTTP/2,
+/// which gRPC needs.
+pub fn strip_request<B>(req: &mut Request<B>, upgrade: bool) {
+    let headers = req.headers_mut();
+    let trailers = headers
+        .get_all(TE)
+        .iter()
+        .any(|value| has_token(value, "trailers"));
+    let protocol = headers.get(UPGRADE).cloned();
+    strip(headers);
+
+    match req.version() {
+        Version::HTTP_2 if trailers => {
+            req.headers_mut()
+                .insert(TE, HeaderValue::from_static("trailers"));
+        }
+        Version::HTTP_2 => (),
+        _ => {
+            if let Some(protocol) = protocol.filter(|_| upgrade) {
+                let headers = req.headers_mut();
+                headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+                headers.insert(UPGRADE, protocol);
+            }
+        }
+    }
+}
+
+/// Strip the hop-by-hop headers of a response to forward, `Upgrade` of a `101` response is kept
+/// for HTTP/1.1 clients
+pub fn strip_response<B>(resp: &mut Response<B>, client_version: Version) {
+    let protocol = resp.headers().get(UPGRADE).cloned();
+    strip(resp.headers_mut());
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS && client_version != Version::HTTP_2 {
+        if let Some(protocol) = protocol {
+            let headers = resp.headers_mut();
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, protocol);
+        }
+    }
+}
+
+/// Append watchdawg to `Via`, with the protocol version the message was received with
+pub fn add_via(headers: &mut HeaderMap, received: Version) {
+    let version = match received {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", version, PSEUDONYM)) {
+        headers.append(VIA, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The hop-by-hop headers of RFC 9110 section 7.6.1, and the obsolete ones still in use
+    const RFC_9110: [&str; 9] = [
+        "connection",
+        "proxy-connection",
+        "keep-alive",
+        "te",
+        "transfer-encoding",
+        "upgrade",
+        "proxy-authenticate",
+        "proxy-authorization",
+        "trailer",
+    ];
+
+    fn request(version: Version, headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().version(version);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn strips_every_hop_by_hop_header() {
+        let mut headers = RFC_9110.iter().map(|name| (*name, "x")).collect::<Vec<_>>();
+        headers.push(("accept", "*/*"));
+        for version in [Version::HTTP_10, Version::HTTP_11, Version::HTTP_2] {
+            let mut req = request(version, &headers);
+            strip_request(&mut req, false);
+            assert_eq!(req.headers().len(), 1, "{:?}", req.headers());
+            assert!(req.headers().contains_key("accept"));
+
+            let mut resp = Response::new(());
+            for (name, value) in &headers {
+                resp.headers_mut().append(
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                );
+            }
+            strip_response(&mut resp, version);
+            assert_eq!(resp.headers().len(), 1, "{:?}", resp.headers());
+        }
+    }
+
+    #[test]
+    fn strips_headers_named_in_connection() {
+        let mut req = request(
+            Version::HTTP_11,
+            &[
+                ("connection", " X-Hop-One ,x-HOP-two,,  "),
+                ("connection", "\tX-Hop-Three"),
+                ("x-hop-one", "1"),
+                ("x-hop-two", "2"),
+                ("x-hop-three", "3"),
+                ("x-end-to-end", "kept"),
+            ],
+        );
+        strip_request(&mut req, false);
+        let names = req
+            .headers()
+            .keys()
+            .map(HeaderName::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["x-end-to-end"]);
+    }
+
+    #[test]
+    fn keeps_te_trailers() {
+        let headers = [("te", "gzip, Trailers;q=1"), ("te", "trailers")];
+        let mut req = request(Version::HTTP_2, &headers);
+        strip_request(&mut req, false);
+        assert_eq!(values(req.headers(), "te"), ["trailers"]);
+
+        let mut req = request(Version::HTTP_2, &[("te", "gzip")]);
+        strip_request(&mut req, false);
+        assert!(!req.headers().contains_key(TE));
+    }
+
+    #[test]
+    fn keeps_upgrade_of_upgrade_requests() {
+        let headers = [
+            ("connection", "keep-alive, Upgrade"),
+            ("upgrade", "websocket"),
+        ];
+        let mut req = request(Version::HTTP_11, &headers);
+        strip_request(&mut req, true);
+        assert_eq!(values(req.headers(), "connection"), ["upgrade"]);
+        assert_eq!(values(req.headers(), "upgrade"), ["websocket"]);
+
+        let mut req = request(Version::HTTP_11, &headers);
+        strip_request(&mut req, false);
+        assert!(req.headers().is_empty());
+
+        let mut resp = Response::new(());
+        *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        resp.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        resp.headers_mut()
+            .insert(UPGRADE, HeaderValue::from_static("websocket"));
+        let mut h2_resp = Response::new(());
+        *h2_resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        *h2_resp.headers_mut() = resp.headers().clone();
+
+        strip_response(&mut resp, Version::HTTP_11);
+        assert_eq!(values(resp.headers(), "connection"), ["upgrade"]);
+        assert_eq!(values(resp.headers(), "upgrade"), ["websocket"]);
+        strip_response(&mut h2_resp, Version::HTTP_2);
+        assert!(h2_resp.headers().is_empty());
+    }
+
+    #[test]
+    fn appends_to_via() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VIA, HeaderValue::from_static("1.0 fred, 1.1 p.example.net"));
+        add_via(&mut headers, Version::HTTP_11);
+        assert_eq!(
+            values(&headers, "via"),
+            ["1.0 fred, 1.1 p.example.net", "1.1 watchdawg"]
+        );
+
+        let mut headers = HeaderMap::new();
+        add_via(&mut headers, Version::HTTP_2);
+        assert_eq!(values(&headers, "via"), ["2 watchdawg"]);
+    }
+}
@@ -2,6 +2,7 @@ use super::AuthRevPrxSvc;
 use crate::service::{TcpService, TcpServiceError};
 use async_trait::async_trait;
 use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
 use tokio::net::TcpStream;
 use tracing::error;
 
@@ -17,12 +18,17 @@ impl HttpAuthRevPrx {
 
 #[async_trait]
 impl TcpService for HttpAuthRevPrx {
-    async fn serve_tcp(&self, incoming: TcpStream) -> Result<(), TcpServiceError> {
+    async fn serve_tcp(
+        &self,
+        incoming: TcpStream,
+        peer: SocketAddr,
+    ) -> Result<(), TcpServiceError> {
         let io = TokioIo::new(incoming);
-        let rev_prx = self.service.clone();
+        let rev_prx = self.service.with_peer(peer);
         tokio::task::spawn(async move {
             if let Err(err) = hyper::server::conn::http1::Builder::new()
                 .serve_connection(io, rev_prx)
+                .with_upgrades()
                 .await
             {
                 error!("Failed to handle connection: {}", err);
@@ -1,111 +1,176 @@
 use crate::{
     auth::Authenticator,
-    client::ProxyClient,
+    client::ProxyClientError,
+    forwarded::{ForwardedHeaders, TrustedProxies},
+    identity::IdentityHeaders,
     session::SessionManager,
-    utils::{header_has_valid_auth, headers_has_valid_session, req_auth},
+    utils::{authenticate, client_info, empty, gateway_error, not_found, strip_cookie},
 };
-use concat_string::concat_string;
-use http_body_util::combinators::BoxBody;
+use body::IdleTimeout;
+use hop::{add_via, strip_request, strip_response};
+use http_body_util::{combinators::BoxBody, BodyExt};
 use hyper::{
-    body::{Bytes, Incoming},
+    body::{Body, Bytes, Incoming},
     header::{HeaderValue, AUTHORIZATION, HOST, SET_COOKIE},
     service::Service,
-    Request, Response, Uri,
+    Method, Request, Response, StatusCode,
 };
-use rustls_pki_types::ServerName;
-use std::{
-    future::Future,
-    net::{SocketAddr, ToSocketAddrs},
-    pin::Pin,
-    sync::Arc,
-};
-use tracing::{debug, error};
+use rewrite::ResponseRewrite;
+use router::Router;
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+use upgrade::{is_upgrade, proxy_upgrade};
+use upstream::{Selected, Upstream};
 
+mod body;
+mod hop;
 pub mod http;
 pub mod https;
+mod rewrite;
+pub mod router;
+pub mod upgrade;
+pub mod upstream;
 
 #[derive(Clone)]
 pub struct AuthRevPrxSvc {
     inner: Arc<AuthRevPrxSvcImpl>,
+    peer: Option<SocketAddr>,
+    /// Fingerprint of the TLS client certificate of the connection
+    client_cert: Option<Arc<str>>,
 }
 
 impl AuthRevPrxSvc {
     pub fn new(
-        dest: impl Into<String>,
-        authenticator: impl Authenticator + Send + Sync + 'static,
-        session_manager: SessionManager,
-        client: Arc<dyn ProxyClient + Send + Sync>,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let dest: String = dest.into();
-        let dest = dest.parse::<Uri>()?;
-        let port = dest
-            .port()
-            .map(|port| port.as_u16())
-            .unwrap_or(client.default_port());
-        let host = dest.host().unwrap().to_owned();
-        let addr = (host.as_str(), port).to_socket_addrs()?.next().unwrap();
-
-        let host_header = HeaderValue::from_str(host.as_str())?;
-
-        debug!("Proxy to domain: {}, address: {}", host, addr);
-
-        let max_age_str = session_manager.max_age.to_string();
-        let cookie_name_str = session_manager.cookie_name.clone();
-        let cookie_generator = Box::new(move |session_id: &str| {
-            concat_string!(
-                cookie_name_str,
-                "=",
-                session_id,
-                "; HttpOnly; Max-Age=",
-                max_age_str
-            )
-        });
-
-        let inner: AuthRevPrxSvcImpl = AuthRevPrxSvcImpl {
-            auth: Arc::new(authenticator),
+        router: Router,
+        authenticator: Arc<dyn Authenticator + Send + Sync + 'static>,
+        session_manager: Arc<SessionManager>,
+        trusted_proxies: Arc<TrustedProxies>,
+        options: ProxyOptions,
+    ) -> Self {
+        let inner = AuthRevPrxSvcImpl {
+            auth: authenticator,
             session_manager,
-            domain: ServerName::try_from(host)?,
-            addr,
-            client,
-            host_header,
-            cookie_generator,
+            trusted_proxies,
+            router,
+            options,
         };
-        Ok(AuthRevPrxSvc {
+        AuthRevPrxSvc {
             inner: inner.into(),
-        })
+            peer: None,
+            client_cert: None,
+        }
+    }
+
+    /// Clone the service for a connection from `peer`
+    pub fn with_peer(&self, peer: SocketAddr) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            peer: Some(peer),
+            client_cert: None,
+        }
+    }
+
+    /// Set the fingerprint of the TLS client certificate of the connection
+    pub fn with_client_cert(mut self, fingerprint: Option<String>) -> Self {
+        self.client_cert = fingerprint.map(Into::into);
+        self
     }
 }
 
 struct AuthRevPrxSvcImpl {
     auth: Arc<dyn Authenticator + Send + Sync + 'static>,
-    session_manager: SessionManager,
-    domain: ServerName<'static>,
-    addr: SocketAddr,
-    client: Arc<dyn ProxyClient + Send + Sync>,
-    host_header: HeaderValue,
-    cookie_generator: Box<dyn Fn(&str) -> String + Send + Sync>,
+    session_manager: Arc<SessionManager>,
+    trusted_proxies: Arc<TrustedProxies>,
+    /// Shared by all routes, so one session is valid for all upstreams
+    router: Router,
+    options: ProxyOptions,
+}
+
+/// How requests are forwarded
+pub struct ProxyOptions {
+    pub forwarded_headers: ForwardedHeaders,
+    pub identity: Option<IdentityHeaders>,
+    /// Add watchdawg to `Via` of requests and responses
+    pub via: bool,
+    /// Map the URLs of the server in `Location` and `Content-Location` back to those of watchdawg
+    pub rewrite_location: bool,
+    /// Map the `Path` and `Domain` of cookies set by the server back to those of watchdawg
+    pub rewrite_cookies: bool,
+    /// How long an upgraded connection is kept open without data sent either way
+    pub upgrade_idle_timeout: Duration,
+    /// How long to wait for the response including retries
+    pub request_timeout: Duration,
+    /// How long to wait for the next frame of a response body before it is aborted
+    pub body_idle_timeout: Duration,
+    /// How many times an idempotent request is sent again when no connection could be made
+    pub retries: u32,
+    /// The wait before the first retry, doubled for each retry
+    pub retry_backoff: Duration,
+    /// The HTML page of `502`, `503` and `504` responses
+    pub error_page: Option<String>,
 }
 
 impl Service<Request<Incoming>> for AuthRevPrxSvc {
-    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Response = Response<BoxBody<Bytes, ProxyClientError>>;
     type Error = Box<dyn std::error::Error + Send + Sync>;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, mut req: Request<Incoming>) -> Self::Future {
         debug!("Receive request: {:?}", req);
 
-        let mut set_cookie = None;
-        let headers = req.headers_mut();
-
-        if headers_has_valid_session(headers, &self.inner.session_manager).is_none() {
-            if !header_has_valid_auth(headers, self.inner.auth.as_ref()) {
-                return Box::pin(async move { Ok(req_auth()) });
-            } else {
-                let new_session = self.inner.session_manager.create_session();
-                let cookie = (self.inner.cookie_generator)(&new_session);
-                set_cookie = Some(cookie);
-            }
+        let client = client_info(
+            req.headers(),
+            self.peer,
+            self.client_cert.as_deref(),
+            &self.inner.trusted_proxies,
+        );
+        let authenticated = match authenticate(
+            &req,
+            &self.inner.session_manager,
+            self.inner.auth.as_ref(),
+            &client,
+        ) {
+            Ok(authenticated) => authenticated,
+            Err(resp) => return Box::pin(async move { Ok(local(*resp)) }),
+        };
+        let (user, set_cookie) = (authenticated.user, authenticated.set_cookie);
+        let Some(route) = self.inner.router.route(&req) else {
+            return Box::pin(async move { Ok(local(not_found())) });
+        };
+        // requests of the same user go to the same server with `session_hash`, the user stays the
+        // same when the session ID is rotated or the user logs in again
+        let Some(selected) = route.upstream().select(&user) else {
+            error!("No upstream server available for {}", req.uri());
+            let response = gateway_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                self.inner.options.error_page.as_deref(),
+            );
+            return Box::pin(async move { Ok(local(response)) });
+        };
+        let upstream = route.upstream().clone();
+        // the host the client asked for, in the URI with HTTP/2
+        let original_host = req.headers().get(HOST).cloned().or_else(|| {
+            req.uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        });
+        *req.uri_mut() = route.rewrite_uri(req.uri());
+        let upgrade = is_upgrade(&req);
+        let version = req.version();
+        let options = &self.inner.options;
+        let rewrite = (options.rewrite_location || options.rewrite_cookies).then(|| {
+            ResponseRewrite::new(
+                route.stripped_prefix().unwrap_or_default(),
+                original_host.as_ref(),
+                options.rewrite_location,
+                options.rewrite_cookies,
+            )
+        });
+        strip_request(&mut req, upgrade);
+        if options.via {
+            add_via(req.headers_mut(), version);
         }
+        let headers = req.headers_mut();
 
         if headers
             .get(AUTHORIZATION)
@@ -116,22 +181,61 @@ impl Service<Request<Incoming>> for AuthRevPrxSvc {
             headers.remove(AUTHORIZATION);
         }
 
-        if let Some(host) = headers.get_mut(HOST) {
-            *host = self.inner.host_header.clone();
+        // the session cookie is only meant for watchdawg
+        strip_cookie(headers, &self.inner.session_manager.cookie.name);
+
+        if let Some(identity) = &options.identity {
+            identity.apply(headers, &user);
         }
 
-        let domain = self.inner.domain.clone();
-        let addr = self.inner.addr;
-        let client = self.inner.client.clone();
+        let peer = self.peer.map(|peer| peer.ip());
+        options.forwarded_headers.apply(
+            headers,
+            peer,
+            original_host,
+            peer.is_some_and(|peer| self.inner.trusted_proxies.is_trusted(&peer)),
+        );
+
+        let inner = self.inner.clone();
 
         Box::pin(async move {
-            let mut response = match client.proxy_request(addr, domain, req).await {
+            let options = &inner.options;
+            let result = tokio::time::timeout(options.request_timeout, async {
+                match upgrade {
+                    true => {
+                        let server = selected.server().clone();
+                        *req.uri_mut() = server.request_uri(req.uri());
+                        req.headers_mut().insert(HOST, server.host_header.clone());
+                        let mut response =
+                            proxy_upgrade(req, selected, options.upgrade_idle_timeout).await?;
+                        if let Some(rewrite) = &rewrite {
+                            rewrite.apply(response.headers_mut(), &server);
+                        }
+                        Ok(response)
+                    }
+                    false => {
+                        let req = req.map(|body| body.boxed());
+                        forward(req, selected, &upstream, &user, options, rewrite.as_ref()).await
+                    }
+                }
+            })
+            .await
+            .unwrap_or(Err(ProxyClientError::Timeout));
+            let response = match result {
                 Ok(response) => response,
                 Err(err) => {
                     error!("Failed to forward request: {}", err);
-                    return Err(err.into());
+                    let response = gateway_error(err.status(), options.error_page.as_deref());
+                    return Ok(local(response));
                 }
             };
+            let mut response =
+                response.map(|body| IdleTimeout::new(body, options.body_idle_timeout).boxed());
+            let received = response.version();
+            strip_response(&mut response, version);
+            if options.via {
+                add_via(response.headers_mut(), received);
+            }
 
             if let Some(cookie) = set_cookie.and_then(|cookie| cookie.to_string().parse().ok()) {
                 response.headers_mut().append(SET_COOKIE, cookie);
@@ -141,3 +245,83 @@ impl Service<Request<Incoming>> for AuthRevPrxSvc {
         })
     }
 }
+
+/// A response of watchdawg itself, with the body type of proxied responses
+fn local(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+) -> Response<BoxBody<Bytes, ProxyClientError>> {
+    response.map(|body| body.map_err(ProxyClientError::from).boxed())
+}
+
+/// Forward the request to the selected server, which counts it as in progress until the response
+/// body is read. An idempotent request without body is sent again to the next server picked when
+/// no connection could be made, as it cannot have reached the server.
+async fn forward(
+    mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    mut selected: Selected,
+    upstream: &Arc<Upstream>,
+    user: &str,
+    options: &ProxyOptions,
+    rewrite: Option<&ResponseRewrite>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError> {
+    let replay = is_retryable(&req).then(|| {
+        let mut copy = Request::new(());
+        *copy.method_mut() = req.method().clone();
+        *copy.uri_mut() = req.uri().clone();
+        *copy.version_mut() = req.version();
+        *copy.headers_mut() = req.headers().clone();
+        copy
+    });
+    let uri = req.uri().clone();
+    let mut attempt = 0;
+    loop {
+        let server = selected.server();
+        *req.uri_mut() = server.request_uri(&uri);
+        // an HTTP/2 request has no `Host`, which is still needed to forward over HTTP/1.1
+        req.headers_mut().insert(HOST, server.host_header.clone());
+        let err = match server
+            .client
+            .proxy_request(&server.dest, server.domain.clone(), req)
+            .await
+        {
+            Ok(mut response) => {
+                selected.succeeded();
+                if let Some(rewrite) = rewrite {
+                    rewrite.apply(response.headers_mut(), server);
+                }
+                return Ok(response.map(|body| selected.track(body)));
+            }
+            Err(err) => err,
+        };
+        // a slow or overloaded server is not backed off, only one that cannot be reached or drops
+        // the connection
+        if err.is_connect() || err.is_reset() {
+            selected.failed();
+        }
+        let Some(replay) = replay
+            .as_ref()
+            .filter(|_| err.is_connect() && attempt < options.retries)
+        else {
+            return Err(err);
+        };
+        warn!(
+            "Failed to forward request to {}, retrying: {}",
+            server.name, err
+        );
+        tokio::time::sleep(options.retry_backoff.saturating_mul(1 << attempt.min(16))).await;
+        attempt += 1;
+        // the same server is tried again if no other is available
+        if let Some(next) = upstream.select(user) {
+            selected = next;
+        }
+        req = replay.clone().map(|()| empty());
+    }
+}
+
+/// Whether the request can be sent again, it is idempotent and has no body to replay
+fn is_retryable<B: Body>(req: &Request<B>) -> bool {
+    matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    ) && req.body().is_end_stream()
+}
@@ -0,0 +1,288 @@
+use super::upstream::Server;
+use hyper::{
+    header::{HeaderValue, CONTENT_LOCATION, LOCATION, SET_COOKIE},
+    HeaderMap, Uri,
+};
+
+/// Maps the URLs of the server in `Location`, `Content-Location` and the `Path` and `Domain` of
+/// `Set-Cookie` back to those the client sees, as nginx's `proxy_redirect` and
+/// `proxy_cookie_path` do. The base path of the server is replaced with the prefix the route
+/// stripped, and URLs of the server are made relative to the host the client asked for.
+pub struct ResponseRewrite {
+    /// The path prefix removed by the route, empty if none
+    prefix: String,
+    /// The host the client sent the request to, without port
+    host: Option<String>,
+    location: bool,
+    cookies: bool,
+}
+
+impl ResponseRewrite {
+    pub fn new(prefix: &str, host: Option<&HeaderValue>, location: bool, cookies: bool) -> Self {
+        let host = host.and_then(|host| host.to_str().ok()).map(|host| {
+            // an IPv6 address is enclosed in brackets
+            match host.rfind(']') {
+                Some(end) => host[..=end].to_owned(),
+                None => host.split(':').next().unwrap_or(host).to_owned(),
+            }
+        });
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            host,
+            location,
+            cookies,
+        }
+    }
+
+    /// Rewrite the headers of a response of `server`
+    pub fn apply(&self, headers: &mut HeaderMap, server: &Server) {
+        if self.location {
+            for name in [LOCATION, CONTENT_LOCATION] {
+                let rewritten = headers
+                    .get(&name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| self.location(value, server))
+                    .and_then(|value| HeaderValue::from_str(&value).ok());
+                if let Some(value) = rewritten {
+                    headers.insert(name, value);
+                }
+            }
+        }
+        if self.cookies {
+            let cookies = headers
+                .get_all(SET_COOKIE)
+                .iter()
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|cookie| self.cookie(cookie, server))
+                        .and_then(|cookie| HeaderValue::from_str(&cookie).ok())
+                        .unwrap_or_else(|| value.clone())
+                })
+                .collect::<Vec<_>>();
+            headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                headers.append(SET_COOKIE, cookie);
+            }
+        }
+    }
+
+    /// A URL of the server is made relative, with its path mapped. `None` if it is unchanged.
+    fn location(&self, value: &str, server: &Server) -> Option<String> {
+        let (url, fragment) = match value.find('#') {
+            Some(index) => value.split_at(index),
+            None => (value, ""),
+        };
+        let path = match url.as_bytes() {
+            [b'/', b'/', ..] => return None,
+            [b'/', ..] => url.to_owned(),
+            _ => {
+                let uri = url.parse::<Uri>().ok()?;
+                if !is_server(&uri, server) {
+                    return None;
+                }
+                uri.path_and_query()
+                    .map_or("/", |path| path.as_str())
+                    .to_owned()
+            }
+        };
+        let mapped = self.map_path(&path, &server.base_path);
+        if mapped.is_none() && path == url {
+            return None;
+        }
+        Some(format!("{}{}", mapped.unwrap_or(path), fragment))
+    }
+
+    /// The `Path` of a cookie is mapped, and a `Domain` of the server is replaced with the host
+    /// of the client, or removed if unknown. `None` if the cookie is unchanged.
+    fn cookie(&self, value: &str, server: &Server) -> Option<String> {
+        let mut parts = value.split(';');
+        let mut cookie = parts.next()?.to_owned();
+        let mut changed = false;
+        for attribute in parts {
+            let (name, attr_value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let (name, attr_value) = (name.trim(), attr_value.trim());
+            let rewritten = if name.eq_ignore_ascii_case("path") {
+                self.map_path(attr_value, &server.base_path)
+                    .map(|path| Some(format!("Path={}", path)))
+            } else if name.eq_ignore_ascii_case("domain")
+                && attr_value
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(server.dest.host())
+            {
+                Some(self.host.as_ref().map(|host| format!("Domain={}", host)))
+            } else {
+                None
+            };
+            match rewritten {
+                Some(Some(attribute)) => {
+                    cookie.push_str("; ");
+                    cookie.push_str(&attribute);
+                    changed = true;
+                }
+                Some(None) => changed = true,
+                None => {
+                    cookie.push(';');
+                    cookie.push_str(attribute);
+                }
+            }
+        }
+        changed.then_some(cookie)
+    }
+
+    /// Replace the base path of the server with the prefix of the route, `None` if the path is
+    /// outside the base path or there is nothing to replace
+    fn map_path(&self, path: &str, base: &str) -> Option<String> {
+        if base.is_empty() && self.prefix.is_empty() {
+            return None;
+        }
+        let rest = path
+            .strip_prefix(base)
+            .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?']))?;
+        Some(match rest.starts_with('/') {
+            true => format!("{}{}", self.prefix, rest),
+            false => format!("{}/{}", self.prefix, rest),
+        })
+    }
+}
+
+/// Whether `uri` is an absolute URL of `server`
+fn is_server(uri: &Uri, server: &Server) -> bool {
+    let (Some(scheme), Some(host)) = (uri.scheme_str(), uri.host()) else {
+        return false;
+    };
+    let port = match uri.port_u16() {
+        Some(port) => port,
+        None if scheme.eq_ignore_ascii_case("https") => 443,
+        None => 80,
+    };
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .eq_ignore_ascii_case(server.dest.host())
+        && port == server.dest.port()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{http::HttpClient, pool::ConnectionPool, resolve::Resolver};
+    use std::{sync::Arc, time::Duration};
+
+    fn server(address: &str) -> Server {
+        let timeout = Duration::from_secs(1);
+        let pool = ConnectionPool::new(timeout, 1, 1, timeout, timeout);
+        let resolver = Resolver::new(&["127.0.0.1:53".parse().unwrap()]).unwrap();
+        Server::new(address, Arc::new(HttpClient::new(pool)), Arc::new(resolver)).unwrap()
+    }
+
+    fn rewrite(prefix: &str, host: Option<&'static str>) -> ResponseRewrite {
+        let host = host.map(HeaderValue::from_static);
+        ResponseRewrite::new(prefix, host.as_ref(), true, true)
+    }
+
+    #[test]
+    fn maps_locations_of_the_server() {
+        let server = server("http://backend:8080/app/");
+        let rewrite = rewrite("/svc/", Some("example.com:8443"));
+        let cases = [
+            ("http://backend:8080/app/a?b=c#d", Some("/svc/a?b=c#d")),
+            ("HTTP://BACKEND:8080/app", Some("/svc/")),
+            ("http://backend:8080/app?q", Some("/svc/?q")),
+            ("/app/a", Some("/svc/a")),
+            // outside the base path, only made relative
+            ("http://backend:8080/other", Some("/other")),
+            ("/application", None),
+            ("/other", None),
+            // other hosts and ports, and protocol relative URLs are left alone
+            ("http://backend:8081/app/a", None),
+            ("https://backend/app/a", None),
+            ("http://example.com/app/a", None),
+            ("//backend:8080/app/a", None),
+            ("relative/path", None),
+        ];
+        for (location, expected) in cases {
+            assert_eq!(
+                rewrite.location(location, &server).as_deref(),
+                expected,
+                "{}",
+                location
+            );
+        }
+    }
+
+    #[test]
+    fn maps_locations_without_base_path() {
+        let server = server("http://backend");
+        assert_eq!(
+            rewrite("", None)
+                .location("http://backend/a#b", &server)
+                .as_deref(),
+            Some("/a#b")
+        );
+        assert_eq!(rewrite("", None).location("/a", &server), None);
+        assert_eq!(
+            rewrite("/svc", None).location("/a", &server).as_deref(),
+            Some("/svc/a")
+        );
+    }
+
+    #[test]
+    fn maps_cookie_attributes() {
+        let server = server("http://backend:8080/app");
+        let mapped = rewrite("/svc", Some("[::1]:8443"));
+        let cases = [
+            (
+                "sid=1; Path=/app/x; Domain=.backend; HttpOnly",
+                Some("sid=1; Path=/svc/x; Domain=[::1]; HttpOnly"),
+            ),
+            (
+                "sid=1; path=/app; Secure",
+                Some("sid=1; Path=/svc/; Secure"),
+            ),
+            ("sid=1; Domain=BACKEND", Some("sid=1; Domain=[::1]")),
+            // other paths and domains, and cookies without them, stay as they are
+            ("sid=1; Path=/other; Domain=example.com", None),
+            ("sid=1; Max-Age=60", None),
+            ("sid=1", None),
+        ];
+        for (cookie, expected) in cases {
+            assert_eq!(
+                mapped.cookie(cookie, &server).as_deref(),
+                expected,
+                "{}",
+                cookie
+            );
+        }
+        // without the host of the client, the domain of the server is dropped
+        assert_eq!(
+            rewrite("/svc", None)
+                .cookie("sid=1; Domain=backend; Path=/", &server)
+                .as_deref(),
+            Some("sid=1; Path=/")
+        );
+    }
+
+    #[test]
+    fn applies_to_headers() {
+        let server = server("http://backend:8080/app");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            HeaderValue::from_static("http://backend:8080/app/a"),
+        );
+        headers.insert(CONTENT_LOCATION, HeaderValue::from_static("/app/b"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1; Path=/app"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("b=2; Path=/"));
+
+        let mut unchanged = headers.clone();
+        ResponseRewrite::new("/svc", None, false, false).apply(&mut unchanged, &server);
+        assert_eq!(unchanged, headers);
+
+        rewrite("/svc", None).apply(&mut headers, &server);
+        assert_eq!(headers[LOCATION], "/svc/a");
+        assert_eq!(headers[CONTENT_LOCATION], "/svc/b");
+        let cookies = headers.get_all(SET_COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(cookies, ["a=1; Path=/svc/", "b=2; Path=/"]);
+    }
+}
@@ -0,0 +1,326 @@
+use super::upstream::Upstream;
+use crate::config::RouteConfig;
+use hyper::{header::HOST, http::uri::PathAndQuery, Method, Request, Uri};
+use regex::{Regex, RegexSet};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Forwards the requests matching all of its conditions to its upstream
+pub struct Route {
+    /// Lowercase host name without port
+    host: Option<String>,
+    /// Any method if empty
+    methods: Vec<Method>,
+    /// The pattern of the path in the [`Router`]
+    path: String,
+    /// Replaces the first match of the regex in the path
+    rewrite: Option<(Regex, String)>,
+    /// The prefix removed by `strip_prefix`, without trailing slash
+    stripped: Option<String>,
+    upstream: Arc<Upstream>,
+}
+
+impl Route {
+    pub fn new(config: &RouteConfig, upstream: Arc<Upstream>) -> Result<Self, RouteError> {
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| RouteError::InvalidMethod(method.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        // the prefix is matched by whole segments, so `/app` matches `/app/` but not `/apple`
+        let (path, matched) = match (&config.path_prefix, &config.path_regex) {
+            (Some(_), Some(_)) => return Err(RouteError::PrefixAndRegex),
+            (Some(prefix), None) => {
+                let prefix = regex::escape(prefix.trim_end_matches('/'));
+                (format!("^{}(?:/|$)", prefix), Some(format!("^{}", prefix)))
+            }
+            (None, Some(regex)) => (regex.clone(), Some(regex.clone())),
+            (None, None) => (String::new(), None),
+        };
+        let rewrite = match (config.strip_prefix, &config.rewrite, matched) {
+            (true, Some(_), _) => return Err(RouteError::StripAndRewrite),
+            (true, None, _) if config.path_prefix.is_none() => {
+                return Err(RouteError::StripWithoutPrefix)
+            }
+            (true, None, Some(matched)) => Some((Regex::new(&matched)?, String::new())),
+            (false, Some(_), None) => return Err(RouteError::RewriteWithoutPath),
+            (false, Some(replacement), Some(matched)) => {
+                Some((Regex::new(&matched)?, replacement.clone()))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            host: config.host.as_ref().map(|host| host.to_ascii_lowercase()),
+            methods,
+            path,
+            rewrite,
+            stripped: config
+                .path_prefix
+                .as_ref()
+                .filter(|_| config.strip_prefix)
+                .map(|prefix| prefix.trim_end_matches('/').to_owned()),
+            upstream,
+        })
+    }
+
+    /// A route for all requests
+    pub fn catch_all(upstream: Arc<Upstream>) -> Self {
+        Self {
+            host: None,
+            methods: Vec::new(),
+            path: String::new(),
+            rewrite: None,
+            stripped: None,
+            upstream,
+        }
+    }
+
+    pub fn upstream(&self) -> &Arc<Upstream> {
+        &self.upstream
+    }
+
+    /// The prefix removed from the path by `strip_prefix`
+    pub fn stripped_prefix(&self) -> Option<&str> {
+        self.stripped.as_deref()
+    }
+
+    /// Return the URI to request from the upstream, with the path rewritten
+    pub fn rewrite_uri(&self, uri: &Uri) -> Uri {
+        let Some((regex, replacement)) = &self.rewrite else {
+            return uri.clone();
+        };
+        let path = regex.replace(uri.path(), replacement.as_str());
+        let path = match path.starts_with('/') {
+            true => path.into_owned(),
+            false => format!("/{}", path),
+        };
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = uri.clone().into_parts();
+        match PathAndQuery::try_from(path_and_query) {
+            Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+            Err(_) => return uri.clone(),
+        }
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+
+    fn matches<B>(&self, host: Option<&str>, req: &Request<B>) -> bool {
+        let host_matches = match &self.host {
+            Some(expected) => host.is_some_and(|host| host.eq_ignore_ascii_case(expected)),
+            None => true,
+        };
+        host_matches && (self.methods.is_empty() || self.methods.contains(req.method()))
+    }
+}
+
+/// Picks the first route matching a request, the paths of all routes are matched at once with a
+/// single compiled set of patterns
+pub struct Router {
+    routes: Vec<Route>,
+    paths: RegexSet,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Result<Self, RouteError> {
+        let paths = RegexSet::new(routes.iter().map(|route| route.path.as_str()))?;
+        Ok(Self { routes, paths })
+    }
+
+    pub fn route<B>(&self, req: &Request<B>) -> Option<&Route> {
+        let host = request_host(req);
+        self.paths
+            .matches(req.uri().path())
+            .iter()
+            .map(|index| &self.routes[index])
+            .find(|route| route.matches(host, req))
+    }
+}
+
+/// The host the request is for without port, taken from the URI for HTTP/2, or the `Host` header
+fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    if let Some(host) = req.uri().host() {
+        return Some(host);
+    }
+    let host = req.headers().get(HOST)?.to_str().ok()?;
+    // an IPv6 address is enclosed in brackets
+    match host.rfind(']') {
+        Some(end) => Some(&host[..=end]),
+        None => Some(host.split(':').next().unwrap_or(host)),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RouteError {
+    #[error("Invalid path regex: {0}")]
+    InvalidRegex(#[from] regex::Error),
+    #[error("Invalid method `{0}`")]
+    InvalidMethod(String),
+    #[error("`path_prefix` and `path_regex` cannot be set in the same route")]
+    PrefixAndRegex,
+    #[error("`strip_prefix` requires `path_prefix`")]
+    StripWithoutPrefix,
+    #[error("`rewrite` requires `path_prefix` or `path_regex`")]
+    RewriteWithoutPath,
+    #[error("`strip_prefix` and `rewrite` cannot be set in the same route")]
+    StripAndRewrite,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(toml: &str) -> RouteConfig {
+        toml::from_str(&format!("proxy_address = \"http://127.0.0.1\"\n{}", toml)).unwrap()
+    }
+
+    fn route(toml: &str) -> Result<Route, RouteError> {
+        Route::new(
+            &config(toml),
+            Arc::new(Upstream::new(
+                Vec::new(),
+                Default::default(),
+                Duration::ZERO,
+            )),
+        )
+    }
+
+    /// A router of the routes, the index of the route a request took is returned
+    fn router(routes: &[&str]) -> impl Fn(Request<()>) -> Option<usize> {
+        let routes = routes
+            .iter()
+            .map(|toml| route(toml).unwrap())
+            .collect::<Vec<_>>();
+        let upstreams = routes
+            .iter()
+            .map(|route| route.upstream().clone())
+            .collect::<Vec<_>>();
+        let router = Router::new(routes).unwrap();
+        move |req| {
+            let route = router.route(&req)?;
+            upstreams
+                .iter()
+                .position(|upstream| Arc::ptr_eq(upstream, route.upstream()))
+        }
+    }
+
+    fn get(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    fn with_host(method: Method, uri: &str, host: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(HOST, host)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let route = router(&["path_prefix = \"/app/admin\"", "path_prefix = \"/app\"", ""]);
+        assert_eq!(route(get("/app/admin/users")), Some(0));
+        assert_eq!(route(get("/app/users")), Some(1));
+        assert_eq!(route(get("/other")), Some(2));
+
+        // a route listed earlier shadows the more specific ones after it
+        let route = router(&["path_prefix = \"/app\"", "path_prefix = \"/app/admin\""]);
+        assert_eq!(route(get("/app/admin/users")), Some(0));
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let route = router(&["path_prefix = \"/app/\""]);
+        assert_eq!(route(get("/app")), Some(0));
+        assert_eq!(route(get("/app/")), Some(0));
+        assert_eq!(route(get("/app/index.html")), Some(0));
+        assert_eq!(route(get("/apple")), None);
+        assert_eq!(route(get("/")), None);
+        // the prefix is not a regex
+        let route = router(&["path_prefix = \"/a.b\""]);
+        assert_eq!(route(get("/a.b/c")), Some(0));
+        assert_eq!(route(get("/axb/c")), None);
+    }
+
+    #[test]
+    fn regexes_match_the_path() {
+        let route = router(&["path_regex = \"^/api/v[0-9]+/\"", ""]);
+        assert_eq!(route(get("/api/v2/users")), Some(0));
+        assert_eq!(route(get("/api/latest/users")), Some(1));
+    }
+
+    #[test]
+    fn conditions_fall_through_to_later_routes() {
+        let route = router(&[
+            "host = \"Example.com\"\npath_prefix = \"/app\"",
+            "methods = [\"get\", \"HEAD\"]\npath_prefix = \"/app\"",
+            "path_prefix = \"/app\"",
+        ]);
+        assert_eq!(
+            route(with_host(Method::POST, "/app", "example.COM:8080")),
+            Some(0)
+        );
+        assert_eq!(route(with_host(Method::HEAD, "/app", "other.com")), Some(1));
+        assert_eq!(route(with_host(Method::POST, "/app", "other.com")), Some(2));
+        // HTTP/2 requests carry the host in the URI
+        assert_eq!(route(get("https://example.com/app")), Some(0));
+        assert_eq!(route(get("/app")), Some(1));
+
+        let route = router(&["host = \"[::1]\""]);
+        assert_eq!(route(with_host(Method::GET, "/", "[::1]:8080")), Some(0));
+        assert_eq!(route(with_host(Method::GET, "/", "127.0.0.1")), None);
+    }
+
+    #[test]
+    fn rewrites_paths() {
+        let uri = |route: Route, uri: &str| route.rewrite_uri(&uri.parse().unwrap()).to_string();
+        let stripped = route("path_prefix = \"/app/\"\nstrip_prefix = true").unwrap();
+        assert_eq!(stripped.stripped_prefix(), Some("/app"));
+        assert_eq!(uri(stripped, "/app/users?page=2"), "/users?page=2");
+        let stripped = route("path_prefix = \"/app\"\nstrip_prefix = true").unwrap();
+        assert_eq!(uri(stripped, "/app"), "/");
+        let rewritten = route("path_regex = \"^/v([0-9]+)\"\nrewrite = \"/api/$1\"").unwrap();
+        assert_eq!(uri(rewritten, "/v2/users"), "/api/2/users");
+        let unchanged = route("path_prefix = \"/app\"").unwrap();
+        assert_eq!(uri(unchanged, "/app/users"), "/app/users");
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        let error = |toml| route(toml).err().unwrap();
+        assert!(matches!(
+            error("path_prefix = \"/a\"\npath_regex = \"/b\""),
+            RouteError::PrefixAndRegex
+        ));
+        assert!(matches!(
+            error("strip_prefix = true"),
+            RouteError::StripWithoutPrefix
+        ));
+        assert!(matches!(
+            error("rewrite = \"/b\""),
+            RouteError::RewriteWithoutPath
+        ));
+        assert!(matches!(
+            error("path_prefix = \"/a\"\nstrip_prefix = true\nrewrite = \"/b\""),
+            RouteError::StripAndRewrite
+        ));
+        assert!(matches!(
+            error("methods = [\"G ET\"]"),
+            RouteError::InvalidMethod(_)
+        ));
+        // the path is compiled by the router
+        let invalid = route("path_regex = \"(\"").unwrap();
+        assert!(matches!(
+            Router::new(vec![invalid]),
+            Err(RouteError::InvalidRegex(_))
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use super::{hop::has_token, upstream::Selected};
+use crate::{client::ProxyClientError, utils::empty};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use hyper::{
+    body::{Bytes, Incoming},
+    ext::Protocol,
+    header::{HeaderValue, CONNECTION, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    Method, Request, Response, StatusCode, Version,
+};
+use hyper_util::rt::TokioIo;
+use std::{
+    io::{Error, ErrorKind},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, error};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Whether `req` asks to switch protocols, with `Connection: upgrade` in HTTP/1.1 or an extended
+/// CONNECT in HTTP/2
+pub fn is_upgrade<B>(req: &Request<B>) -> bool {
+    match req.version() {
+        Version::HTTP_2 => {
+            req.method() == Method::CONNECT && req.extensions().get::<Protocol>().is_some()
+        }
+        _ => {
+            req.headers().contains_key(UPGRADE)
+                && req
+                    .headers()
+                    .get_all(CONNECTION)
+                    .iter()
+                    .any(|value| has_token(value, "upgrade"))
+        }
+    }
+}
+
+/// Forward the upgrade request to the selected server over a connection of its own, and splice
+/// the two connections once both have switched protocols. The session is only checked when the
+/// request is authenticated, not for the lifetime of the upgraded connection.
+pub async fn proxy_upgrade(
+    mut req: Request<Incoming>,
+    selected: Selected,
+    idle_timeout: Duration,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError> {
+    let downstream = hyper::upgrade::on(&mut req);
+    let extended_connect = req.version() == Version::HTTP_2;
+    let server = selected.server();
+    let upstream_req = upstream_request(req, &server.host_header)?;
+    let mut response = match server
+        .client
+        .upgrade(&server.dest, server.domain.clone(), upstream_req)
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            if err.is_connect() || err.is_reset() {
+                selected.failed();
+            }
+            return Err(err);
+        }
+    };
+    selected.succeeded();
+
+    // the upstream refused to switch, its answer is forwarded as it is
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(response.map(|body| selected.track(body.boxed())));
+    }
+
+    let upstream = hyper::upgrade::on(&mut response);
+    let (mut parts, _) = response.into_parts();
+    if extended_connect {
+        // HTTP/2 accepts an extended CONNECT with `200`, the connection specific headers are
+        // stripped with the other hop-by-hop headers
+        parts.status = StatusCode::OK;
+        parts.headers.remove(SEC_WEBSOCKET_ACCEPT);
+    }
+
+    tokio::task::spawn(async move {
+        let (downstream, upstream) = match tokio::try_join!(downstream, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(err) => return error!("Failed to upgrade connection: {}", err),
+        };
+        let name = &selected.server().name;
+        debug!("Upgraded connection to {} opened", name);
+        match splice(
+            TokioIo::new(downstream),
+            TokioIo::new(upstream),
+            idle_timeout,
+        )
+        .await
+        {
+            Ok(()) => debug!("Upgraded connection to {} closed", name),
+            Err(err) => debug!("Upgraded connection to {} closed: {}", name, err),
+        }
+    });
+
+    Ok(Response::from_parts(parts, empty()))
+}
+
+/// The HTTP/1.1 request to send to the upstream, an extended CONNECT is translated to the
+/// equivalent `GET` with `Upgrade`
+fn upstream_request(
+    req: Request<Incoming>,
+    host: &HeaderValue,
+) -> Result<Request<Empty<Bytes>>, ProxyClientError> {
+    let (parts, _) = req.into_parts();
+    let mut headers = parts.headers;
+    let method = match parts.extensions.get::<Protocol>() {
+        Some(protocol) if parts.version == Version::HTTP_2 => {
+            let upgrade =
+                HeaderValue::from_str(protocol.as_str()).map_err(hyper::http::Error::from)?;
+            headers.insert(HOST, host.clone());
+            headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+            headers.insert(UPGRADE, upgrade);
+            // RFC 8441 does without the key, which the upstream needs for the WebSocket handshake
+            if protocol.as_str().eq_ignore_ascii_case("websocket") {
+                let key = BASE64_STANDARD.encode(uuid::Uuid::new_v4().as_bytes());
+                headers.insert(
+                    SEC_WEBSOCKET_KEY,
+                    HeaderValue::from_str(&key).map_err(hyper::http::Error::from)?,
+                );
+            }
+            Method::GET
+        }
+        _ => parts.method,
+    };
+
+    let mut upstream_req = Request::builder()
+        .method(method)
+        .uri(parts.uri.path_and_query().map_or("/", |path| path.as_str()))
+        .version(Version::HTTP_11)
+        .body(Empty::new())?;
+    *upstream_req.headers_mut() = headers;
+    Ok(upstream_req)
+}
+
+/// Copy bytes between `a` and `b` in both directions until both sides are closed, or nothing is
+/// sent either way for `idle_timeout`
+async fn splice<A, B>(mut a: A, mut b: B, idle_timeout: Duration) -> std::io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_buf, mut b_buf) = (vec![0; BUFFER_SIZE], vec![0; BUFFER_SIZE]);
+    let (mut a_open, mut b_open) = (true, true);
+    while a_open || b_open {
+        tokio::select! {
+            read = a.read(&mut a_buf), if a_open => match read? {
+                0 => {
+                    a_open = false;
+                    b.shutdown().await?;
+                }
+                n => b.write_all(&a_buf[..n]).await?,
+            },
+            read = b.read(&mut b_buf), if b_open => match read? {
+                0 => {
+                    b_open = false;
+                    a.shutdown().await?;
+                }
+                n => a.write_all(&b_buf[..n]).await?,
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                return Err(Error::new(ErrorKind::TimedOut, "idle timeout"));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::duplex, time::Instant};
+
+    #[test]
+    fn detects_upgrades() {
+        let upgrade = |version, connection| {
+            let mut req = Request::get("/")
+                .version(version)
+                .header(UPGRADE, "websocket");
+            if let Some(connection) = connection {
+                req = req.header(CONNECTION, connection);
+            }
+            is_upgrade(&req.body(()).unwrap())
+        };
+        assert!(upgrade(Version::HTTP_11, Some("keep-alive, Upgrade")));
+        assert!(!upgrade(Version::HTTP_11, Some("keep-alive")));
+        assert!(!upgrade(Version::HTTP_11, None));
+
+        let mut connect = Request::connect("/chat")
+            .version(Version::HTTP_2)
+            .body(())
+            .unwrap();
+        assert!(!is_upgrade(&connect));
+        connect
+            .extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        assert!(is_upgrade(&connect));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn copies_both_ways_until_closed() {
+        let (mut client, a) = duplex(BUFFER_SIZE);
+        let (b, mut server) = duplex(BUFFER_SIZE);
+        let spliced = tokio::spawn(splice(a, b, Duration::from_secs(10)));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // a side closing only closes its direction
+        client.shutdown().await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        server.shutdown().await.unwrap();
+        spliced.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_when_idle() {
+        let (mut client, a) = duplex(BUFFER_SIZE);
+        let (b, mut server) = duplex(BUFFER_SIZE);
+        let started = Instant::now();
+        let spliced = tokio::spawn(splice(a, b, Duration::from_secs(10)));
+
+        // traffic either way keeps the connection open
+        let mut buf = [0; 4];
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        client.write_all(b"ping").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+
+        let err = spliced.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), Duration::from_secs(28));
+    }
+}
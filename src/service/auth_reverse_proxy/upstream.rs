@@ -0,0 +1,604 @@
+use crate::client::{
+    resolve::{Destination, Resolver},
+    ProxyClient,
+};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header::HeaderValue,
+    http::uri::PathAndQuery,
+    Uri,
+};
+use rustls_pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// The longest a failing server is backed off for
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How requests are spread over the servers of an upstream
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// Each server in turn
+    #[default]
+    RoundRobin,
+    /// The server with the fewest requests in progress
+    LeastConnections,
+    /// The same server for the same session, by rendezvous hashing of the authenticated user
+    /// rather than the session ID, which changes on rotation. Only the users of a server are moved
+    /// when it goes down.
+    SessionHash,
+}
+
+/// Requests `path` from each server every `interval`, a server is taken out after `fails`
+/// consecutive failed checks and put back after a successful one
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub fails: u32,
+}
+
+/// Consecutive failures of requests forwarded to a server
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+/// A server of an upstream
+pub struct Server {
+    /// The address as configured
+    pub name: String,
+    pub domain: ServerName<'static>,
+    pub dest: Destination,
+    pub client: Arc<dyn ProxyClient + Send + Sync>,
+    /// Replaces the `Host` header of forwarded requests
+    pub host_header: HeaderValue,
+    /// The path of the address, which the paths of forwarded requests are prefixed with, empty if
+    /// none
+    pub base_path: String,
+    /// Requests in progress, until the response body is read
+    active: AtomicUsize,
+    requests: AtomicU64,
+    failures: AtomicU64,
+    /// Result of the active health checks
+    healthy: AtomicBool,
+    failed_checks: AtomicU32,
+    backoff: Mutex<Backoff>,
+}
+
+impl Server {
+    pub fn new(
+        dest: &str,
+        client: Arc<dyn ProxyClient + Send + Sync>,
+        resolver: Arc<Resolver>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let uri = dest.parse::<Uri>()?;
+        let port = uri
+            .port()
+            .map(|port| port.as_u16())
+            .unwrap_or(client.default_port());
+        let host = uri
+            .host()
+            .ok_or_else(|| std::io::Error::other(format!("No host in `{}`", dest)))?
+            .to_owned();
+        let host_header = HeaderValue::from_str(host.as_str())?;
+
+        debug!("Proxy to domain: {}, port: {}", host, port);
+
+        Ok(Self {
+            name: dest.to_string(),
+            dest: Destination::new(&host, port, resolver),
+            domain: ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))?
+                .to_owned(),
+            client,
+            host_header,
+            base_path: uri.path().trim_end_matches('/').to_owned(),
+            active: AtomicUsize::new(0),
+            requests: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            failed_checks: AtomicU32::new(0),
+            backoff: Mutex::new(Backoff::default()),
+        })
+    }
+
+    /// Return the URI to request from the server, with the base path prepended
+    pub fn request_uri(&self, uri: &Uri) -> Uri {
+        if self.base_path.is_empty() {
+            return uri.clone();
+        }
+        let path_and_query = format!(
+            "{}{}",
+            self.base_path,
+            uri.path_and_query().map_or("/", |path| path.as_str())
+        );
+        let mut parts = uri.clone().into_parts();
+        match PathAndQuery::try_from(path_and_query) {
+            Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+            Err(_) => return uri.clone(),
+        }
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+
+    /// Healthy and not backed off
+    fn is_available(&self, now: Instant) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+            && self.lock_backoff().until.is_none_or(|until| now >= until)
+    }
+
+    fn lock_backoff(&self) -> std::sync::MutexGuard<'_, Backoff> {
+        self.backoff.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn check_passed(&self) {
+        self.failed_checks.store(0, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            info!(
+                "Upstream server {} passed health check, back in service",
+                self.name
+            );
+        }
+    }
+
+    fn check_failed(&self, threshold: u32, reason: impl std::fmt::Display) {
+        let failed = self.failed_checks.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!("Health check of {} failed: {}", self.name, reason);
+        if failed >= threshold && self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Upstream server {} failed {} health checks, out of service: {}",
+                self.name, failed, reason
+            );
+        }
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        let backoff = self.lock_backoff();
+        ServerStats {
+            address: self.name.clone(),
+            healthy: self.healthy.load(Ordering::Relaxed),
+            backed_off: backoff
+                .until
+                .map(|until| until.saturating_duration_since(Instant::now()).as_secs())
+                .filter(|remaining| *remaining > 0),
+            active: self.active.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The state of a server as reported by the admin API
+#[derive(Serialize, Deserialize)]
+pub struct ServerStats {
+    pub address: String,
+    /// Whether the server passes the health checks
+    pub healthy: bool,
+    /// Seconds until a server backed off after failed requests is tried again
+    pub backed_off: Option<u64>,
+    /// Requests in progress
+    pub active: usize,
+    pub requests: u64,
+    /// Requests which could not be forwarded
+    pub failures: u64,
+}
+
+/// Where requests are forwarded to, a group of servers
+pub struct Upstream {
+    servers: Vec<Arc<Server>>,
+    balance: Balance,
+    next: AtomicUsize,
+    /// The initial backoff of a server after a failed request, doubled for each consecutive
+    /// failure
+    fail_backoff: Duration,
+    health_check: Option<Arc<HealthCheck>>,
+}
+
+impl Upstream {
+    pub fn new(servers: Vec<Server>, balance: Balance, fail_backoff: Duration) -> Self {
+        Self {
+            servers: servers.into_iter().map(Arc::new).collect(),
+            balance,
+            next: AtomicUsize::new(0),
+            fail_backoff,
+            health_check: None,
+        }
+    }
+
+    /// Check the health of the servers once [`Upstream::spawn_health_checks`] is called
+    pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+        self.health_check = Some(Arc::new(check));
+        self
+    }
+
+    pub fn balance(&self) -> Balance {
+        self.balance
+    }
+
+    pub fn servers(&self) -> &[Arc<Server>] {
+        &self.servers
+    }
+
+    /// Pick a server for a request, `user` is the key for [`Balance::SessionHash`]. Return `None`
+    /// if all servers are out of service.
+    pub fn select(self: &Arc<Self>, user: &str) -> Option<Selected> {
+        let now = Instant::now();
+        let available = self
+            .servers
+            .iter()
+            .filter(|server| server.is_available(now))
+            .collect::<Vec<_>>();
+        let server = match self.balance {
+            _ if available.is_empty() => return None,
+            Balance::RoundRobin => {
+                available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()]
+            }
+            Balance::LeastConnections => available
+                .iter()
+                .min_by_key(|server| server.active.load(Ordering::Relaxed))
+                .copied()?,
+            Balance::SessionHash => available
+                .iter()
+                .max_by_key(|server| {
+                    let mut hasher = DefaultHasher::new();
+                    (user, &server.name).hash(&mut hasher);
+                    hasher.finish()
+                })
+                .copied()?,
+        };
+        server.active.fetch_add(1, Ordering::Relaxed);
+        server.requests.fetch_add(1, Ordering::Relaxed);
+        Some(Selected {
+            server: server.clone(),
+            upstream: self.clone(),
+        })
+    }
+
+    /// Check the health of the servers in a task on the current runtime if a health check is set,
+    /// the task stops once the upstream is dropped
+    pub fn spawn_health_checks(self: &Arc<Self>) {
+        let Some(check) = self.health_check.clone() else {
+            return;
+        };
+        let upstream: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(check.interval).await;
+                let Some(upstream) = upstream.upgrade() else {
+                    break;
+                };
+                let mut checks = JoinSet::new();
+                for server in &upstream.servers {
+                    checks.spawn(check_server(server.clone(), check.clone()));
+                }
+                drop(upstream);
+                while checks.join_next().await.is_some() {}
+            }
+        });
+    }
+
+    fn succeeded(&self, server: &Server) {
+        let mut backoff = server.lock_backoff();
+        if backoff.failures > 0 {
+            info!("Upstream server {} recovered", server.name);
+            *backoff = Backoff::default();
+        }
+    }
+
+    fn failed(&self, server: &Server) {
+        server.failures.fetch_add(1, Ordering::Relaxed);
+        let mut backoff = server.lock_backoff();
+        backoff.failures += 1;
+        let duration = self
+            .fail_backoff
+            .saturating_mul(1 << (backoff.failures - 1).min(16))
+            .min(MAX_BACKOFF);
+        backoff.until = Some(Instant::now() + duration);
+        warn!(
+            "Upstream server {} failed {} times in a row, backing off for {}s",
+            server.name,
+            backoff.failures,
+            duration.as_secs()
+        );
+    }
+}
+
+async fn check_server(server: Arc<Server>, check: Arc<HealthCheck>) {
+    let result = tokio::time::timeout(
+        check.timeout,
+        server.client.health_check(
+            &server.dest,
+            server.domain.clone(),
+            server.host_header.clone(),
+            &check.path,
+        ),
+    )
+    .await;
+    match result {
+        Ok(Ok(status)) if status.is_success() || status.is_redirection() => server.check_passed(),
+        Ok(Ok(status)) => server.check_failed(check.fails, status),
+        Ok(Err(err)) => server.check_failed(check.fails, err),
+        Err(_) => server.check_failed(check.fails, "timed out"),
+    }
+}
+
+/// A server picked for a request, which counts as in progress until this is dropped
+pub struct Selected {
+    server: Arc<Server>,
+    upstream: Arc<Upstream>,
+}
+
+impl Selected {
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    /// The request was forwarded and a response received
+    pub fn succeeded(&self) {
+        self.upstream.succeeded(&self.server)
+    }
+
+    /// The server could not be connected to or reset the connection, other errors such as
+    /// timeouts do not back it off
+    pub fn failed(&self) {
+        self.upstream.failed(&self.server)
+    }
+
+    /// Keep the request in progress until `body` is read
+    pub fn track(self, body: BoxBody<Bytes, hyper::Error>) -> BoxBody<Bytes, hyper::Error> {
+        Tracked {
+            inner: body,
+            _selected: self,
+        }
+        .boxed()
+    }
+}
+
+impl Drop for Selected {
+    fn drop(&mut self) {
+        self.server.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Tracked {
+    inner: BoxBody<Bytes, hyper::Error>,
+    _selected: Selected,
+}
+
+impl Body for Tracked {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ProxyClientError;
+    use async_trait::async_trait;
+    use http_body_util::Empty;
+    use hyper::{body::Incoming, Request, Response, StatusCode};
+    use std::sync::atomic::AtomicU16;
+
+    /// Answers health checks with the status set, and fails everything else
+    struct Checked(Arc<AtomicU16>);
+
+    #[async_trait]
+    impl ProxyClient for Checked {
+        async fn proxy_request(
+            &self,
+            _dest: &Destination,
+            _domain: ServerName<'static>,
+            _req: Request<BoxBody<Bytes, hyper::Error>>,
+        ) -> Result<hyper::Response<BoxBody<Bytes, hyper::Error>>, ProxyClientError> {
+            Err(ProxyClientError::BodyTimeout)
+        }
+
+        async fn health_check(
+            &self,
+            _dest: &Destination,
+            _domain: ServerName<'static>,
+            _host: HeaderValue,
+            _path: &str,
+        ) -> Result<StatusCode, ProxyClientError> {
+            Ok(StatusCode::from_u16(self.0.load(Ordering::Relaxed)).unwrap())
+        }
+
+        async fn upgrade(
+            &self,
+            _dest: &Destination,
+            _domain: ServerName<'static>,
+            _req: Request<Empty<Bytes>>,
+        ) -> Result<Response<Incoming>, ProxyClientError> {
+            Err(ProxyClientError::BodyTimeout)
+        }
+
+        fn default_port(&self) -> u16 {
+            80
+        }
+    }
+
+    /// An upstream of `count` servers, with the status each answers health checks with
+    fn upstream(count: usize, balance: Balance) -> (Upstream, Vec<Arc<AtomicU16>>) {
+        let resolver = Arc::new(Resolver::new(&["127.0.0.1:53".parse().unwrap()]).unwrap());
+        let statuses = (0..count)
+            .map(|_| Arc::new(AtomicU16::new(200)))
+            .collect::<Vec<_>>();
+        let servers = statuses
+            .iter()
+            .enumerate()
+            .map(|(index, status)| {
+                let address = format!("http://10.0.0.{}", index + 1);
+                Server::new(
+                    &address,
+                    Arc::new(Checked(status.clone())),
+                    resolver.clone(),
+                )
+                .unwrap()
+            })
+            .collect();
+        let upstream = Upstream::new(servers, balance, Duration::from_secs(10));
+        (upstream, statuses)
+    }
+
+    fn name(selected: &Selected) -> &str {
+        &selected.server().name
+    }
+
+    #[test]
+    fn round_robin_skips_failed_servers() {
+        let upstream = Arc::new(upstream(3, Balance::RoundRobin).0);
+        let names = (0..3)
+            .map(|_| name(&upstream.select("alice").unwrap()).to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["http://10.0.0.1", "http://10.0.0.2", "http://10.0.0.3"]
+        );
+
+        upstream.select("alice").unwrap().failed();
+        for _ in 0..4 {
+            assert_ne!(name(&upstream.select("alice").unwrap()), "http://10.0.0.1");
+        }
+        for server in upstream.servers() {
+            upstream.failed(server);
+        }
+        assert!(upstream.select("alice").is_none());
+    }
+
+    #[test]
+    fn least_connections_picks_the_idlest_server() {
+        let upstream = Arc::new(upstream(2, Balance::LeastConnections).0);
+        let first = upstream.select("alice").unwrap();
+        let second = upstream.select("alice").unwrap();
+        assert_ne!(name(&first), name(&second));
+        let idle = name(&second).to_owned();
+        drop(second);
+        assert_eq!(name(&upstream.select("alice").unwrap()), idle);
+
+        // the request is in progress until its body is read
+        let body = Empty::new().map_err(|err| match err {}).boxed();
+        let tracked = first.track(body);
+        let third = upstream.select("alice").unwrap();
+        assert_eq!(name(&third), idle);
+        drop(third);
+        drop(tracked);
+        assert!(upstream
+            .servers()
+            .iter()
+            .all(|server| server.stats().active == 0));
+    }
+
+    #[test]
+    fn session_hash_keeps_users_on_their_server() {
+        let upstream = Arc::new(upstream(4, Balance::SessionHash).0);
+        let users = (0..32)
+            .map(|user| format!("user{}", user))
+            .collect::<Vec<_>>();
+        let servers = |upstream: &Arc<Upstream>| {
+            users
+                .iter()
+                .map(|user| name(&upstream.select(user).unwrap()).to_owned())
+                .collect::<Vec<_>>()
+        };
+        let before = servers(&upstream);
+        assert_eq!(servers(&upstream), before);
+        let spread = before.iter().collect::<std::collections::HashSet<_>>();
+        assert!(spread.len() > 1);
+
+        // only the users of a server which goes down are moved
+        let down = upstream.servers()[0].clone();
+        upstream.failed(&down);
+        let after = servers(&upstream);
+        for (before, after) in before.iter().zip(&after) {
+            match *before == down.name {
+                true => assert_ne!(*after, down.name),
+                false => assert_eq!(after, before),
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_until_success() {
+        let upstream = Arc::new(upstream(1, Balance::RoundRobin).0);
+        let server = upstream.servers()[0].clone();
+        let backed_off = || server.stats().backed_off.unwrap();
+
+        upstream.failed(&server);
+        assert!((9..=10).contains(&backed_off()));
+        upstream.failed(&server);
+        assert!((19..=20).contains(&backed_off()));
+        for _ in 0..20 {
+            upstream.failed(&server);
+        }
+        assert!((299..=300).contains(&backed_off()));
+        assert_eq!(server.stats().failures, 22);
+        assert!(upstream.select("alice").is_none());
+
+        upstream.succeeded(&server);
+        assert!(server.stats().backed_off.is_none());
+        upstream.failed(&server);
+        assert!((9..=10).contains(&backed_off()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_checks_take_servers_out_and_back() {
+        let (upstream, statuses) = upstream(2, Balance::RoundRobin);
+        let upstream = Arc::new(upstream.with_health_check(HealthCheck {
+            path: "/health".to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            fails: 2,
+        }));
+        upstream.spawn_health_checks();
+        let healthy = || {
+            upstream
+                .servers()
+                .iter()
+                .map(|server| server.stats().healthy)
+                .collect::<Vec<_>>()
+        };
+
+        statuses[0].store(503, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        assert_eq!(healthy(), [true, true]);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(healthy(), [false, true]);
+        for _ in 0..4 {
+            assert_eq!(name(&upstream.select("alice").unwrap()), "http://10.0.0.2");
+        }
+
+        // a redirection passes
+        statuses[0].store(302, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(healthy(), [true, true]);
+    }
+}
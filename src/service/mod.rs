@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use std::net::SocketAddr;
 use thiserror::Error;
 use tokio::net::TcpStream;
 
@@ -7,7 +8,8 @@ pub mod auth_reverse_proxy;
 
 #[async_trait]
 pub trait TcpService {
-    async fn serve_tcp(&self, incoming: TcpStream) -> Result<(), TcpServiceError>;
+    async fn serve_tcp(&self, incoming: TcpStream, peer: SocketAddr)
+        -> Result<(), TcpServiceError>;
 }
 
 #[derive(Error, Debug)]
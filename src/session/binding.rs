@@ -0,0 +1,83 @@
+use super::Session;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// What is known about the client sending a request
+#[derive(Default, Clone, Debug)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// SHA-256 fingerprint of the TLS client certificate
+    pub client_cert: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IpBinding {
+    /// The client IP must not change
+    Exact,
+    /// The client IP must stay in the same subnet
+    Subnet,
+}
+
+/// Which attributes of the client a session is bound to
+pub struct SessionBinding {
+    ip: Option<IpBinding>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    user_agent: bool,
+    client_cert: bool,
+}
+
+impl SessionBinding {
+    pub fn new(
+        ip: Option<IpBinding>,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+        user_agent: bool,
+        client_cert: bool,
+    ) -> Self {
+        Self {
+            ip,
+            ipv4_prefix: ipv4_prefix.min(32),
+            ipv6_prefix: ipv6_prefix.min(128),
+            user_agent,
+            client_cert,
+        }
+    }
+
+    /// Return the name of the first bound attribute the client does not match
+    pub fn mismatch(&self, session: &Session, client: &ClientInfo) -> Option<&'static str> {
+        if let Some(binding) = self.ip {
+            if !self.same_ip(binding, session.ip, client.ip) {
+                return Some("IP");
+            }
+        }
+        if self.user_agent && session.user_agent != client.user_agent {
+            return Some("user agent");
+        }
+        if self.client_cert && session.client_cert != client.client_cert {
+            return Some("client certificate");
+        }
+        None
+    }
+
+    fn same_ip(&self, binding: IpBinding, bound: Option<IpAddr>, ip: Option<IpAddr>) -> bool {
+        let (Some(bound), Some(ip)) = (bound, ip) else {
+            return bound == ip;
+        };
+        match binding {
+            IpBinding::Exact => bound == ip,
+            IpBinding::Subnet => {
+                let prefix = match bound {
+                    IpAddr::V4(_) => self.ipv4_prefix,
+                    IpAddr::V6(_) => self.ipv6_prefix,
+                };
+                IpNet::new(bound, prefix)
+                    .map(|net| net.contains(&ip))
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
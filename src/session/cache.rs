@@ -0,0 +1,240 @@
+use super::{limit::UserLimit, redis::RedisStore, Session, SessionIter, SessionStore, StoreError};
+use dashmap::DashMap;
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Number of invalidation counters the session IDs are spread over
+const GENERATIONS: usize = 256;
+
+/// What the invalidation listener of a [`RedisStore`] reports
+pub enum CacheEvent<'a> {
+    /// Invalidations are received from now on
+    Subscribed,
+    /// The subscription is lost, invalidations may be missed until it is back
+    Unsubscribed,
+    /// The session was deleted or changed by some instance
+    Invalidated(&'a str),
+}
+
+/// Caches sessions found in Redis for a short time, so most requests are validated without a
+/// round trip. Deletions are broadcast over Redis pub/sub, and every instance drops the session
+/// from its cache immediately. While the subscription is down, the cache is emptied and bypassed.
+pub struct CachedStore {
+    inner: Arc<RedisStore>,
+    entries: DashMap<String, (Session, Instant)>,
+    ttl: Duration,
+    capacity: usize,
+    subscribed: AtomicBool,
+    /// Bumped whenever a session is dropped from the cache, so a session read from the store
+    /// before it was dropped is not cached afterwards
+    generations: Box<[AtomicU64]>,
+    hasher: RandomState,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<RedisStore>, ttl: Duration, capacity: usize) -> Arc<Self> {
+        let store = Arc::new(Self {
+            inner,
+            entries: DashMap::new(),
+            ttl,
+            capacity,
+            subscribed: AtomicBool::new(false),
+            generations: (0..GENERATIONS).map(|_| AtomicU64::new(0)).collect(),
+            hasher: RandomState::new(),
+        });
+
+        let cache = Arc::downgrade(&store);
+        store.inner.spawn_invalidation_listener(move |event| {
+            let Some(cache) = cache.upgrade() else {
+                return false;
+            };
+            match event {
+                CacheEvent::Subscribed => {
+                    // anything published before is lost
+                    cache.forget_all();
+                    cache.subscribed.store(true, Ordering::Relaxed);
+                    info!("Session cache subscribed to invalidations");
+                }
+                CacheEvent::Unsubscribed => {
+                    if cache.subscribed.swap(false, Ordering::Relaxed) {
+                        warn!("Session cache lost the invalidation subscription, bypassing it");
+                    }
+                    cache.forget_all();
+                }
+                CacheEvent::Invalidated(session_id) => cache.forget(session_id),
+            }
+            true
+        });
+        store.spawn_sweeper();
+        store
+    }
+
+    /// Drop the expired sessions every `ttl` in a background thread, which stops once the cache is
+    /// dropped. Expired sessions are never returned anyway, this only frees their room.
+    fn spawn_sweeper(self: &Arc<Self>) {
+        let cache = Arc::downgrade(self);
+        let interval = self.ttl.max(Duration::from_secs(1));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(cache) = cache.upgrade() else {
+                break;
+            };
+            cache
+                .entries
+                .retain(|_, (_, cached_at)| cached_at.elapsed() < cache.ttl);
+        });
+    }
+
+    fn get(&self, session_id: &str) -> Option<Session> {
+        if !self.subscribed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let entry = self.entries.get(session_id)?;
+        let (session, cached_at) = entry.value();
+        match cached_at.elapsed() < self.ttl {
+            true => Some(session.clone()),
+            false => {
+                drop(entry);
+                self.entries.remove(session_id);
+                None
+            }
+        }
+    }
+
+    fn generation(&self, session_id: &str) -> &AtomicU64 {
+        &self.generations[self.hasher.hash_one(session_id) as usize % GENERATIONS]
+    }
+
+    /// Cache a session read or written when the generation of its ID was `generation`, unless it
+    /// has been dropped since
+    fn put(&self, session_id: &str, session: &Session, generation: u64) {
+        if !self.subscribed.load(Ordering::SeqCst) {
+            return;
+        }
+        // a full cache is left as it is until the sweeper makes room
+        if self.entries.len() >= self.capacity {
+            return;
+        }
+        self.entries
+            .insert(session_id.to_string(), (session.clone(), Instant::now()));
+        // checked after inserting, as a drop bumps the generation before removing the entry
+        if self.generation(session_id).load(Ordering::SeqCst) != generation {
+            self.entries.remove(session_id);
+        }
+    }
+
+    /// Cache a session just written to the store
+    fn refresh(
+        &self,
+        session_id: &str,
+        session: &Session,
+        generation: u64,
+    ) -> Result<(), StoreError> {
+        // a session replaced by rotation must stop being accepted at its deadline everywhere
+        match session.is_replaced() {
+            true => self.invalidate(session_id),
+            false => {
+                self.put(session_id, session, generation);
+                Ok(())
+            }
+        }
+    }
+
+    /// Drop the session from the local cache
+    fn forget(&self, session_id: &str) {
+        self.generation(session_id).fetch_add(1, Ordering::SeqCst);
+        self.entries.remove(session_id);
+    }
+
+    fn forget_all(&self) {
+        for generation in self.generations.iter() {
+            generation.fetch_add(1, Ordering::SeqCst);
+        }
+        self.entries.clear();
+    }
+
+    /// Drop the session from the cache of every instance. If the broadcast fails, other instances
+    /// keep accepting the session until their cache expires, so the error is returned to make the
+    /// operation fail.
+    fn invalidate(&self, session_id: &str) -> Result<(), StoreError> {
+        self.forget(session_id);
+        self.inner
+            .publish_invalidation(session_id)
+            .inspect_err(|err| {
+                error!(
+                "Failed to broadcast invalidation of session, other instances may still accept \
+                 it for {}s: {}",
+                self.ttl.as_secs(),
+                err
+            )
+            })
+    }
+}
+
+impl SessionStore for CachedStore {
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        if let Some(session) = self.get(session_id) {
+            return Ok(Some(session));
+        }
+        let generation = self.generation(session_id).load(Ordering::SeqCst);
+        let session = self.inner.load(session_id)?;
+        if let Some(session) = &session {
+            self.put(session_id, session, generation);
+        }
+        Ok(session)
+    }
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        let generation = self.generation(session_id).load(Ordering::SeqCst);
+        self.inner.save(session_id, session)?;
+        self.refresh(session_id, session, generation)
+    }
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        let generation = self.generation(session_id).load(Ordering::SeqCst);
+        let updated = self.inner.update(session_id, session)?;
+        match updated {
+            true => self.refresh(session_id, session, generation)?,
+            false => self.forget(session_id),
+        }
+        Ok(updated)
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        let generation = self.generation(session_id).load(Ordering::SeqCst);
+        let evicted = self.inner.create(session_id, session, limit)?;
+        if let Some(evicted) = &evicted {
+            for session_id in evicted {
+                self.invalidate(session_id)?;
+            }
+            self.put(session_id, session, generation);
+        }
+        Ok(evicted)
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        // a record which could not be read is deleted all the same
+        let session = self.inner.delete(session_id);
+        self.invalidate(session_id).and(session)
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        self.inner.list()
+    }
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        self.inner.iter()
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        self.inner.user_sessions(user)
+    }
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,198 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+const TOKEN_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const AAD: &[u8] = b"watchdawg-session";
+
+/// The session state carried in an encrypted cookie
+pub struct CookieSession {
+    pub id: Uuid,
+    pub user: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+/// Seal and open session cookies with XChaCha20-Poly1305
+///
+/// The first key is used for sealing, all keys are tried for opening, so a new key can be put in
+/// front of the old ones to rotate keys without logging everyone out.
+pub struct CookieSealer {
+    ciphers: Vec<XChaCha20Poly1305>,
+}
+
+impl CookieSealer {
+    /// Create from base64 encoded 32-byte keys, the first one is the current key
+    pub fn new<S: AsRef<str>>(keys: &[S]) -> Result<Self, CookieKeyError> {
+        if keys.is_empty() {
+            return Err(CookieKeyError::Empty);
+        }
+        let mut ciphers = Vec::with_capacity(keys.len());
+        for (index, key) in keys.iter().enumerate() {
+            let key = BASE64_STANDARD_LENIENT
+                .decode(key.as_ref().trim())
+                .map_err(|_| CookieKeyError::Invalid(index))?;
+            if key.len() != KEY_LEN {
+                return Err(CookieKeyError::Invalid(index));
+            }
+            ciphers.push(XChaCha20Poly1305::new_from_slice(&key).unwrap());
+        }
+        Ok(Self { ciphers })
+    }
+
+    pub fn seal(&self, session: &CookieSession) -> String {
+        let mut plaintext = Vec::with_capacity(32 + session.user.len());
+        plaintext.extend_from_slice(&session.issued_at.to_be_bytes());
+        plaintext.extend_from_slice(&session.expires_at.to_be_bytes());
+        plaintext.extend_from_slice(session.id.as_bytes());
+        plaintext.extend_from_slice(session.user.as_bytes());
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.ciphers[0]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: AAD,
+                },
+            )
+            .expect("Encryption with a valid key never fails");
+
+        let mut token = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        token.push(TOKEN_VERSION);
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&ciphertext);
+        BASE64_URL_SAFE_NO_PAD.encode(token)
+    }
+
+    /// Return the session if the token is authentic, expiry is not checked here
+    pub fn open(&self, token: &str) -> Option<CookieSession> {
+        let token = BASE64_URL_SAFE_NO_PAD.decode(token).ok()?;
+        if token.len() < 1 + NONCE_LEN || token[0] != TOKEN_VERSION {
+            return None;
+        }
+        let nonce = XNonce::from_slice(&token[1..1 + NONCE_LEN]);
+        let ciphertext = &token[1 + NONCE_LEN..];
+        let plaintext = self.ciphers.iter().find_map(|cipher| {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: AAD,
+            };
+            cipher.decrypt(nonce, payload).ok()
+        })?;
+
+        if plaintext.len() < 32 {
+            return None;
+        }
+        Some(CookieSession {
+            issued_at: u64::from_be_bytes(plaintext[0..8].try_into().ok()?),
+            expires_at: u64::from_be_bytes(plaintext[8..16].try_into().ok()?),
+            id: Uuid::from_slice(&plaintext[16..32]).ok()?,
+            user: String::from_utf8(plaintext[32..].to_vec()).ok()?,
+        })
+    }
+}
+
+const BASE64_STANDARD_LENIENT: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
+
+#[derive(Error, Debug)]
+pub enum CookieKeyError {
+    #[error("At least one cookie key is required")]
+    Empty,
+    #[error("Cookie key #{0} should be a base64 encoded 32-byte key")]
+    Invalid(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const NEW_KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE";
+
+    fn session() -> CookieSession {
+        CookieSession {
+            id: Uuid::new_v4(),
+            user: "alice@example.com".to_string(),
+            issued_at: 1000,
+            expires_at: 4600,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let sealer = CookieSealer::new(&[NEW_KEY]).unwrap();
+        let session = session();
+        let token = sealer.seal(&session);
+        let opened = sealer.open(&token).unwrap();
+        assert_eq!(opened.id, session.id);
+        assert_eq!(opened.user, session.user);
+        assert_eq!(opened.issued_at, 1000);
+        // expiry is left to the caller
+        assert_eq!(opened.expires_at, 4600);
+        // a fresh nonce each time
+        assert_ne!(sealer.seal(&session), token);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let sealer = CookieSealer::new(&[NEW_KEY]).unwrap();
+        let token = BASE64_URL_SAFE_NO_PAD
+            .decode(sealer.seal(&session()))
+            .unwrap();
+        for index in [0, 1, NONCE_LEN + 1, token.len() - 1] {
+            let mut tampered = token.clone();
+            tampered[index] ^= 1;
+            assert!(sealer
+                .open(&BASE64_URL_SAFE_NO_PAD.encode(tampered))
+                .is_none());
+        }
+        for len in [0, 1, NONCE_LEN + 1, token.len() - 1] {
+            let truncated = BASE64_URL_SAFE_NO_PAD.encode(&token[..len]);
+            assert!(sealer.open(&truncated).is_none());
+        }
+        assert!(sealer.open("not base64!").is_none());
+    }
+
+    #[test]
+    fn opens_with_rotated_keys() {
+        let old = CookieSealer::new(&[OLD_KEY]).unwrap();
+        let rotated = CookieSealer::new(&[NEW_KEY, OLD_KEY]).unwrap();
+        let session = session();
+        let opened = rotated.open(&old.seal(&session)).unwrap();
+        assert_eq!(opened.id, session.id);
+        // new tokens are sealed with the new key only
+        let token = rotated.seal(&session);
+        assert!(old.open(&token).is_none());
+        assert!(CookieSealer::new(&[NEW_KEY])
+            .unwrap()
+            .open(&token)
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(matches!(
+            CookieSealer::new::<&str>(&[]),
+            Err(CookieKeyError::Empty)
+        ));
+        assert!(matches!(
+            CookieSealer::new(&[NEW_KEY, "c2hvcnQ="]),
+            Err(CookieKeyError::Invalid(1))
+        ));
+        assert!(matches!(
+            CookieSealer::new(&["not base64!"]),
+            Err(CookieKeyError::Invalid(0))
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use super::{audit_id, limit::UserLimit, memory::MemoryStore, Session, SessionStore, StoreError};
+use std::{sync::Arc, time::Duration};
+use tracing::warn;
+
+/// Keeps sessions in a local memory store while the primary store is unavailable
+///
+/// Sessions created during an outage only exist on this instance and are looked up locally after
+/// the primary store is back, until they expire. Sessions deleted during an outage stay in the
+/// primary store.
+pub struct FallbackStore {
+    primary: Arc<dyn SessionStore + Send + Sync>,
+    local: Arc<MemoryStore>,
+}
+
+impl FallbackStore {
+    pub fn new(primary: Arc<dyn SessionStore + Send + Sync>) -> Self {
+        Self {
+            primary,
+            local: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    /// Delete the local sessions older than `max_age` every `interval`, see
+    /// [`MemoryStore::spawn_cleanup_task`]
+    pub fn spawn_cleanup_task(&self, interval: Duration, max_age: u64) {
+        self.local.spawn_cleanup_task(interval, max_age);
+    }
+}
+
+impl SessionStore for FallbackStore {
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        match self.primary.load(session_id) {
+            Ok(Some(session)) => Ok(Some(session)),
+            Ok(None) => self.local.load(session_id),
+            Err(err) if err.is_unavailable() => self.local.load(session_id),
+            Err(err) => Err(err),
+        }
+    }
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        match self.primary.save(session_id, session) {
+            Err(err) if err.is_unavailable() => self.local.save(session_id, session),
+            res => res,
+        }
+    }
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        match self.primary.update(session_id, session) {
+            Ok(true) => Ok(true),
+            // the session may have been created during an outage
+            Ok(false) => self.local.update(session_id, session),
+            // only sessions created during the outage can be updated, the others are unknown
+            Err(err) if err.is_unavailable() => match self.local.update(session_id, session)? {
+                true => Ok(true),
+                false => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        // during an outage, only the sessions kept locally count towards the limit
+        match self.primary.create(session_id, session, limit) {
+            Err(err) if err.is_unavailable() => self.local.create(session_id, session, limit),
+            res => res,
+        }
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let local = self.local.delete(session_id)?;
+        match self.primary.delete(session_id) {
+            Ok(primary) => Ok(primary.or(local)),
+            Err(err) if err.is_unavailable() => {
+                warn!(
+                    "Session {} is only deleted locally: {}",
+                    audit_id(session_id),
+                    err
+                );
+                Ok(local)
+            }
+            Err(err) => Err(err),
+        }
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        let mut sessions = match self.primary.list() {
+            Ok(sessions) => sessions,
+            Err(err) if err.is_unavailable() => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        sessions.extend(self.local.list()?);
+        Ok(sessions)
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        let mut sessions = match self.primary.user_sessions(user) {
+            Ok(sessions) => sessions,
+            Err(err) if err.is_unavailable() => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        sessions.extend(self.local.user_sessions(user)?);
+        Ok(sessions)
+    }
+    fn flush(&self) {
+        self.primary.flush()
+    }
+}
@@ -0,0 +1,125 @@
+use super::{limit::UserLimit, unix_now, Session, SessionIter, SessionStore, StoreError};
+use serde::Deserialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use tracing::{info, warn};
+
+/// What to do with requests while the session store is unavailable
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OutagePolicy {
+    /// Respond with `503 Service Unavailable`
+    #[default]
+    FailClosed,
+    /// Let requests with valid Basic credentials through without a session
+    BasicAuth,
+    /// Keep sessions in a local memory store until the store is back
+    Memory,
+}
+
+/// Whether the session store is reachable, updated by [`MonitoredStore`]
+pub struct StoreHealth {
+    healthy: AtomicBool,
+    /// When the health last changed
+    since: AtomicU64,
+    /// Number of failed operations, whether the store was unavailable or failed otherwise
+    failures: AtomicU64,
+}
+
+impl StoreHealth {
+    pub fn new() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            since: AtomicU64::new(unix_now()),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn since(&self) -> u64 {
+        self.since.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn observe<T>(&self, result: &Result<T, StoreError>) {
+        if result.is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        match result {
+            Err(err) if err.is_unavailable() => {
+                if self.healthy.swap(false, Ordering::Relaxed) {
+                    self.since.store(unix_now(), Ordering::Relaxed);
+                    warn!("{}", err);
+                }
+            }
+            // an error answered by the store shows it is reachable
+            _ => {
+                if !self.healthy.swap(true, Ordering::Relaxed) {
+                    let down =
+                        unix_now().saturating_sub(self.since.swap(unix_now(), Ordering::Relaxed));
+                    info!("Session store is available again after {}s", down);
+                }
+            }
+        }
+    }
+}
+
+/// Records the outcome of every operation on the inner store in a [`StoreHealth`]
+pub struct MonitoredStore {
+    inner: Arc<dyn SessionStore + Send + Sync>,
+    health: Arc<StoreHealth>,
+}
+
+impl MonitoredStore {
+    pub fn new(inner: Arc<dyn SessionStore + Send + Sync>, health: Arc<StoreHealth>) -> Self {
+        Self { inner, health }
+    }
+
+    fn observe<T>(&self, result: Result<T, StoreError>) -> Result<T, StoreError> {
+        self.health.observe(&result);
+        result
+    }
+}
+
+impl SessionStore for MonitoredStore {
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        self.observe(self.inner.load(session_id))
+    }
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        self.observe(self.inner.save(session_id, session))
+    }
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        self.observe(self.inner.update(session_id, session))
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        self.observe(self.inner.create(session_id, session, limit))
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        self.observe(self.inner.delete(session_id))
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        self.observe(self.inner.list())
+    }
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        self.observe(self.inner.iter())
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        self.observe(self.inner.user_sessions(user))
+    }
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,94 @@
+use super::Session;
+use crate::auth::groups::Groups;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+
+/// What to do when a user logs in with the maximum number of sessions
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    /// Revoke the oldest sessions to make room for the new one
+    EvictOldest,
+    /// Refuse the new login
+    Refuse,
+}
+
+/// Maximum number of concurrent sessions per user
+pub struct SessionLimit {
+    pub policy: LimitPolicy,
+    default: Option<usize>,
+    users: HashMap<String, usize>,
+    groups: HashMap<String, usize>,
+    membership: Arc<Groups>,
+}
+
+impl SessionLimit {
+    /// A limit of 0 means unlimited
+    pub fn new(
+        policy: LimitPolicy,
+        default: usize,
+        users: HashMap<String, usize>,
+        groups: HashMap<String, usize>,
+        membership: Arc<Groups>,
+    ) -> Self {
+        Self {
+            policy,
+            default: (default > 0).then_some(default),
+            users,
+            groups,
+            membership,
+        }
+    }
+
+    /// The limit of the user, a limit set for the user takes precedence over the ones of the
+    /// groups, and the smallest limit of the groups takes precedence over the default
+    pub fn of(&self, user: &str) -> Option<usize> {
+        let limit = match self.users.get(user) {
+            Some(limit) => Some(*limit),
+            None => self
+                .membership
+                .of(user)
+                .iter()
+                .filter_map(|group| self.groups.get(group).copied())
+                .min()
+                .or(self.default),
+        };
+        limit.filter(|limit| *limit > 0)
+    }
+}
+
+/// The limit applied to a login, see [`SessionStore::create`](super::SessionStore::create)
+#[derive(Clone, Copy, Debug)]
+pub struct UserLimit {
+    pub max_sessions: usize,
+    pub policy: LimitPolicy,
+    /// Sessions older than this are not counted
+    pub max_age: u64,
+}
+
+impl UserLimit {
+    /// The IDs of the sessions to delete to make room for a new session among the sessions of the
+    /// user, or `None` if the login is refused
+    pub fn evictions(&self, mut sessions: Vec<(String, Session)>, now: u64) -> Option<Vec<String>> {
+        sessions.retain(|(_, session)| {
+            !session.is_replaced() && now.saturating_sub(session.created_at) < self.max_age
+        });
+        if sessions.len() < self.max_sessions {
+            return Some(Vec::new());
+        }
+        match self.policy {
+            LimitPolicy::Refuse => None,
+            LimitPolicy::EvictOldest => {
+                sessions.sort_by_key(|(_, session)| session.created_at);
+                let evicted = sessions.len() + 1 - self.max_sessions;
+                Some(
+                    sessions
+                        .into_iter()
+                        .take(evicted)
+                        .map(|(session_id, _)| session_id)
+                        .collect(),
+                )
+            }
+        }
+    }
+}
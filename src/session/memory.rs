@@ -1,27 +1,221 @@
+use super::{
+    create_limited, limit::UserLimit, snapshot, unix_now, Session, SessionStore, StoreError,
+};
 use dashmap::DashMap;
-use super::SessionStore;
+use std::{
+    collections::HashSet,
+    hash::{BuildHasher, RandomState},
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tracing::{debug, error, info};
+
+/// How many locks the logins of users are spread over
+const LOGIN_LOCKS: usize = 64;
 
 pub struct MemoryStore {
-    inner: DashMap<String, u64>,
+    inner: DashMap<String, Session>,
+    /// Session IDs by user
+    by_user: DashMap<String, HashSet<String>>,
+    snapshot_path: Option<PathBuf>,
+    /// Serialize the logins of each user, so concurrent logins cannot exceed the session limit
+    logins: Box<[Mutex<()>]>,
+    hasher: RandomState,
 }
 
 impl MemoryStore {
     pub fn new() -> Self {
         Self {
             inner: DashMap::new(),
+            by_user: DashMap::new(),
+            snapshot_path: None,
+            logins: (0..LOGIN_LOCKS).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Create a store which persists itself to `path`, the sessions in the existing snapshot are
+    /// restored and the ones older than `max_age` are discarded
+    pub fn with_snapshot(path: impl Into<PathBuf>, max_age: u64) -> Self {
+        let path = path.into();
+        let store = Self {
+            snapshot_path: Some(path.clone()),
+            ..Self::new()
+        };
+
+        if path.exists() {
+            match snapshot::read(&path) {
+                Ok(entries) => {
+                    let now = unix_now();
+                    for (session_id, session) in entries {
+                        if now.saturating_sub(session.created_at) < max_age {
+                            store.insert(&session_id, session);
+                        }
+                    }
+                    info!(
+                        "Restored {} sessions from {}",
+                        store.inner.len(),
+                        path.display()
+                    );
+                }
+                Err(err) => error!(
+                    "Failed to restore sessions from {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+
+        store
+    }
+
+    /// Write all sessions to the snapshot file, do nothing if snapshot is not enabled
+    pub fn snapshot(&self) -> std::io::Result<()> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        snapshot::write(path, &self.list_entries())
+    }
+
+    fn insert(&self, session_id: &str, session: Session) {
+        let user = session.user.clone();
+        let previous = self.inner.insert(session_id.to_string(), session);
+        if let Some(previous) = previous.filter(|previous| previous.user != user) {
+            self.unindex(&previous.user, session_id);
+        }
+        self.by_user
+            .entry(user)
+            .or_default()
+            .insert(session_id.to_string());
+    }
+
+    fn unindex(&self, user: &str, session_id: &str) {
+        self.by_user.remove_if_mut(user, |_, session_ids| {
+            session_ids.remove(session_id);
+            session_ids.is_empty()
+        });
+    }
+
+    fn list_entries(&self) -> Vec<(String, Session)> {
+        self.inner
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Delete the sessions older than `max_age`, return the number of sessions deleted
+    pub fn delete_expired(&self, max_age: u64) -> usize {
+        let now = unix_now();
+        let expired = self
+            .inner
+            .iter()
+            .filter(|entry| entry.value().is_expired(now, max_age))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let mut deleted = 0;
+        for session_id in expired {
+            // the session may have been replaced since it was found expired
+            if let Some((_, session)) = self
+                .inner
+                .remove_if(&session_id, |_, session| session.is_expired(now, max_age))
+            {
+                self.unindex(&session.user, &session_id);
+                deleted += 1;
+            }
         }
+        deleted
+    }
+
+    /// Delete the sessions older than `max_age` every `interval` in a background thread, the
+    /// thread stops once the store is dropped
+    pub fn spawn_cleanup_task(self: &Arc<Self>, interval: Duration, max_age: u64) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            debug!("Deleted {} expired sessions", store.delete_expired(max_age));
+        });
+    }
+
+    /// Snapshot the store every `interval` in a background thread, the thread stops once the store
+    /// is dropped
+    pub fn spawn_snapshot_task(self: &Arc<Self>, interval: Duration) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            if let Err(err) = store.snapshot() {
+                error!("Failed to snapshot sessions: {}", err);
+            }
+        });
     }
 }
 
 impl SessionStore for MemoryStore {
-    fn load(&self, session_id: &str) -> Option<u64> {
-        self.inner.get(session_id).map(|res| *res.value())
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        Ok(self.inner.get(session_id).map(|res| res.value().clone()))
     }
-    fn save(&self, session_id: &str, timestamp: u64) -> Option<()> {
-        self.inner.insert(session_id.to_string(), timestamp)?;
-        Some(())
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        self.insert(session_id, session.clone());
+        Ok(())
     }
-    fn delete(&self, session_id: &str) -> Option<u64> {
-        self.inner.remove(session_id).map(|(_key, value)| value)
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        let Some(mut entry) = self.inner.get_mut(session_id) else {
+            return Ok(false);
+        };
+        let previous = std::mem::replace(entry.value_mut(), session.clone());
+        drop(entry);
+        if previous.user != session.user {
+            self.unindex(&previous.user, session_id);
+            self.by_user
+                .entry(session.user.clone())
+                .or_default()
+                .insert(session_id.to_string());
+        }
+        Ok(true)
     }
-}
\ No newline at end of file
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        let lock = &self.logins[self.hasher.hash_one(&session.user) as usize % LOGIN_LOCKS];
+        let _guard = lock.lock().unwrap_or_else(|err| err.into_inner());
+        create_limited(self, session_id, session, limit)
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let Some((_key, session)) = self.inner.remove(session_id) else {
+            return Ok(None);
+        };
+        self.unindex(&session.user, session_id);
+        Ok(Some(session))
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        Ok(self.list_entries())
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        let session_ids = match self.by_user.get(user) {
+            Some(session_ids) => session_ids.iter().cloned().collect::<Vec<_>>(),
+            None => return Ok(Vec::new()),
+        };
+        let sessions = session_ids
+            .into_iter()
+            .filter_map(|session_id| {
+                let session = self.inner.get(&session_id)?.value().clone();
+                Some((session_id, session))
+            })
+            .collect();
+        Ok(sessions)
+    }
+    fn flush(&self) {
+        if let Err(err) = self.snapshot() {
+            error!("Failed to snapshot sessions: {}", err);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use super::{unix_now, SessionStore, StoreError};
+use tracing::warn;
+
+/// What [`migrate`] did with the sessions it found
+#[derive(Default, Debug)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    /// Sessions found expired, which are left out
+    pub expired: usize,
+    /// Records which cannot be read, which are left out
+    pub skipped: usize,
+}
+
+/// Copy every live session from `from` to `to`. Stores expire a session `max_age` seconds after it
+/// was created and the creation time is copied along, so each session keeps its remaining
+/// lifetime. Unreadable records are skipped, while an unavailable store aborts the migration.
+pub fn migrate(
+    from: &dyn SessionStore,
+    to: &dyn SessionStore,
+    max_age: u64,
+) -> Result<MigrationReport, StoreError> {
+    let mut report = MigrationReport::default();
+    for record in from.iter()? {
+        let (session_id, session) = match record {
+            Ok(record) => record,
+            Err(err) if err.is_unavailable() => return Err(err),
+            Err(err) => {
+                warn!("Skipping session: {}", err);
+                report.skipped += 1;
+                continue;
+            }
+        };
+        if session.is_expired(unix_now(), max_age) {
+            report.expired += 1;
+            continue;
+        }
+        to.save(&session_id, &session)?;
+        report.migrated += 1;
+    }
+    to.flush();
+    Ok(report)
+}
@@ -1,61 +1,1085 @@
+use binding::{ClientInfo, SessionBinding};
+use cookie::{CookieSealer, CookieSession};
+use health::{OutagePolicy, StoreHealth};
+use limit::{SessionLimit, UserLimit};
+use serde::{Deserialize, Serialize};
+use set_cookie::SessionCookie;
+use signer::SessionSigner;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::runtime::RuntimeFlavor;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+pub mod binding;
+pub mod cache;
+pub mod cookie;
+pub mod fallback;
+pub mod health;
+pub mod limit;
 pub mod memory;
+pub mod migrate;
+pub mod postgres;
 pub mod redis;
+pub mod set_cookie;
+pub mod signer;
+mod snapshot;
+pub mod sqlite;
+
+/// How often the last seen time of a session is written back to the store, denoted in second
+const LAST_SEEN_RESOLUTION: u64 = 60;
+/// How many sessions stores which page through their records fetch at once
+const PAGE_SIZE: usize = 500;
+/// Security events are logged under this target, with their details as fields
+pub const AUDIT_TARGET: &str = "watchdawg::audit";
+
+/// A session kept in a [`SessionStore`]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Session {
+    pub user: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    /// SHA-256 fingerprint of the TLS client certificate the session was created with
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// When the session ID was last rotated, 0 if never
+    #[serde(default)]
+    pub rotated_at: u64,
+    /// Set on the old ID after rotation, which is still accepted until then
+    #[serde(default)]
+    pub grace_until: Option<u64>,
+    /// Whether the user agent and client certificate were recorded when the session was created,
+    /// sessions from before session binding get them from the first request instead
+    #[serde(default)]
+    pub client_recorded: bool,
+}
+
+impl Session {
+    pub fn new(user: impl Into<String>, ip: Option<IpAddr>, now: u64) -> Self {
+        Self {
+            user: user.into(),
+            created_at: now,
+            last_seen: now,
+            ip,
+            user_agent: None,
+            client_cert: None,
+            rotated_at: 0,
+            grace_until: None,
+            client_recorded: true,
+        }
+    }
+
+    /// Return true if this is an old ID kept for the grace window after rotation
+    pub fn is_replaced(&self) -> bool {
+        self.grace_until.is_some()
+    }
+
+    /// Return true if the session is older than `max_age`, or its grace window after rotation is
+    /// over
+    pub fn is_expired(&self, now: u64, max_age: u64) -> bool {
+        now.saturating_sub(self.created_at) >= max_age
+            || self.grace_until.is_some_and(|until| now >= until)
+    }
+}
+
+/// A session which passed validation
+pub struct ValidSession {
+    pub session: Session,
+    /// The new cookie value if the session ID was rotated
+    pub renewed: Option<String>,
+}
 
 pub trait SessionStore {
-    fn load(&self, session_id: &str) -> Option<u64>;
-    fn save(&self, session_id: &str, timestamp: u64) -> Option<()>;
-    fn delete(&self, session_id: &str) -> Option<u64>;
+    /// Return `Ok(None)` if there is no such session
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError>;
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError>;
+    /// Overwrite a session only if it is still in the store, so that a session deleted meanwhile
+    /// is not brought back. Return false if there is no such session.
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError>;
+    /// Save a new session, first making room for it if the user already has as many live
+    /// sessions as `limit` allows. The check and the save are atomic with regard to other logins
+    /// of the user. Return the IDs of the sessions evicted, or `None` if the login is refused.
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError>;
+    /// Return the deleted session, or `Ok(None)` if there is no such session
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError>;
+    /// Return all sessions in the store
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError>;
+    /// Iterate over all sessions in the store without loading them at once, stores which can
+    /// page through their records should override this default
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        Ok(Box::new(self.list()?.into_iter().map(Ok)))
+    }
+    /// Return all sessions of the user, stores should keep an index of sessions by user rather
+    /// than relying on this default
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        let mut sessions = self.list()?;
+        sessions.retain(|(_, session)| session.user == user);
+        Ok(sessions)
+    }
+    /// Persist anything still buffered, called once on graceful shutdown
+    fn flush(&self) {}
+}
+
+/// Make room for the session under `limit` and save it, for stores which implement
+/// [`SessionStore::create`] by holding a lock on the logins of the user around this
+fn create_limited<S: SessionStore + ?Sized>(
+    store: &S,
+    session_id: &str,
+    session: &Session,
+    limit: Option<&UserLimit>,
+) -> Result<Option<Vec<String>>, StoreError> {
+    let evicted = match limit {
+        Some(limit) => match limit.evictions(store.user_sessions(&session.user)?, unix_now()) {
+            Some(evicted) => evicted,
+            None => return Ok(None),
+        },
+        None => Vec::new(),
+    };
+    for session_id in &evicted {
+        store.delete(session_id)?;
+    }
+    store.save(session_id, session)?;
+    Ok(Some(evicted))
+}
+
+/// Load the session, a record which cannot be read is deleted so the client has to log in again
+fn load_readable(
+    store: &(dyn SessionStore + Send + Sync),
+    session_id: &str,
+) -> Result<Option<Session>, StoreError> {
+    match store.load(session_id) {
+        Err(StoreError::Corrupted(err)) => {
+            warn!(
+                "Deleting unreadable session {}: {}",
+                audit_id(session_id),
+                err
+            );
+            match store.delete(session_id) {
+                Ok(_) | Err(StoreError::Corrupted(_)) => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+        result => result,
+    }
+}
+
+/// Run a blocking call of a store, on a worker thread of the server the other tasks are moved
+/// to another thread meanwhile
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// Sessions yielded by [`SessionStore::iter`], records which cannot be read are yielded as errors
+pub type SessionIter<'a> = Box<dyn Iterator<Item = Result<(String, Session), StoreError>> + 'a>;
+
+/// Page through records ordered by session ID, `fetch` returns up to [`PAGE_SIZE`] session IDs
+/// greater than the given one with their sessions as JSON
+fn paged<'a>(
+    mut fetch: impl FnMut(&str) -> Result<Vec<(String, String)>, StoreError> + 'a,
+) -> SessionIter<'a> {
+    let mut page = Vec::<(String, String)>::new().into_iter();
+    let mut after = String::new();
+    let mut done = false;
+    Box::new(std::iter::from_fn(move || loop {
+        if let Some((session_id, data)) = page.next() {
+            after.clone_from(&session_id);
+            return Some(
+                serde_json::from_str(&data)
+                    .map(|session| (session_id, session))
+                    .map_err(StoreError::from),
+            );
+        }
+        if done {
+            return None;
+        }
+        match fetch(&after) {
+            Ok(records) => {
+                done = records.len() < PAGE_SIZE;
+                page = records.into_iter();
+            }
+            Err(err) => {
+                done = true;
+                return Some(Err(err));
+            }
+        }
+    }))
+}
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    /// The store cannot be reached, the operation may succeed later
+    #[error("Session store unavailable: {0}")]
+    Unavailable(Box<dyn std::error::Error + Send + Sync>),
+    /// The store refused the operation, such as a command on a key of the wrong type, retrying
+    /// fails the same way
+    #[error("Session store error: {0}")]
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+    /// The record cannot be read, the session it holds is treated as invalid
+    #[error("Invalid session record: {0}")]
+    Corrupted(#[from] serde_json::Error),
+}
+
+impl StoreError {
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, Self::Unavailable(_))
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(err: r2d2::Error) -> Self {
+        Self::Unavailable(err.into())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("Too many active sessions")]
+    TooManySessions,
+    #[error("Sessions are not kept on the server side")]
+    Unsupported,
 }
 
 pub struct SessionManager {
-    pub cookie_name: String,
+    pub cookie: SessionCookie,
     pub max_age: u64,
-    store: Arc<dyn SessionStore + Send + Sync>,
+    /// Requests to this path revoke the current session
+    pub logout_path: Option<String>,
+    /// Requests to this path verify the credentials again and rotate the session ID
+    pub reauth_path: Option<String>,
+    /// Maximum number of concurrent sessions per user, only for sessions kept in a store
+    pub limit: Option<SessionLimit>,
+    /// Attributes of the client sessions are bound to, only for sessions kept in a store
+    pub binding: Option<SessionBinding>,
+    /// Rotate the session ID after this many seconds
+    pub rotate_interval: Option<u64>,
+    /// How long the old ID is still accepted after rotation, denoted in second
+    pub rotate_grace: u64,
+    /// What to do with requests while the store is unavailable
+    pub outage_policy: OutagePolicy,
+    /// The health of the store, if it is monitored
+    pub health: Option<Arc<StoreHealth>>,
+    backend: SessionBackend,
+}
+
+enum SessionBackend {
+    /// The cookie holds a random session ID that points into the store, optionally signed
+    Store {
+        store: Arc<dyn SessionStore + Send + Sync>,
+        signer: Option<SessionSigner>,
+    },
+    /// The cookie holds the encrypted session itself, the optional store is the revocation list
+    Cookie {
+        sealer: CookieSealer,
+        revoked: Option<Arc<dyn SessionStore + Send + Sync>>,
+    },
 }
 
 impl SessionManager {
     pub fn new(
-        cookie_name: impl Into<String>,
+        cookie: SessionCookie,
         store: Arc<dyn SessionStore + Send + Sync>,
+        signer: Option<SessionSigner>,
+        max_age: u64,
+    ) -> Self {
+        Self {
+            cookie,
+            backend: SessionBackend::Store { store, signer },
+            max_age,
+            logout_path: None,
+            reauth_path: None,
+            limit: None,
+            binding: None,
+            rotate_interval: None,
+            rotate_grace: 0,
+            outage_policy: OutagePolicy::default(),
+            health: None,
+        }
+    }
+
+    /// Create a manager for stateless sessions, which are carried in encrypted cookies
+    pub fn with_cookie(
+        cookie: SessionCookie,
+        sealer: CookieSealer,
+        revoked: Option<Arc<dyn SessionStore + Send + Sync>>,
         max_age: u64,
     ) -> Self {
         Self {
-            cookie_name: cookie_name.into(),
-            store,
+            cookie,
+            backend: SessionBackend::Cookie { sealer, revoked },
             max_age,
+            logout_path: None,
+            reauth_path: None,
+            limit: None,
+            binding: None,
+            rotate_interval: None,
+            rotate_grace: 0,
+            outage_policy: OutagePolicy::default(),
+            health: None,
+        }
+    }
+
+    /// Return [`SessionError::TooManySessions`] if the user has too many sessions and new logins
+    /// are refused
+    pub fn create_session(&self, user: &str, client: &ClientInfo) -> Result<String, SessionError> {
+        let now = unix_now();
+        let session_id = match &self.backend {
+            SessionBackend::Store { store, signer } => {
+                let uuid = Uuid::new_v4().to_string();
+                let mut session = Session::new(user, client.ip, now);
+                session.user_agent = client.user_agent.clone();
+                session.client_cert = client.client_cert.clone();
+                let limit = self.limit.as_ref().and_then(|limit| {
+                    Some(UserLimit {
+                        max_sessions: limit.of(user)?,
+                        policy: limit.policy,
+                        max_age: self.max_age,
+                    })
+                });
+                match store.create(&uuid, &session, limit.as_ref())? {
+                    Some(evicted) if !evicted.is_empty() => {
+                        info!("Evicted {} oldest sessions of {}", evicted.len(), user)
+                    }
+                    Some(_) => (),
+                    None => {
+                        info!("Refused login of {}, too many sessions", user);
+                        return Err(SessionError::TooManySessions);
+                    }
+                }
+                sign(signer, uuid)
+            }
+            SessionBackend::Cookie { sealer, .. } => sealer.seal(&CookieSession {
+                id: Uuid::new_v4(),
+                user: user.to_string(),
+                issued_at: now,
+                expires_at: now.saturating_add(self.max_age),
+            }),
+        };
+        Ok(session_id)
+    }
+
+    /// Return the session if it is valid, the session ID is rotated if it is due
+    pub fn validate_session(
+        &self,
+        cookie_value: &str,
+        client: &ClientInfo,
+    ) -> Result<Option<ValidSession>, StoreError> {
+        let now = unix_now();
+        match &self.backend {
+            SessionBackend::Store { store, signer } => {
+                let Some(session_id) = verify(signer, cookie_value) else {
+                    return Ok(None);
+                };
+                let Some(mut session) = load_readable(store.as_ref(), session_id)? else {
+                    return Ok(None);
+                };
+
+                // delete session if expired
+                if session.is_expired(now, self.max_age) {
+                    store.delete(session_id)?;
+                    return Ok(None);
+                }
+                // the client of a session from before session binding is unknown, so the session is
+                // bound to the first one using it
+                if self.binding.is_some() && !session.client_recorded {
+                    session.user_agent.clone_from(&client.user_agent);
+                    session.client_cert.clone_from(&client.client_cert);
+                    session.client_recorded = true;
+                    if !store.update(session_id, &session)? {
+                        return Ok(None);
+                    }
+                }
+                // a session used by another client is likely stolen, so it is revoked for good
+                if let Some(attribute) = self
+                    .binding
+                    .as_ref()
+                    .and_then(|binding| binding.mismatch(&session, client))
+                {
+                    store.delete(session_id)?;
+                    let ip = client.ip.map(|ip| ip.to_string()).unwrap_or_default();
+                    warn!(
+                        target: AUDIT_TARGET,
+                        event = "session_revoked",
+                        user = %session.user,
+                        session = %audit_id(session_id),
+                        reason = %format!("{} mismatch", attribute),
+                        client = %ip,
+                        "Revoked session of {} used with a different {} from {}",
+                        session.user,
+                        attribute,
+                        ip
+                    );
+                    return Ok(None);
+                }
+                if session.is_replaced() {
+                    return Ok(Some(ValidSession {
+                        session,
+                        renewed: None,
+                    }));
+                }
+
+                if self.is_rotation_due(session.created_at.max(session.rotated_at), now) {
+                    let Some(renewed) =
+                        self.rotate_stored(store.as_ref(), signer, session_id, &session)?
+                    else {
+                        return Ok(None);
+                    };
+                    return Ok(Some(ValidSession {
+                        session,
+                        renewed: Some(renewed),
+                    }));
+                }
+                // a session revoked since it was loaded is not brought back
+                if now.saturating_sub(session.last_seen) >= LAST_SEEN_RESOLUTION {
+                    session.last_seen = now;
+                    if !store.update(session_id, &session)? {
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(ValidSession {
+                    session,
+                    renewed: None,
+                }))
+            }
+            SessionBackend::Cookie { sealer, revoked } => {
+                let Some(cookie_session) = sealer.open(cookie_value) else {
+                    return Ok(None);
+                };
+                if now >= cookie_session.expires_at {
+                    return Ok(None);
+                }
+                if let Some(revoked) = revoked {
+                    // a revocation takes effect at the time recorded, which is in the future for
+                    // the old ID during the grace window after rotation
+                    if revoked
+                        .load(&revocation_key(&cookie_session.id))?
+                        .is_some_and(|record| record.created_at <= now)
+                    {
+                        return Ok(None);
+                    }
+                    // all sessions of the user issued before the cutoff are revoked
+                    if revoked
+                        .load(&user_revocation_key(&cookie_session.user))?
+                        .is_some_and(|cutoff| cookie_session.issued_at <= cutoff.created_at)
+                    {
+                        return Ok(None);
+                    }
+                }
+
+                let renewed = match self.is_rotation_due(cookie_session.issued_at, now) {
+                    true => Some(self.rotate_cookie(sealer, revoked, &cookie_session, now)?),
+                    false => None,
+                };
+                let mut session =
+                    Session::new(&cookie_session.user, None, cookie_session.issued_at);
+                session.last_seen = now;
+                Ok(Some(ValidSession { session, renewed }))
+            }
+        }
+    }
+
+    /// Give the session of the cookie a new ID at once, to be called whenever the privilege of the
+    /// session changes, such as after re-authentication. The old ID is still accepted for the
+    /// grace window. Return the new cookie value, or `None` if the session is not valid or its ID
+    /// was already replaced.
+    pub fn rotate(&self, cookie_value: &str) -> Result<Option<String>, StoreError> {
+        let now = unix_now();
+        match &self.backend {
+            SessionBackend::Store { store, signer } => {
+                let Some(session_id) = verify(signer, cookie_value) else {
+                    return Ok(None);
+                };
+                // rotating a replaced ID again would fork the session and extend its grace window
+                match load_readable(store.as_ref(), session_id)? {
+                    Some(session)
+                        if !session.is_expired(now, self.max_age) && !session.is_replaced() =>
+                    {
+                        self.rotate_stored(store.as_ref(), signer, session_id, &session)
+                    }
+                    _ => Ok(None),
+                }
+            }
+            SessionBackend::Cookie { sealer, revoked } => {
+                let Some(session) = sealer.open(cookie_value) else {
+                    return Ok(None);
+                };
+                if now >= session.expires_at {
+                    return Ok(None);
+                }
+                if let Some(revoked) = revoked {
+                    if revoked.load(&revocation_key(&session.id))?.is_some() {
+                        return Ok(None);
+                    }
+                }
+                self.rotate_cookie(sealer, revoked, &session, now).map(Some)
+            }
+        }
+    }
+
+    fn is_rotation_due(&self, since: u64, now: u64) -> bool {
+        self.rotate_interval
+            .is_some_and(|interval| now.saturating_sub(since) >= interval)
+    }
+
+    /// Move the session to a new ID, the old ID is kept for the grace window. Return `None` if
+    /// the session was revoked since it was loaded.
+    fn rotate_stored(
+        &self,
+        store: &(dyn SessionStore + Send + Sync),
+        signer: &Option<SessionSigner>,
+        session_id: &str,
+        session: &Session,
+    ) -> Result<Option<String>, StoreError> {
+        let now = unix_now();
+        // the old ID is retired first, so a revoked session is not carried over to the new ID
+        let retired = match self.rotate_grace {
+            0 => store.delete(session_id)?.is_some(),
+            grace => {
+                let mut old = session.clone();
+                old.grace_until = Some(now.saturating_add(grace));
+                store.update(session_id, &old)?
+            }
+        };
+        if !retired {
+            return Ok(None);
+        }
+
+        let new_id = Uuid::new_v4().to_string();
+        let mut renewed = session.clone();
+        renewed.rotated_at = now;
+        renewed.last_seen = now;
+        store.save(&new_id, &renewed)?;
+        Ok(Some(sign(signer, new_id)))
+    }
+
+    /// Seal the session under a new ID, the old ID is revoked after the grace window if there is
+    /// a revocation list
+    fn rotate_cookie(
+        &self,
+        sealer: &CookieSealer,
+        revoked: &Option<Arc<dyn SessionStore + Send + Sync>>,
+        session: &CookieSession,
+        now: u64,
+    ) -> Result<String, StoreError> {
+        if let Some(revoked) = revoked {
+            let effective_at = now.saturating_add(self.rotate_grace);
+            let record = Session::new(&session.user, None, effective_at);
+            revoked.save(&revocation_key(&session.id), &record)?;
+        }
+        Ok(sealer.seal(&CookieSession {
+            id: Uuid::new_v4(),
+            user: session.user.clone(),
+            issued_at: now,
+            expires_at: session.expires_at,
+        }))
+    }
+
+    /// Return all live sessions, or [`SessionError::Unsupported`] if the sessions are not kept on
+    /// the server side
+    pub fn list_sessions(&self) -> Result<Vec<(String, Session)>, SessionError> {
+        match &self.backend {
+            SessionBackend::Store { store, .. } => {
+                let now = unix_now();
+                let mut sessions = store.list()?;
+                sessions.retain(|(_, session)| {
+                    !session.is_replaced() && now.saturating_sub(session.created_at) < self.max_age
+                });
+                Ok(sessions)
+            }
+            SessionBackend::Cookie { .. } => Err(SessionError::Unsupported),
+        }
+    }
+
+    /// Invalidate the session of the cookie, used for logout
+    pub fn logout(&self, cookie_value: &str) -> Result<bool, StoreError> {
+        match &self.backend {
+            SessionBackend::Store { signer, .. } => match verify(signer, cookie_value) {
+                Some(session_id) => self.revoke_session(session_id),
+                None => Ok(false),
+            },
+            SessionBackend::Cookie { .. } => self.revoke_session(cookie_value),
         }
     }
 
-    pub fn create_session(&self) -> String {
-        let uuid = Uuid::new_v4().to_string();
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+    /// Invalidate the session, return false if there is no such session
+    ///
+    /// The session ID is the one in the store as listed by [`SessionManager::list_sessions`], or
+    /// the cookie value for sessions carried in cookies.
+    pub fn revoke_session(&self, session_id: &str) -> Result<bool, StoreError> {
+        match &self.backend {
+            SessionBackend::Store { store, .. } => match store.delete(session_id) {
+                Ok(session) => Ok(session.is_some()),
+                // the record is gone even though it could not be read
+                Err(StoreError::Corrupted(_)) => Ok(true),
+                Err(err) => Err(err),
+            },
+            SessionBackend::Cookie { sealer, revoked } => {
+                let Some(session) = sealer.open(session_id) else {
+                    return Ok(false);
+                };
+                match revoked {
+                    Some(revoked) => {
+                        let record = Session::new(session.user, None, session.issued_at);
+                        revoked.save(&revocation_key(&session.id), &record)?;
+                        Ok(true)
+                    }
+                    None => {
+                        warn!(
+                            "Session of `{}` cannot be revoked without a revocation list",
+                            session.user
+                        );
+                        Ok(false)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Invalidate all sessions of the user, return the number of sessions revoked, or
+    /// [`SessionError::Unsupported`] if the sessions cannot be revoked
+    ///
+    /// For sessions carried in cookies, the number of sessions is unknown so 0 is returned, every
+    /// session of the user issued until now is rejected afterwards.
+    pub fn revoke_user(&self, user: &str) -> Result<usize, SessionError> {
+        match &self.backend {
+            SessionBackend::Store { store, .. } => {
+                let mut revoked = 0;
+                for (session_id, _) in store.user_sessions(user)? {
+                    if store.delete(&session_id)?.is_some() {
+                        revoked += 1;
+                    }
+                }
+                Ok(revoked)
+            }
+            SessionBackend::Cookie { revoked, .. } => {
+                let cutoff = Session::new(user, None, unix_now());
+                revoked
+                    .as_ref()
+                    .ok_or(SessionError::Unsupported)?
+                    .save(&user_revocation_key(user), &cutoff)?;
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Strip and check the tag if session IDs are signed
+fn verify<'a>(signer: &Option<SessionSigner>, session_id: &'a str) -> Option<&'a str> {
+    match signer {
+        Some(signer) => signer.verify(session_id),
+        None => Some(session_id),
+    }
+}
+
+fn sign(signer: &Option<SessionSigner>, session_id: String) -> String {
+    match signer {
+        Some(signer) => signer.sign(&session_id),
+        None => session_id,
+    }
+}
+
+/// The start of the session ID, enough to tell sessions apart in the logs without the logs
+/// holding IDs which could be used
+fn audit_id(session_id: &str) -> &str {
+    session_id.get(..8).unwrap_or(session_id)
+}
+
+fn revocation_key(id: &Uuid) -> String {
+    concat_string::concat_string!("revoked:", id.to_string())
+}
+
+fn user_revocation_key(user: &str) -> String {
+    concat_string::concat_string!("revoked-user:", user)
+}
+
+/// Current UNIX timestamp in seconds
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Check the behavior every [`SessionStore`] shares. Session IDs and the user are random, so
+    /// stores shared with other tests can be used.
+    pub(crate) fn check_store(store: &dyn SessionStore) {
+        let user = Uuid::new_v4().to_string();
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let now = unix_now();
+        assert!(store.load(&first).unwrap().is_none());
+
+        store.save(&first, &Session::new(&user, None, now)).unwrap();
+        store
+            .save(&second, &Session::new(&user, None, now))
+            .unwrap();
+        let loaded = store.load(&first).unwrap().unwrap();
+        assert_eq!(loaded.user, user);
+        assert_eq!(loaded.created_at, now);
+
+        let mut user_sessions = store
+            .user_sessions(&user)
             .unwrap()
-            .as_secs();
+            .into_iter()
+            .map(|(session_id, _)| session_id)
+            .collect::<Vec<_>>();
+        user_sessions.sort();
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort();
+        assert_eq!(user_sessions, expected);
+        let listed = store.list().unwrap();
+        assert!(listed.iter().any(|(session_id, _)| *session_id == first));
+        let iterated = store
+            .iter()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(iterated.iter().any(|(session_id, _)| *session_id == second));
+
+        let mut seen = loaded.clone();
+        seen.last_seen = now + 60;
+        assert!(store.update(&first, &seen).unwrap());
+        assert_eq!(store.load(&first).unwrap().unwrap().last_seen, now + 60);
 
-        self.store.save(&uuid, now);
-        uuid
+        assert_eq!(store.delete(&first).unwrap().unwrap().user, user);
+        assert!(store.delete(&first).unwrap().is_none());
+        assert!(store.load(&first).unwrap().is_none());
+        // a deleted session is not brought back by an update
+        assert!(!store.update(&first, &seen).unwrap());
+        assert!(store.load(&first).unwrap().is_none());
+        let user_sessions = store.user_sessions(&user).unwrap();
+        assert_eq!(user_sessions.len(), 1);
+        assert_eq!(user_sessions[0].0, second);
+
+        store.delete(&second).unwrap();
+        assert!(store.user_sessions(&user).unwrap().is_empty());
     }
 
-    pub fn is_session_valid(&self, session: &str) -> bool {
-        let Some(since) = self.store.load(session) else {
-            return false;
+    /// Check that [`SessionStore::create`] keeps the sessions of a user within the limit, also
+    /// when logins race each other
+    pub(crate) fn check_limit(store: &(dyn SessionStore + Sync)) {
+        let user = Uuid::new_v4().to_string();
+        let now = unix_now();
+        let limit = UserLimit {
+            max_sessions: 2,
+            policy: limit::LimitPolicy::EvictOldest,
+            max_age: 3600,
         };
+        let ids = (0..3)
+            .map(|_| Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for (age, session_id) in ids.iter().enumerate() {
+            let session = Session::new(&user, None, now - 10 + age as u64);
+            assert!(store
+                .create(session_id, &session, Some(&limit))
+                .unwrap()
+                .is_some());
+        }
+        assert!(store.load(&ids[0]).unwrap().is_none());
+        assert_eq!(store.user_sessions(&user).unwrap().len(), 2);
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+        let refuse = UserLimit {
+            policy: limit::LimitPolicy::Refuse,
+            ..limit
+        };
+        let session = Session::new(&user, None, now);
+        let refused = Uuid::new_v4().to_string();
+        assert!(store
+            .create(&refused, &session, Some(&refuse))
             .unwrap()
-            .as_secs();
-        let res = now.saturating_sub(since) < self.max_age;
+            .is_none());
+        assert!(store.load(&refused).unwrap().is_none());
+        for (session_id, _) in store.user_sessions(&user).unwrap() {
+            store.delete(&session_id).unwrap();
+        }
 
-        // delete session if expired
-        if !res {
-            self.store.delete(session);
+        let user = Uuid::new_v4().to_string();
+        let refuse = UserLimit {
+            max_sessions: 3,
+            ..refuse
+        };
+        let created = std::thread::scope(|scope| {
+            let logins = (0..16)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let session = Session::new(&user, None, unix_now());
+                        store
+                            .create(&Uuid::new_v4().to_string(), &session, Some(&refuse))
+                            .unwrap()
+                            .is_some()
+                    })
+                })
+                .collect::<Vec<_>>();
+            logins
+                .into_iter()
+                .map(|login| login.join().unwrap())
+                .filter(|created| *created)
+                .count()
+        });
+        assert_eq!(created, 3);
+        for (session_id, _) in store.user_sessions(&user).unwrap() {
+            store.delete(&session_id).unwrap();
         }
-        res
+    }
+
+    fn manager(store: Arc<dyn SessionStore + Send + Sync>) -> SessionManager {
+        let cookie = SessionCookie::new("session_id", Default::default(), 3600).unwrap();
+        SessionManager::new(cookie, store, None, 3600)
+    }
+
+    #[test]
+    fn binding_adopts_sessions_from_before_binding() {
+        let store = Arc::new(memory::MemoryStore::new());
+        let mut manager = manager(store.clone());
+        manager.binding = Some(SessionBinding::new(None, 24, 64, true, false));
+        let mut session = Session::new("alice", None, unix_now());
+        session.client_recorded = false;
+        store.save("old", &session).unwrap();
+
+        let client = ClientInfo {
+            user_agent: Some("first".to_string()),
+            ..Default::default()
+        };
+        assert!(manager.validate_session("old", &client).unwrap().is_some());
+        let adopted = store.load("old").unwrap().unwrap();
+        assert!(adopted.client_recorded);
+        assert_eq!(adopted.user_agent.as_deref(), Some("first"));
+        assert!(manager.validate_session("old", &client).unwrap().is_some());
+
+        let other = ClientInfo {
+            user_agent: Some("second".to_string()),
+            ..Default::default()
+        };
+        assert!(manager.validate_session("old", &other).unwrap().is_none());
+        assert!(store.load("old").unwrap().is_none());
+    }
+
+    #[test]
+    fn binding_revokes_sessions_without_user_agent() {
+        let store = Arc::new(memory::MemoryStore::new());
+        let mut manager = manager(store.clone());
+        manager.binding = Some(SessionBinding::new(None, 24, 64, true, false));
+        store
+            .save("new", &Session::new("alice", None, unix_now()))
+            .unwrap();
+        let client = ClientInfo {
+            user_agent: Some("curl".to_string()),
+            ..Default::default()
+        };
+        assert!(manager.validate_session("new", &client).unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_cookie_sessions_are_invalid() {
+        let cookie = SessionCookie::new("session_id", Default::default(), 3600).unwrap();
+        let key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE";
+        let sealer = CookieSealer::new(&[key]).unwrap();
+        let now = unix_now();
+        let sealed = |expires_at| {
+            sealer.seal(&CookieSession {
+                id: Uuid::new_v4(),
+                user: "alice".to_string(),
+                issued_at: now - 3600,
+                expires_at,
+            })
+        };
+        let (live, expired) = (sealed(now + 60), sealed(now));
+        let manager = SessionManager::with_cookie(cookie, sealer, None, 3600);
+        let client = ClientInfo::default();
+        let valid = manager.validate_session(&live, &client).unwrap().unwrap();
+        assert_eq!(valid.session.user, "alice");
+        assert!(manager
+            .validate_session(&expired, &client)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rotation_keeps_the_old_id_for_the_grace_window() {
+        let store = Arc::new(memory::MemoryStore::new());
+        let mut manager = manager(store.clone());
+        manager.rotate_grace = 30;
+        let client = ClientInfo::default();
+        let old = manager.create_session("alice", &client).unwrap();
+
+        let new = manager.rotate(&old).unwrap().unwrap();
+        assert_ne!(new, old);
+        let valid = manager.validate_session(&new, &client).unwrap().unwrap();
+        assert_eq!(valid.session.user, "alice");
+        // the old ID is still accepted, but not rotated again or listed
+        let valid = manager.validate_session(&old, &client).unwrap().unwrap();
+        assert!(valid.renewed.is_none());
+        assert!(manager.rotate(&old).unwrap().is_none());
+        let listed = manager.list_sessions().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, new);
+
+        let mut replaced = store.load(&old).unwrap().unwrap();
+        replaced.grace_until = Some(unix_now());
+        store.update(&old, &replaced).unwrap();
+        assert!(manager.validate_session(&old, &client).unwrap().is_none());
+        assert!(store.load(&old).unwrap().is_none());
+        assert!(manager.validate_session(&new, &client).unwrap().is_some());
+    }
+
+    #[test]
+    fn rotation_without_grace_window_retires_the_old_id() {
+        let store = Arc::new(memory::MemoryStore::new());
+        let manager = manager(store.clone());
+        let client = ClientInfo::default();
+        let old = manager.create_session("alice", &client).unwrap();
+        let new = manager.rotate(&old).unwrap().unwrap();
+        assert!(manager.validate_session(&old, &client).unwrap().is_none());
+        assert!(manager.validate_session(&new, &client).unwrap().is_some());
+        // a revoked session is not rotated
+        manager.revoke_session(&new).unwrap();
+        assert!(manager.rotate(&new).unwrap().is_none());
+    }
+
+    #[test]
+    fn sessions_are_rotated_once_due() {
+        let store = Arc::new(memory::MemoryStore::new());
+        let mut manager = manager(store.clone());
+        manager.rotate_interval = Some(600);
+        manager.rotate_grace = 30;
+        let client = ClientInfo::default();
+        let now = unix_now();
+        store
+            .save("fresh", &Session::new("alice", None, now - 60))
+            .unwrap();
+        store
+            .save("due", &Session::new("alice", None, now - 600))
+            .unwrap();
+
+        let valid = manager.validate_session("fresh", &client).unwrap().unwrap();
+        assert!(valid.renewed.is_none());
+        let valid = manager.validate_session("due", &client).unwrap().unwrap();
+        let renewed = valid.renewed.unwrap();
+        assert!(store.load("due").unwrap().unwrap().is_replaced());
+        // the interval starts again for the new ID
+        let valid = manager
+            .validate_session(&renewed, &client)
+            .unwrap()
+            .unwrap();
+        assert!(valid.renewed.is_none());
+    }
+
+    #[test]
+    fn cookie_rotation_revokes_the_old_id_after_the_grace_window() {
+        let cookie = SessionCookie::new("session_id", Default::default(), 3600).unwrap();
+        let key = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE";
+        let revoked = Arc::new(memory::MemoryStore::new());
+        let sealer = CookieSealer::new(&[key]).unwrap();
+        let mut manager = SessionManager::with_cookie(cookie, sealer, Some(revoked.clone()), 3600);
+        manager.rotate_grace = 30;
+        let client = ClientInfo::default();
+
+        let old = manager.create_session("alice", &client).unwrap();
+        let new = manager.rotate(&old).unwrap().unwrap();
+        assert!(manager.validate_session(&old, &client).unwrap().is_some());
+        assert!(manager.validate_session(&new, &client).unwrap().is_some());
+        assert!(manager.rotate(&old).unwrap().is_none());
+
+        // the grace window is over
+        for (key, mut record) in revoked.list().unwrap() {
+            record.created_at = unix_now();
+            revoked.save(&key, &record).unwrap();
+        }
+        assert!(manager.validate_session(&old, &client).unwrap().is_none());
+        assert!(manager.validate_session(&new, &client).unwrap().is_some());
+
+        manager.rotate_grace = 0;
+        let newer = manager.rotate(&new).unwrap().unwrap();
+        assert!(manager.validate_session(&new, &client).unwrap().is_none());
+        assert!(manager.validate_session(&newer, &client).unwrap().is_some());
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&memory::MemoryStore::new());
+        check_limit(&memory::MemoryStore::new());
+    }
+
+    /// Holds records which cannot be read, deleting one fails after it is removed as Redis does
+    struct UnreadableStore(std::sync::Mutex<std::collections::HashSet<String>>);
+
+    impl UnreadableStore {
+        fn corrupted() -> StoreError {
+            serde_json::from_str::<Session>("{").unwrap_err().into()
+        }
+    }
+
+    impl SessionStore for UnreadableStore {
+        fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+            match self.0.lock().unwrap().contains(session_id) {
+                true => Err(Self::corrupted()),
+                false => Ok(None),
+            }
+        }
+        fn save(&self, session_id: &str, _: &Session) -> Result<(), StoreError> {
+            self.0.lock().unwrap().insert(session_id.to_string());
+            Ok(())
+        }
+        fn update(&self, session_id: &str, _: &Session) -> Result<bool, StoreError> {
+            Ok(self.0.lock().unwrap().contains(session_id))
+        }
+        fn create(
+            &self,
+            _: &str,
+            _: &Session,
+            _: Option<&UserLimit>,
+        ) -> Result<Option<Vec<String>>, StoreError> {
+            Err(StoreError::Failed("Not supported by the test store".into()))
+        }
+        fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+            match self.0.lock().unwrap().remove(session_id) {
+                true => Err(Self::corrupted()),
+                false => Ok(None),
+            }
+        }
+        fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn unreadable_sessions_are_invalid() {
+        let store = Arc::new(UnreadableStore(Default::default()));
+        let manager = manager(store.clone());
+        let session = Session::new("alice", None, unix_now());
+        store.save("first", &session).unwrap();
+        store.save("second", &session).unwrap();
+
+        let client = ClientInfo::default();
+        assert!(manager
+            .validate_session("first", &client)
+            .unwrap()
+            .is_none());
+        assert!(store.load("first").unwrap().is_none());
+        assert!(manager.rotate("second").unwrap().is_none());
+        assert!(store.load("second").unwrap().is_none());
+
+        store.save("third", &session).unwrap();
+        assert!(manager.revoke_session("third").unwrap());
+        assert!(!manager.revoke_session("third").unwrap());
     }
 }
@@ -0,0 +1,338 @@
+use super::{
+    blocking, limit::UserLimit, paged, unix_now, Session, SessionIter, SessionStore, StoreError,
+    PAGE_SIZE,
+};
+use hyper_rustls::ConfigBuilderExt;
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::postgres::{self, error::SqlState, Row, Transaction};
+use r2d2_postgres::PostgresConnectionManager;
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{debug, error};
+
+/// How long to wait for a connection before the store is considered unavailable
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Each migration brings the schema to the next version, the applied version is kept in
+/// `watchdawg_schema`
+const MIGRATIONS: &[&str] = &["CREATE TABLE watchdawg_sessions (
+        id TEXT PRIMARY KEY,
+        user_name TEXT NOT NULL,
+        expires_at BIGINT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX watchdawg_sessions_user ON watchdawg_sessions (user_name);
+    CREATE INDEX watchdawg_sessions_expires ON watchdawg_sessions (expires_at);"];
+
+type Manager = PostgresConnectionManager<MakeRustlsConnect>;
+
+/// Each session is a row holding the session as JSON, which expires `max_age` seconds after it was
+/// created. Expired rows are ignored and deleted periodically.
+pub struct PostgresStore {
+    pool: Pool<Manager>,
+    max_age: u64,
+}
+
+impl PostgresStore {
+    /// Connect with the connection string `conn` and migrate the schema. TLS is used as its
+    /// `sslmode` says, with the server certificate verified against the system roots.
+    pub fn new(conn: &str, max_age: u64) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let tls = rustls::ClientConfig::builder()
+            .with_native_roots()?
+            .with_no_client_auth();
+        let manager = PostgresConnectionManager::new(conn.parse()?, MakeRustlsConnect::new(tls));
+        let pool = blocking(|| {
+            Pool::builder()
+                .connection_timeout(CONNECTION_TIMEOUT)
+                .build(manager)
+        })?;
+        let store = Self { pool, max_age };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&self) -> Result<(), StoreError> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            // concurrent instances starting at the same time migrate one after another
+            tx.batch_execute(
+                "SET LOCAL client_min_messages = warning;
+                CREATE TABLE IF NOT EXISTS watchdawg_schema (version INTEGER NOT NULL);
+                LOCK TABLE watchdawg_schema IN EXCLUSIVE MODE;",
+            )?;
+            let version = schema_version(&mut tx)?;
+            for (version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                debug!(
+                    "Migrating PostgreSQL session schema to version {}",
+                    version + 1
+                );
+                tx.batch_execute(migration)?;
+            }
+            tx.execute("DELETE FROM watchdawg_schema", &[])?;
+            tx.execute(
+                "INSERT INTO watchdawg_schema (version) VALUES ($1)",
+                &[&(MIGRATIONS.len() as i32)],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Run `f` with a pooled connection, the synchronous client blocks on a runtime of its own so
+    /// the worker thread is handed over to other tasks meanwhile
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut PooledConnection<Manager>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        blocking(|| f(&mut self.pool.get()?))
+    }
+
+    /// Delete the expired sessions, return the number of sessions deleted
+    pub fn delete_expired(&self) -> Result<u64, StoreError> {
+        self.with_conn(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM watchdawg_sessions WHERE expires_at <= $1",
+                &[&(unix_now() as i64)],
+            )?)
+        })
+    }
+
+    /// Delete the expired sessions every `interval` in a background thread, the thread stops once
+    /// the store is dropped
+    pub fn spawn_cleanup_task(self: &Arc<Self>, interval: Duration) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            match store.delete_expired() {
+                Ok(deleted) => debug!("Deleted {} expired sessions", deleted),
+                Err(err) => error!("Failed to delete expired sessions: {}", err),
+            }
+        });
+    }
+}
+
+fn schema_version(tx: &mut Transaction) -> Result<usize, postgres::Error> {
+    let row = tx.query_opt("SELECT version FROM watchdawg_schema", &[])?;
+    Ok(row.map(|row| row.get::<_, i32>(0)).unwrap_or(0) as usize)
+}
+
+/// Read the `(id, data)` rows, skipping records which cannot be read rather than failing the whole
+/// listing
+fn sessions(rows: Vec<Row>) -> Vec<(String, Session)> {
+    rows.into_iter()
+        .filter_map(|row| {
+            let session = serde_json::from_str(row.get::<_, &str>(1)).ok()?;
+            Some((row.get(0), session))
+        })
+        .collect()
+}
+
+impl SessionStore for PostgresStore {
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT data FROM watchdawg_sessions WHERE id = $1 AND expires_at > $2",
+                &[&session_id, &(unix_now() as i64)],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+            None => Ok(None),
+        }
+    }
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        let expires_at = session.created_at.saturating_add(self.max_age) as i64;
+        let data = serde_json::to_string(session)?;
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO watchdawg_sessions (id, user_name, expires_at, data)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (id) DO UPDATE SET
+                    user_name = excluded.user_name,
+                    expires_at = excluded.expires_at,
+                    data = excluded.data",
+                &[&session_id, &session.user, &expires_at, &data],
+            )?;
+            Ok(())
+        })
+    }
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        let data = serde_json::to_string(session)?;
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "UPDATE watchdawg_sessions SET user_name = $2, data = $3
+                WHERE id = $1 AND expires_at > $4",
+                &[&session_id, &session.user, &data, &(unix_now() as i64)],
+            )?)
+        })?;
+        Ok(updated > 0)
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        let expires_at = session.created_at.saturating_add(self.max_age) as i64;
+        let data = serde_json::to_string(session)?;
+        self.with_conn(|conn| {
+            let now = unix_now();
+            let mut tx = conn.transaction()?;
+            // logins of the same user wait for each other until the transaction ends
+            tx.execute(
+                "SELECT pg_advisory_xact_lock(hashtext($1))",
+                &[&session.user],
+            )?;
+            let evicted = match limit {
+                Some(limit) => {
+                    let rows = tx.query(
+                        "SELECT id, data FROM watchdawg_sessions
+                        WHERE user_name = $1 AND expires_at > $2",
+                        &[&session.user, &(now as i64)],
+                    )?;
+                    match limit.evictions(sessions(rows), now) {
+                        Some(evicted) => evicted,
+                        None => return Ok(None),
+                    }
+                }
+                None => Vec::new(),
+            };
+            for session_id in &evicted {
+                tx.execute(
+                    "DELETE FROM watchdawg_sessions WHERE id = $1",
+                    &[session_id],
+                )?;
+            }
+            tx.execute(
+                "INSERT INTO watchdawg_sessions (id, user_name, expires_at, data)
+                VALUES ($1, $2, $3, $4)",
+                &[&session_id, &session.user, &expires_at, &data],
+            )?;
+            tx.commit()?;
+            Ok(Some(evicted))
+        })
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "DELETE FROM watchdawg_sessions WHERE id = $1 RETURNING data, expires_at",
+                &[&session_id],
+            )?)
+        })?;
+        match row {
+            Some(row) if row.get::<_, i64>(1) as u64 > unix_now() => {
+                Ok(Some(serde_json::from_str(row.get(0))?))
+            }
+            _ => Ok(None),
+        }
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT id, data FROM watchdawg_sessions WHERE expires_at > $1",
+                &[&(unix_now() as i64)],
+            )?)
+        })?;
+        Ok(sessions(rows))
+    }
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        Ok(paged(|after| {
+            let rows = self.with_conn(|conn| {
+                Ok(conn.query(
+                    "SELECT id, data FROM watchdawg_sessions WHERE expires_at > $1 AND id > $2
+                    ORDER BY id LIMIT $3",
+                    &[&(unix_now() as i64), &after, &(PAGE_SIZE as i64)],
+                )?)
+            })?;
+            Ok(rows
+                .into_iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect())
+        }))
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT id, data FROM watchdawg_sessions WHERE user_name = $1 AND expires_at > $2",
+                &[&user, &(unix_now() as i64)],
+            )?)
+        })?;
+        Ok(sessions(rows))
+    }
+}
+
+impl From<postgres::Error> for StoreError {
+    /// A lost connection or a server refusing connections is unavailable, an error reported by
+    /// the server about the query itself is not
+    fn from(err: postgres::Error) -> Self {
+        let unavailable = err.is_closed()
+            || err.code().is_none_or(|code| {
+                code.code().starts_with("08")
+                    || [
+                        SqlState::ADMIN_SHUTDOWN,
+                        SqlState::CRASH_SHUTDOWN,
+                        SqlState::CANNOT_CONNECT_NOW,
+                        SqlState::TOO_MANY_CONNECTIONS,
+                    ]
+                    .contains(code)
+            });
+        match unavailable {
+            true => Self::Unavailable(err.into()),
+            false => Self::Failed(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::{check_limit, check_store};
+
+    fn store() -> PostgresStore {
+        let conn =
+            std::env::var("WATCHDAWG_TEST_POSTGRES").expect("WATCHDAWG_TEST_POSTGRES is not set");
+        PostgresStore::new(&conn, 3600).unwrap()
+    }
+
+    #[test]
+    fn unreachable() {
+        let Err(err) = postgres::Client::connect("host=127.0.0.1 port=1", postgres::NoTls) else {
+            panic!("connected to a closed port");
+        };
+        assert!(StoreError::from(err).is_unavailable());
+    }
+
+    #[test]
+    #[ignore]
+    fn failed_queries() {
+        let store = store();
+        let err = store
+            .with_conn(|conn| Ok(conn.execute("SELECT * FROM missing", &[])?))
+            .unwrap_err();
+        assert!(!err.is_unavailable());
+    }
+
+    #[test]
+    #[ignore]
+    fn contract() {
+        let store = store();
+        check_store(&store);
+        check_limit(&store);
+        store.delete_expired().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn on_server_runtime() {
+        let store = store();
+        check_store(&store);
+        // closing the connections stops their runtimes, which a worker thread must not block on
+        tokio::task::block_in_place(|| drop(store));
+    }
+}
@@ -1,82 +1,878 @@
-use super::SessionStore;
-use r2d2::{ManageConnection, Pool, PooledConnection};
+use super::{
+    blocking, cache::CacheEvent, create_limited, limit::UserLimit, unix_now, Session, SessionIter,
+    SessionStore, StoreError,
+};
+use concat_string::concat_string;
+use r2d2::{ManageConnection, NopErrorHandler, Pool, PooledConnection};
 use redis::{
-    cmd, Commands, Connection, ConnectionInfo, ConnectionLike, IntoConnectionInfo, RedisError,
+    cluster::{ClusterClient, ClusterConnection},
+    cmd,
+    sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType},
+    Cmd, Commands, Connection, ConnectionAddr, ConnectionInfo, ConnectionLike, ErrorKind,
+    IntoConnectionInfo, RedisError, RedisResult, Script, Value,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use tracing::error;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const KEY_PREFIX: &str = "session:";
+const USER_KEY_PREFIX: &str = "session-user:";
+/// Held while a login of the user is checked against the session limit
+const LOGIN_LOCK_PREFIX: &str = "session-lock:";
+/// How long a login lock is held at most, in case its holder dies
+const LOGIN_LOCK_TTL: Duration = Duration::from_secs(10);
+/// How long to wait for the login lock of a user
+const LOGIN_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Save a session and add it to the index of its user, which is kept as long as the newest session
+const SAVE_SCRIPT: &str = r#"
+redis.call("SET", KEYS[1], ARGV[1], "EX", ARGV[2])
+redis.call("SADD", KEYS[2], ARGV[3])
+if redis.call("TTL", KEYS[2]) < tonumber(ARGV[2]) then
+    redis.call("EXPIRE", KEYS[2], ARGV[2])
+end
+"#;
+/// Release a lock only if it is still held by the token
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+/// Matches the keys of sessions from before sessions belonged to a user, which were kept under the
+/// bare session ID
+const LEGACY_KEY_PATTERN: &str = "????????-????-????-????-????????????";
+/// How long to wait for a connection before the store is considered unavailable
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
+/// Deleted session IDs are published here for the caches of all instances
+const INVALIDATION_CHANNEL: &str = "watchdawg:session-invalidated";
+/// How long to wait before subscribing again after the subscription is lost
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often every instance publishes a heartbeat on the invalidation channel
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// A subscription which has not received anything for this long, not even the heartbeat of its
+/// own instance, is considered dead and made again
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(15);
+/// Published as the heartbeat, which is never a session ID
+const HEARTBEAT: &str = "";
+
+/// Where the Redis server is
+#[derive(Clone)]
+pub enum RedisTarget {
+    /// A single server
+    Single(String),
+    /// The master named `master` monitored by `sentinels`, `node` is the connection information
+    /// used for the master, of which only the database and credentials are used
+    Sentinel {
+        sentinels: Vec<String>,
+        master: String,
+        node: Option<String>,
+    },
+    /// A cluster discovered from the seed nodes
+    Cluster(Vec<String>),
+}
 
+/// Each session is kept as a JSON string which expires `max_age` seconds after it was created, and
+/// the IDs of the sessions of each user are kept in a set which expires with the newest session
 pub struct RedisStore {
     pool: Pool<RedisConnPool>,
+    /// Makes the dedicated connections for pub/sub, which cannot be pooled, and those to each node
+    /// of a cluster
+    pubsub: Arc<RedisConnPool>,
+    max_age: u64,
+    save_script: Script,
+    unlock_script: Script,
 }
 
 impl RedisStore {
-    pub fn new(conn_str: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let manager = RedisConnPool::new(conn_str)?;
-        let pool = Pool::builder().build(manager)?;
-        Ok(Self { pool })
+    pub fn new(
+        target: RedisTarget,
+        max_age: u64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        // a message published to any node of a cluster reaches every node
+        let pubsub = match &target {
+            RedisTarget::Cluster(nodes) => RedisTarget::Single(
+                nodes
+                    .first()
+                    .ok_or(RedisError::from((
+                        redis::ErrorKind::InvalidClientConfig,
+                        "No cluster node",
+                    )))?
+                    .clone(),
+            ),
+            target => target.clone(),
+        };
+        let pubsub = Arc::new(RedisConnPool::new(pubsub)?);
+        let manager = RedisConnPool::new(target)?;
+        // connections are made in the background, so watchdawg starts even if Redis is down and
+        // the outage policy applies until it is reachable
+        // failures are reported by the health of the store instead of on every retry
+        let pool = Pool::builder()
+            .connection_timeout(CONNECTION_TIMEOUT)
+            .error_handler(Box::new(NopErrorHandler))
+            .build_unchecked(manager);
+        Ok(Self {
+            pool,
+            pubsub,
+            max_age,
+            save_script: Script::new(SAVE_SCRIPT),
+            unlock_script: Script::new(UNLOCK_SCRIPT),
+        })
+    }
+
+    /// Tell the caches of all instances to drop the session
+    pub fn publish_invalidation(&self, session_id: &str) -> Result<(), StoreError> {
+        self.with_conn(|conn| {
+            conn.publish::<&str, &str, ()>(INVALIDATION_CHANNEL, session_id)?;
+            Ok(())
+        })
+    }
+
+    /// Listen to invalidations in a background thread, which subscribes again whenever the
+    /// subscription is lost, until `handler` returns false. A second thread publishes heartbeats
+    /// meanwhile, so a subscription on a connection which died silently is noticed.
+    pub fn spawn_invalidation_listener(
+        &self,
+        handler: impl Fn(CacheEvent) -> bool + Send + 'static,
+    ) {
+        let listening = Arc::new(AtomicBool::new(true));
+        let pubsub = self.pubsub.clone();
+        std::thread::spawn({
+            let listening = listening.clone();
+            move || {
+                listen(&pubsub, handler);
+                listening.store(false, Ordering::Relaxed);
+            }
+        });
+        let pool = self.pool.clone();
+        std::thread::spawn(move || {
+            while listening.load(Ordering::Relaxed) {
+                let published = pool.get().map_err(StoreError::from).and_then(|mut conn| {
+                    Ok(conn.publish::<&str, &str, ()>(INVALIDATION_CHANNEL, HEARTBEAT)?)
+                });
+                if let Err(err) = published {
+                    debug!("Failed to publish invalidation heartbeat: {}", err);
+                }
+                std::thread::sleep(HEARTBEAT_INTERVAL);
+            }
+        });
+    }
+
+    fn get_conn(&self) -> Result<PooledConnection<RedisConnPool>, StoreError> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Run `f` with a pooled connection, the worker thread is handed over to other tasks while
+    /// waiting for a connection or for Redis
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut PooledConnection<RedisConnPool>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        blocking(|| f(&mut self.get_conn()?))
+    }
+
+    /// Hold the login lock of the user while running `f`, so the logins of a user are checked
+    /// against the session limit one after another across all instances
+    fn with_login_lock<T>(
+        &self,
+        user: &str,
+        f: impl FnOnce() -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        // waiting for the lock holds the thread, like the calls to Redis
+        blocking(|| {
+            let lock = concat_string!(LOGIN_LOCK_PREFIX, user);
+            let token = Uuid::new_v4().to_string();
+            // the connection goes back to the pool before `f` takes one
+            let mut conn = self.get_conn()?;
+            let deadline = Instant::now() + LOGIN_LOCK_TIMEOUT;
+            loop {
+                let acquired = cmd("SET")
+                    .arg(&lock)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(LOGIN_LOCK_TTL.as_millis() as u64)
+                    .query::<Option<String>>(&mut *conn)?;
+                if acquired.is_some() {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    return Err(StoreError::Unavailable(
+                        format!("Timed out waiting for the login lock of {}", user).into(),
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            drop(conn);
+
+            let res = f();
+            let unlocked = self.get_conn().and_then(|mut conn| {
+                Ok(self
+                    .unlock_script
+                    .key(&lock)
+                    .arg(&token)
+                    .invoke::<()>(&mut *conn)?)
+            });
+            // the lock expires by itself anyway
+            if let Err(err) = unlocked {
+                warn!("Failed to release the login lock of {}: {}", user, err);
+            }
+            res
+        })
+    }
+
+    /// Seconds until the session expires
+    fn ttl(&self, session: &Session) -> u64 {
+        session
+            .created_at
+            .saturating_add(self.max_age)
+            .saturating_sub(unix_now())
+            .max(1)
     }
 
-    fn get_conn(&self) -> Option<PooledConnection<RedisConnPool>> {
-        match self.pool.get() {
-            Ok(conn) => Some(conn),
-            Err(err) => {
-                error!("Failed to set redis value: {}", err);
-                None
+    /// Delete the sessions left by watchdawg versions from before sessions belonged to a user,
+    /// which hold only the creation time and never expire. Return the number of keys deleted.
+    pub fn purge_legacy(&self) -> Result<usize, StoreError> {
+        self.with_conn(|conn| {
+            let mut purged = 0;
+            for key in self.keys(conn, LEGACY_KEY_PATTERN)? {
+                if Uuid::parse_str(&key).is_err() {
+                    continue;
+                }
+                // a key holding anything but a timestamp belongs to someone else
+                let Ok(Some(_)) = conn.get::<&str, Option<u64>>(&key) else {
+                    continue;
+                };
+                purged += conn.del::<&str, usize>(&key)?;
+            }
+            Ok(purged)
+        })
+    }
+
+    /// All keys of sessions
+    fn session_keys(&self, conn: &mut RedisConn) -> RedisResult<Vec<String>> {
+        self.keys(conn, &concat_string!(KEY_PREFIX, "*"))
+    }
+
+    /// All keys matching `pattern`. `SCAN` only covers the server it is sent to, so in a cluster
+    /// every primary is scanned over a connection of its own.
+    fn keys(&self, conn: &mut RedisConn, pattern: &str) -> RedisResult<Vec<String>> {
+        match conn {
+            RedisConn::Single(conn) => Ok(conn.scan_match::<&str, String>(pattern)?.collect()),
+            RedisConn::Cluster(conn) => {
+                let nodes = cmd("CLUSTER").arg("NODES").query::<String>(conn)?;
+                let mut keys = Vec::new();
+                for (host, port) in primaries(&nodes) {
+                    let mut node = self.pubsub.connect_node(host, port)?;
+                    keys.extend(node.scan_match::<&str, String>(pattern)?);
+                }
+                // a key being migrated between primaries may be found on both
+                keys.sort_unstable();
+                keys.dedup();
+                Ok(keys)
             }
         }
     }
 }
 
 impl SessionStore for RedisStore {
-    fn save(&self, session_id: &str, timestamp: u64) -> Option<()> {
-        let mut conn = self.get_conn()?;
-        if let Err(err) = conn.set::<&str, u64, ()>(session_id, timestamp) {
-            error!("Failed to set redis value: {}", err);
-            return None;
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        self.with_conn(|conn| {
+            let value = serde_json::to_string(session)?;
+            let ttl = self.ttl(session);
+            let user_key = user_key(&session.user);
+            match &mut **conn {
+                RedisConn::Single(conn) => self
+                    .save_script
+                    .key(key(session_id))
+                    .key(&user_key)
+                    .arg(value)
+                    .arg(ttl)
+                    .arg(session_id)
+                    .invoke::<()>(conn)?,
+                // the keys are in different slots, which a script cannot span, so the session is
+                // indexed first: an index entry without a session is dropped by `user_sessions`, while
+                // a session missing from the index would escape revocation
+                RedisConn::Cluster(conn) => {
+                    conn.sadd::<&str, &str, ()>(&user_key, session_id)?;
+                    if conn.ttl::<&str, i64>(&user_key)? < ttl as i64 {
+                        conn.expire::<&str, ()>(&user_key, ttl as i64)?;
+                    }
+                    conn.set_ex::<String, String, ()>(key(session_id), value, ttl)?;
+                }
+            }
+            Ok(())
+        })
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        match limit {
+            Some(_) => self.with_login_lock(&session.user, || {
+                create_limited(self, session_id, session, limit)
+            }),
+            None => create_limited(self, session_id, session, None),
         }
-        Some(())
     }
-    fn load(&self, session_id: &str) -> Option<u64> {
-        let mut conn = self.get_conn()?;
-        conn.get(session_id).ok()
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        self.with_conn(|conn| {
+            let updated = cmd("SET")
+                .arg(key(session_id))
+                .arg(serde_json::to_string(session)?)
+                .arg("XX")
+                .arg("EX")
+                .arg(self.ttl(session))
+                .query::<Option<String>>(&mut **conn)?;
+            Ok(updated.is_some())
+        })
+    }
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        self.with_conn(
+            |conn| match conn.get::<String, Option<String>>(key(session_id))? {
+                Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+                None => Ok(None),
+            },
+        )
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        self.with_conn(|conn| {
+            let Some(value) = conn.get_del::<String, Option<String>>(key(session_id))? else {
+                return Ok(None);
+            };
+            let session = serde_json::from_str::<Session>(&value)?;
+            conn.srem::<String, &str, ()>(user_key(&session.user), session_id)?;
+            Ok(Some(session))
+        })
     }
-    fn delete(&self, session_id: &str) -> Option<u64> {
-        let mut conn = self.get_conn()?;
-        match conn.get_del(session_id) {
-            Ok(res) => Some(res),
-            Err(err) => {
-                error!("Failed to delete redis value: {}", err);
-                None
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        self.with_conn(|conn| {
+            let keys = self.session_keys(conn)?;
+            let mut sessions = Vec::with_capacity(keys.len());
+            for key in keys {
+                // the session may have expired since the scan
+                let Some(value) = conn.get::<&str, Option<String>>(&key)? else {
+                    continue;
+                };
+                if let (Some(session_id), Ok(session)) = (
+                    key.strip_prefix(KEY_PREFIX),
+                    serde_json::from_str::<Session>(&value),
+                ) {
+                    sessions.push((session_id.to_string(), session));
+                }
             }
+            Ok(sessions)
+        })
+    }
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        // only the keys are collected, the sessions are fetched as the iteration goes
+        let mut conn = blocking(|| self.get_conn())?;
+        let keys = blocking(|| self.session_keys(&mut conn))?;
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            let session_id = key.strip_prefix(KEY_PREFIX)?.to_string();
+            match blocking(|| conn.get::<&str, Option<String>>(&key)) {
+                Ok(value) => {
+                    // the session may have expired since the scan
+                    let session = serde_json::from_str::<Session>(&value?);
+                    Some(
+                        session
+                            .map(|session| (session_id, session))
+                            .map_err(Into::into),
+                    )
+                }
+                Err(err) => Some(Err(err.into())),
+            }
+        })))
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        self.with_conn(|conn| {
+            let user_key = user_key(user);
+            let session_ids = conn.smembers::<&str, Vec<String>>(&user_key)?;
+            let mut sessions = Vec::with_capacity(session_ids.len());
+            for session_id in session_ids {
+                let value = conn.get::<String, Option<String>>(key(&session_id))?;
+                match value.and_then(|value| serde_json::from_str(&value).ok()) {
+                    Some(session) => sessions.push((session_id, session)),
+                    // the session has expired, remove it from the index
+                    None => conn.srem::<&str, &str, ()>(&user_key, &session_id)?,
+                }
+            }
+            Ok(sessions)
+        })
+    }
+}
+
+/// Pass the invalidations to `handler` until it returns false, subscribing again whenever the
+/// subscription is lost
+fn listen(pubsub: &RedisConnPool, handler: impl Fn(CacheEvent) -> bool) {
+    loop {
+        match pubsub.connect_single() {
+            Ok(mut conn) => {
+                let mut conn = conn.as_pubsub();
+                let subscribed = conn
+                    .subscribe(INVALIDATION_CHANNEL)
+                    .and_then(|()| conn.set_read_timeout(Some(SUBSCRIPTION_TIMEOUT)));
+                match subscribed {
+                    Ok(()) if !handler(CacheEvent::Subscribed) => return,
+                    // a read that timed out leaves the connection unusable, so it is dropped
+                    Ok(()) => loop {
+                        let message = match conn.get_message() {
+                            Ok(message) => message,
+                            Err(err) => {
+                                warn!("Invalidation subscription lost: {}", err);
+                                break;
+                            }
+                        };
+                        let Ok(session_id) = message.get_payload::<String>() else {
+                            continue;
+                        };
+                        if session_id == HEARTBEAT {
+                            continue;
+                        }
+                        if !handler(CacheEvent::Invalidated(&session_id)) {
+                            return;
+                        }
+                    },
+                    Err(err) => debug!("Failed to subscribe to invalidations: {}", err),
+                }
+            }
+            Err(err) => debug!("Failed to subscribe to invalidations: {}", err),
+        }
+        if !handler(CacheEvent::Unsubscribed) {
+            return;
+        }
+        std::thread::sleep(RESUBSCRIBE_INTERVAL);
+    }
+}
+
+impl From<RedisError> for StoreError {
+    fn from(err: RedisError) -> Self {
+        // a server that is loading, failing over or lost is an outage, an error answered by a
+        // healthy server is not
+        let unavailable = err.is_io_error()
+            || err.is_connection_dropped()
+            || err.is_connection_refusal()
+            || err.is_timeout()
+            || matches!(
+                err.kind(),
+                ErrorKind::BusyLoadingError
+                    | ErrorKind::TryAgain
+                    | ErrorKind::ClusterDown
+                    | ErrorKind::MasterDown
+                    | ErrorKind::ReadOnly
+                    | ErrorKind::MasterNameNotFoundBySentinel
+                    | ErrorKind::NoValidReplicasFoundBySentinel
+                    | ErrorKind::ClusterConnectionNotFound
+            );
+        match unavailable {
+            true => Self::Unavailable(err.into()),
+            false => Self::Failed(err.into()),
         }
     }
 }
 
-struct RedisConnPool {
-    conn_info: ConnectionInfo,
+/// The addresses of the primaries serving slots, from the output of `CLUSTER NODES`
+fn primaries(nodes: &str) -> Vec<(&str, u16)> {
+    nodes
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let flags = fields.get(2)?.split(',').collect::<Vec<_>>();
+            // fields after the 8th are the slots
+            if !flags.contains(&"master") || flags.contains(&"fail") || fields.len() <= 8 {
+                return None;
+            }
+            // `ip:port@cport` optionally followed by `,hostname`
+            let addr = fields[1].split(['@', ',']).next()?;
+            let (host, port) = addr.rsplit_once(':')?;
+            Some((host, port.parse().ok()?))
+        })
+        .collect()
+}
+
+fn key(session_id: &str) -> String {
+    concat_string!(KEY_PREFIX, session_id)
+}
+
+fn user_key(user: &str) -> String {
+    concat_string!(USER_KEY_PREFIX, user)
+}
+
+enum RedisConnPool {
+    Single(ConnectionInfo),
+    Sentinel(Mutex<SentinelClient>),
+    Cluster(ClusterClient),
 }
 
 impl RedisConnPool {
-    fn new(conn_info: impl IntoConnectionInfo) -> Result<Self, RedisError> {
-        Ok(Self {
-            conn_info: conn_info.into_connection_info()?,
-        })
+    fn new(target: RedisTarget) -> Result<Self, RedisError> {
+        let pool = match target {
+            RedisTarget::Single(conn_info) => Self::Single(conn_info.into_connection_info()?),
+            RedisTarget::Sentinel {
+                sentinels,
+                master,
+                node,
+            } => {
+                let node = node
+                    .map(|node| node.into_connection_info())
+                    .transpose()?
+                    .map(|node| SentinelNodeConnectionInfo {
+                        tls_mode: None,
+                        redis_connection_info: Some(node.redis),
+                    });
+                Self::Sentinel(Mutex::new(SentinelClient::build(
+                    sentinels,
+                    master,
+                    node,
+                    SentinelServerType::Master,
+                )?))
+            }
+            RedisTarget::Cluster(nodes) => Self::Cluster(ClusterClient::new(nodes)?),
+        };
+        Ok(pool)
+    }
+}
+
+impl RedisConnPool {
+    /// Connect to the node of a cluster at `host:port`, with the credentials and TLS settings of
+    /// the seed node
+    fn connect_node(&self, host: &str, port: u16) -> RedisResult<Connection> {
+        let Self::Single(conn_info) = self else {
+            return Err(RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "Not a cluster seed node",
+            )));
+        };
+        let mut conn_info = conn_info.clone();
+        match &mut conn_info.addr {
+            ConnectionAddr::Tcp(node_host, node_port)
+            | ConnectionAddr::TcpTls {
+                host: node_host,
+                port: node_port,
+                ..
+            } => {
+                *node_host = host.to_string();
+                *node_port = port;
+            }
+            ConnectionAddr::Unix(_) => {
+                return Err(RedisError::from((
+                    redis::ErrorKind::InvalidClientConfig,
+                    "Cluster nodes cannot be reached over a Unix socket",
+                )))
+            }
+        }
+        redis::Client::open(conn_info)?.get_connection()
+    }
+
+    /// Connect to a single server, the master if behind sentinels
+    fn connect_single(&self) -> RedisResult<Connection> {
+        match self {
+            Self::Single(conn_info) => redis::Client::open(conn_info.clone())?.get_connection(),
+            Self::Sentinel(client) => client.lock().unwrap().get_connection(),
+            Self::Cluster(_) => Err(RedisError::from((
+                redis::ErrorKind::InvalidClientConfig,
+                "Not a single server",
+            ))),
+        }
     }
 }
 
 impl ManageConnection for RedisConnPool {
-    type Connection = Connection;
+    type Connection = RedisConn;
     type Error = RedisError;
 
     fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        redis::Client::open(self.conn_info.clone())?.get_connection()
+        match self {
+            // the sentinels are asked for the current master on every new connection
+            Self::Single(_) | Self::Sentinel(_) => Ok(RedisConn::Single(self.connect_single()?)),
+            Self::Cluster(client) => Ok(RedisConn::Cluster(client.get_connection()?)),
+        }
     }
 
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        cmd("PING").query(conn)
+        match self {
+            // after a failover the old master is demoted, drop connections to it
+            Self::Sentinel(_) => {
+                let role: Vec<Value> = cmd("ROLE").query(conn)?;
+                match role.first() {
+                    Some(Value::BulkString(role)) if role == b"master" => Ok(()),
+                    _ => Err(RedisError::from((
+                        redis::ErrorKind::ReadOnly,
+                        "Connected to a demoted master",
+                    ))),
+                }
+            }
+            _ => cmd("PING").query(conn),
+        }
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
         !conn.is_open()
     }
 }
+
+/// A connection to a single server or to a cluster
+enum RedisConn {
+    Single(Connection),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        match self {
+            Self::Single(conn) => conn.req_packed_command(cmd),
+            Self::Cluster(conn) => conn.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        match self {
+            Self::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            Self::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        match self {
+            Self::Single(conn) => conn.req_command(cmd),
+            Self::Cluster(conn) => conn.req_command(cmd),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(conn) => conn.get_db(),
+            Self::Cluster(conn) => conn.get_db(),
+        }
+    }
+
+    fn supports_pipelining(&self) -> bool {
+        match self {
+            Self::Single(conn) => conn.supports_pipelining(),
+            Self::Cluster(conn) => conn.supports_pipelining(),
+        }
+    }
+
+    fn check_connection(&mut self) -> bool {
+        match self {
+            Self::Single(conn) => conn.check_connection(),
+            Self::Cluster(conn) => conn.check_connection(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Self::Single(conn) => conn.is_open(),
+            Self::Cluster(conn) => conn.is_open(),
+        }
+    }
+}
+
+/// These tests need Redis servers, so they are ignored unless asked for with
+/// `cargo test -- --ignored` and the servers given in the environment:
+///
+/// - `WATCHDAWG_TEST_REDIS`: a standalone server, e.g. `redis://127.0.0.1:6379/15`
+/// - `WATCHDAWG_TEST_REDIS_SENTINELS` and `WATCHDAWG_TEST_REDIS_SENTINEL_MASTER`: comma separated
+///   sentinels and the name of the master they monitor, which is failed over by the test
+/// - `WATCHDAWG_TEST_REDIS_CLUSTER`: comma separated nodes of a cluster
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::{check_limit, check_store};
+    use std::time::Instant;
+
+    fn env(name: &str) -> String {
+        std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name))
+    }
+
+    fn env_list(name: &str) -> Vec<String> {
+        env(name).split(',').map(str::to_string).collect()
+    }
+
+    #[test]
+    #[ignore]
+    fn standalone() {
+        let conn = env("WATCHDAWG_TEST_REDIS");
+        let store = RedisStore::new(RedisTarget::Single(conn.clone()), 3600).unwrap();
+        check_store(&store);
+        check_limit(&store);
+
+        let session_id = Uuid::new_v4().to_string();
+        store
+            .save(&session_id, &Session::new("alice", None, unix_now()))
+            .unwrap();
+        let ttl = store.get_conn().unwrap().ttl::<_, i64>(key(&session_id));
+        assert!((3590..=3600).contains(&ttl.unwrap()));
+        store.delete(&session_id).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn purge_legacy() {
+        let conn = env("WATCHDAWG_TEST_REDIS");
+        let store = RedisStore::new(RedisTarget::Single(conn), 3600).unwrap();
+        let (legacy, foreign) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        let mut conn = store.get_conn().unwrap();
+        conn.set::<_, _, ()>(&legacy, unix_now()).unwrap();
+        conn.set::<_, _, ()>(&foreign, "not a timestamp").unwrap();
+
+        assert!(store.purge_legacy().unwrap() >= 1);
+        assert!(!conn.exists::<_, bool>(&legacy).unwrap());
+        assert!(conn.exists::<_, bool>(&foreign).unwrap());
+        conn.del::<_, ()>(&foreign).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn cached() {
+        use crate::session::cache::CachedStore;
+
+        let conn = env("WATCHDAWG_TEST_REDIS");
+        let store = || Arc::new(RedisStore::new(RedisTarget::Single(conn.clone()), 3600).unwrap());
+        let (first, second) = (
+            CachedStore::new(store(), Duration::from_secs(60), 100),
+            CachedStore::new(store(), Duration::from_secs(60), 100),
+        );
+        // wait for both to subscribe
+        std::thread::sleep(Duration::from_millis(500));
+        check_store(&*first);
+
+        let session_id = Uuid::new_v4().to_string();
+        first
+            .save(&session_id, &Session::new("alice", None, unix_now()))
+            .unwrap();
+        assert!(second.load(&session_id).unwrap().is_some());
+        first.delete(&session_id).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while second.load(&session_id).unwrap().is_some() {
+            assert!(Instant::now() < deadline, "the invalidation never arrived");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn sentinel_failover() {
+        let sentinels = env_list("WATCHDAWG_TEST_REDIS_SENTINELS");
+        let master = env("WATCHDAWG_TEST_REDIS_SENTINEL_MASTER");
+        let target = RedisTarget::Sentinel {
+            sentinels: sentinels.clone(),
+            master: master.clone(),
+            node: None,
+        };
+        let store = RedisStore::new(target, 3600).unwrap();
+        check_store(&store);
+        let before = Uuid::new_v4().to_string();
+        store
+            .save(&before, &Session::new("alice", None, unix_now()))
+            .unwrap();
+        // let the replicas catch up before the master is demoted
+        std::thread::sleep(Duration::from_secs(1));
+
+        let mut sentinel = redis::Client::open(sentinels[0].as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        cmd("SENTINEL")
+            .arg("FAILOVER")
+            .arg(&master)
+            .query::<()>(&mut sentinel)
+            .unwrap();
+
+        // connections to the demoted master are dropped, and new ones go to the promoted replica
+        let after = Uuid::new_v4().to_string();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while let Err(err) = store.save(&after, &Session::new("alice", None, unix_now())) {
+            assert!(
+                Instant::now() < deadline,
+                "No master after failover: {}",
+                err
+            );
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        assert!(store.load(&before).unwrap().is_some());
+        assert!(store.load(&after).unwrap().is_some());
+        store.delete(&before).unwrap();
+        store.delete(&after).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn cluster() {
+        let nodes = env_list("WATCHDAWG_TEST_REDIS_CLUSTER");
+        let store = RedisStore::new(RedisTarget::Cluster(nodes), 3600).unwrap();
+        check_store(&store);
+        check_limit(&store);
+
+        // enough sessions to land on every primary
+        let session_ids = (0..64)
+            .map(|_| Uuid::new_v4().to_string())
+            .collect::<Vec<_>>();
+        for session_id in &session_ids {
+            store
+                .save(session_id, &Session::new("alice", None, unix_now()))
+                .unwrap();
+        }
+        let listed = store.list().unwrap();
+        let iterated = store
+            .iter()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for session_id in &session_ids {
+            assert!(listed.iter().any(|(listed, _)| listed == session_id));
+            assert!(iterated.iter().any(|(listed, _)| listed == session_id));
+            store.delete(session_id).unwrap();
+        }
+    }
+
+    #[test]
+    fn cluster_primaries() {
+        let nodes = "\
+07c3 127.0.0.1:30004@31004 slave e7d1 0 1426238317239 4 connected
+67ed 127.0.0.1:30002@31002,node-b master - 0 1426238316232 2 connected 5461-10922
+292f 127.0.0.1:30003@31003 master - 0 1426238318243 3 connected 10923-16383
+6ec2 127.0.0.1:30005@31005 master,fail - 1426238316232 0 5 disconnected 0-5460
+e7d1 ::1:30001@31001 myself,master - 0 0 1 connected 0-5460
+a1b2 127.0.0.1:30006@31006 master - 0 1426238316232 6 connected
+";
+        assert_eq!(
+            primaries(nodes),
+            [("127.0.0.1", 30002), ("127.0.0.1", 30003), ("::1", 30001)]
+        );
+    }
+
+    #[test]
+    fn error_classes() {
+        let dropped = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let unavailable = [
+            RedisError::from(dropped),
+            RedisError::from((ErrorKind::BusyLoadingError, "loading")),
+            RedisError::from((ErrorKind::ReadOnly, "replica")),
+            RedisError::from((ErrorKind::ClusterDown, "down")),
+        ];
+        for err in unavailable {
+            assert!(StoreError::from(err).is_unavailable());
+        }
+        let failed = [
+            RedisError::from((ErrorKind::TypeError, "WRONGTYPE")),
+            RedisError::from((ErrorKind::ResponseError, "NOPERM")),
+            RedisError::from((ErrorKind::AuthenticationFailed, "WRONGPASS")),
+        ];
+        for err in failed {
+            assert!(matches!(StoreError::from(err), StoreError::Failed(_)));
+        }
+    }
+}
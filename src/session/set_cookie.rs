@@ -0,0 +1,172 @@
+use concat_string::concat_string;
+use serde::Deserialize;
+use thiserror::Error;
+
+const HOST_PREFIX: &str = "__Host-";
+const SECURE_PREFIX: &str = "__Secure-";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CookiePrefix {
+    #[serde(rename = "__Host-")]
+    Host,
+    #[serde(rename = "__Secure-")]
+    Secure,
+}
+
+/// Attributes of the session cookie other than its name and value
+#[derive(Default)]
+pub struct CookieOptions {
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub partitioned: bool,
+    pub prefix: Option<CookiePrefix>,
+}
+
+/// Generates the `Set-Cookie` header values of the session cookie
+pub struct SessionCookie {
+    /// The cookie name including the prefix
+    pub name: String,
+    max_age: String,
+    attributes: String,
+}
+
+impl SessionCookie {
+    /// Validate the cookie name and attributes, the combination required by the prefix is checked
+    pub fn new(name: &str, options: CookieOptions, max_age: u64) -> Result<Self, CookieError> {
+        let name = match options.prefix {
+            Some(CookiePrefix::Host) => concat_string!(HOST_PREFIX, name),
+            Some(CookiePrefix::Secure) => concat_string!(SECURE_PREFIX, name),
+            None => name.to_string(),
+        };
+        if !is_token(&name) {
+            return Err(CookieError::InvalidName(name));
+        }
+
+        let lowercase = name.to_ascii_lowercase();
+        let is_host = lowercase.starts_with(&HOST_PREFIX.to_ascii_lowercase());
+        let is_secure = is_host || lowercase.starts_with(&SECURE_PREFIX.to_ascii_lowercase());
+        if is_secure && !options.secure {
+            return Err(CookieError::Prefix(name, "requires `Secure`"));
+        }
+        if is_host && (options.domain.is_some() || options.path.as_deref().unwrap_or("/") != "/") {
+            return Err(CookieError::Prefix(
+                name,
+                "requires `Path=/` and no `Domain`",
+            ));
+        }
+        if options.same_site == Some(SameSite::None) && !options.secure {
+            return Err(CookieError::Attribute("`SameSite=None` requires `Secure`"));
+        }
+        if options.partitioned && !options.secure {
+            return Err(CookieError::Attribute("`Partitioned` requires `Secure`"));
+        }
+
+        let mut attributes = String::new();
+        let path = match is_host {
+            true => Some("/"),
+            false => options.path.as_deref(),
+        };
+        if let Some(path) = path {
+            if !is_attribute_value(path) {
+                return Err(CookieError::Attribute("invalid `Path`"));
+            }
+            attributes.push_str(&concat_string!("; Path=", path));
+        }
+        if let Some(domain) = &options.domain {
+            if !is_attribute_value(domain) {
+                return Err(CookieError::Attribute("invalid `Domain`"));
+            }
+            attributes.push_str(&concat_string!("; Domain=", domain));
+        }
+        if options.secure {
+            attributes.push_str("; Secure");
+        }
+        attributes.push_str("; HttpOnly");
+        match options.same_site {
+            Some(SameSite::Strict) => attributes.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => attributes.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => attributes.push_str("; SameSite=None"),
+            None => {}
+        }
+        if options.partitioned {
+            attributes.push_str("; Partitioned");
+        }
+
+        Ok(Self {
+            name,
+            max_age: max_age.to_string(),
+            attributes,
+        })
+    }
+
+    /// The `Set-Cookie` value to give the session to the client
+    pub fn set(&self, session_id: &str) -> String {
+        concat_string!(
+            self.name,
+            "=",
+            session_id,
+            "; Max-Age=",
+            self.max_age,
+            self.attributes
+        )
+    }
+
+    /// The `Set-Cookie` value to remove the session cookie from the client
+    pub fn clear(&self) -> String {
+        concat_string!(self.name, "=; Max-Age=0", self.attributes)
+    }
+}
+
+/// `token` of RFC 2616, which is the syntax of cookie names in RFC 6265
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|byte| {
+            byte.is_ascii_graphic()
+                && !matches!(
+                    byte,
+                    b'(' | b')'
+                        | b'<'
+                        | b'>'
+                        | b'@'
+                        | b','
+                        | b';'
+                        | b':'
+                        | b'\\'
+                        | b'"'
+                        | b'/'
+                        | b'['
+                        | b']'
+                        | b'?'
+                        | b'='
+                        | b'{'
+                        | b'}'
+                )
+        })
+}
+
+/// Any CHAR except CTLs or `;` per RFC 6265
+fn is_attribute_value(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| (byte == b' ' || byte.is_ascii_graphic()) && byte != b';')
+}
+
+#[derive(Error, Debug)]
+pub enum CookieError {
+    #[error("Cookie name `{0}` is not a valid RFC 6265 cookie name")]
+    InvalidName(String),
+    #[error("Cookie name `{0}` {1}")]
+    Prefix(String, &'static str),
+    #[error("Invalid cookie attribute: {0}")]
+    Attribute(&'static str),
+}
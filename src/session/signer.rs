@@ -0,0 +1,131 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use concat_string::concat_string;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+const SEPARATOR: char = '.';
+/// Secrets shorter than this are too easy to guess, the length of the HMAC-SHA256 key
+const MIN_SECRET_LEN: usize = 32;
+
+/// Sign session IDs with HMAC-SHA256, so forged IDs are rejected before looking up the store
+///
+/// The first secret is used for signing, all secrets are tried for verifying, so a new secret can
+/// be put in front of the old ones to rotate secrets without logging everyone out.
+pub struct SessionSigner {
+    macs: Vec<Hmac<Sha256>>,
+}
+
+impl SessionSigner {
+    /// Create from the secrets, the first one is the current secret
+    pub fn new<S: AsRef<str>>(secrets: &[S]) -> Result<Self, SecretError> {
+        if secrets.is_empty() {
+            return Err(SecretError::Empty);
+        }
+        let mut macs = Vec::with_capacity(secrets.len());
+        for (index, secret) in secrets.iter().enumerate() {
+            let secret = secret.as_ref().as_bytes();
+            if secret.len() < MIN_SECRET_LEN {
+                return Err(SecretError::TooShort(index));
+            }
+            macs.push(Hmac::<Sha256>::new_from_slice(secret).unwrap());
+        }
+        Ok(Self { macs })
+    }
+
+    /// Append the tag to the session ID
+    pub fn sign(&self, session_id: &str) -> String {
+        let mut mac = self.macs[0].clone();
+        mac.update(session_id.as_bytes());
+        let tag = BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        concat_string!(session_id, SEPARATOR.to_string(), tag)
+    }
+
+    /// Return the session ID if the tag is valid, tags are compared in constant time
+    pub fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+        let (session_id, tag) = signed.rsplit_once(SEPARATOR)?;
+        let tag = BASE64_URL_SAFE_NO_PAD.decode(tag).ok()?;
+        self.macs
+            .iter()
+            .any(|mac| {
+                let mut mac = mac.clone();
+                mac.update(session_id.as_bytes());
+                mac.verify_slice(&tag).is_ok()
+            })
+            .then_some(session_id)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("At least one session secret is required")]
+    Empty,
+    #[error("Session secret #{0} should be at least 32 bytes long")]
+    TooShort(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_SECRET: &str = "an old secret of at least 32 bytes";
+    const NEW_SECRET: &str = "the new secret of at least 32 bytes";
+    const SESSION_ID: &str = "0b6c2f3e-5a8e-4d1f-9a7c-3f2e1d0c9b8a";
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = SessionSigner::new(&[NEW_SECRET]).unwrap();
+        let signed = signer.sign(SESSION_ID);
+        assert!(signed.starts_with(concat_string!(SESSION_ID, ".").as_str()));
+        assert_eq!(signer.verify(&signed), Some(SESSION_ID));
+        // the same ID always gets the same tag
+        assert_eq!(signer.sign(SESSION_ID), signed);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let signer = SessionSigner::new(&[NEW_SECRET]).unwrap();
+        let signed = signer.sign(SESSION_ID);
+        let (_, tag) = signed.rsplit_once(SEPARATOR).unwrap();
+        let other = SessionSigner::new(&[OLD_SECRET]).unwrap();
+        let mut flipped = tag.to_string();
+        let first = if flipped.starts_with('A') { "B" } else { "A" };
+        flipped.replace_range(..1, first);
+        for forged in [
+            SESSION_ID.to_string(),
+            concat_string!(SESSION_ID, "."),
+            concat_string!("another-id.", tag),
+            concat_string!(SESSION_ID, ".", flipped),
+            concat_string!(SESSION_ID, ".", &tag[..tag.len() - 2]),
+            concat_string!(SESSION_ID, ".!", tag),
+            other.sign(SESSION_ID),
+        ] {
+            assert_eq!(signer.verify(&forged), None, "{}", forged);
+        }
+    }
+
+    #[test]
+    fn verifies_with_rotated_secrets() {
+        let old = SessionSigner::new(&[OLD_SECRET]).unwrap();
+        let rotated = SessionSigner::new(&[NEW_SECRET, OLD_SECRET]).unwrap();
+        let signed = old.sign(SESSION_ID);
+        assert_eq!(rotated.verify(&signed), Some(SESSION_ID));
+        // new IDs are signed with the new secret only
+        let signed = rotated.sign(SESSION_ID);
+        assert_eq!(old.verify(&signed), None);
+        let new = SessionSigner::new(&[NEW_SECRET]).unwrap();
+        assert_eq!(new.verify(&signed), Some(SESSION_ID));
+    }
+
+    #[test]
+    fn rejects_short_secrets() {
+        assert!(matches!(
+            SessionSigner::new::<&str>(&[]),
+            Err(SecretError::Empty)
+        ));
+        assert!(matches!(
+            SessionSigner::new(&[NEW_SECRET, "short"]),
+            Err(SecretError::TooShort(1))
+        ));
+    }
+}
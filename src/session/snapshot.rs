@@ -0,0 +1,177 @@
+use super::Session;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Error, ErrorKind, Result, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use uuid::Uuid;
+
+const MAGIC: &str = "WATCHDAWG-SESSIONS-V2";
+/// Snapshots of sessions which only had a creation time, from before sessions belonged to a user
+const LEGACY_MAGIC: &str = "WATCHDAWG-SESSIONS-V1";
+
+/// Write the sessions to `path` atomically, the file is first written to a temporary file next to
+/// it and then renamed, so a crash while writing never leaves a truncated snapshot behind. The
+/// session IDs let anyone reading them in, so only the owner can read the file, and the temporary
+/// file has a name of its own which must not exist yet rather than following a link left there.
+pub fn write(path: impl AsRef<Path>, entries: &[(String, Session)]) -> Result<()> {
+    let path = path.as_ref();
+    let mut body = String::new();
+    for (session_id, session) in entries {
+        body.push_str(session_id);
+        body.push(' ');
+        body.push_str(&serde_json::to_string(session)?);
+        body.push('\n');
+    }
+    let checksum = crc32fast::hash(body.as_bytes());
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{}.tmp", Uuid::new_v4()));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            writeln!(file, "{} {:08x}", MAGIC, checksum)?;
+            file.write_all(body.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp_path, path));
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    // the rename is only durable once the directory is synced too
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Read the sessions from the snapshot at `path`, return an error if the checksum mismatch
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<(String, Session)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    if header.starts_with(LEGACY_MAGIC) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Snapshot of an older watchdawg, its sessions have no user and are not restored",
+        ));
+    }
+    let checksum = header
+        .trim_end()
+        .strip_prefix(MAGIC)
+        .and_then(|rest| u32::from_str_radix(rest.trim(), 16).ok())
+        .ok_or(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid snapshot header",
+        ))?;
+
+    let mut body = String::new();
+    std::io::Read::read_to_string(&mut reader, &mut body)?;
+    if crc32fast::hash(body.as_bytes()) != checksum {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Snapshot checksum mismatch",
+        ));
+    }
+
+    let mut entries = Vec::new();
+    for line in body.lines() {
+        let Some((session_id, session)) = line.split_once(' ') else {
+            continue;
+        };
+        if let Ok(session) = serde_json::from_str::<Session>(session) {
+            entries.push((session_id.to_string(), session));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+    /// A directory of its own for the snapshot of a test, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("watchdawg-{}", Uuid::new_v4()));
+            std::fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entries() -> Vec<(String, Session)> {
+        let mut bob = Session::new("bob", Some([10, 0, 0, 1].into()), 1000);
+        bob.user_agent = Some("curl/8.0".to_string());
+        vec![
+            ("first".to_string(), Session::new("alice", None, 1000)),
+            ("second".to_string(), bob),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let path = dir.0.join("sessions");
+        write(&path, &entries()).unwrap();
+        let read = read(&path).unwrap();
+        assert_eq!(read.len(), 2);
+        for ((id, session), (read_id, read_session)) in entries().iter().zip(&read) {
+            assert_eq!(id, read_id);
+            assert_eq!(
+                serde_json::to_string(session).unwrap(),
+                serde_json::to_string(read_session).unwrap()
+            );
+        }
+        // only the snapshot is left, readable by its owner only
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+
+        write(&path, &[]).unwrap();
+        assert!(super::read(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let dir = TempDir::new();
+        let path = dir.0.join("sessions");
+        write(&path, &entries()).unwrap();
+        let snapshot = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, snapshot.replace("alice", "mallory")).unwrap();
+        let err = read(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Snapshot checksum mismatch");
+
+        std::fs::write(&path, snapshot.lines().next().unwrap()).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn rejects_other_snapshots() {
+        let dir = TempDir::new();
+        let path = dir.0.join("sessions");
+        std::fs::write(&path, "WATCHDAWG-SESSIONS-V1 00000000\nfirst 1000\n").unwrap();
+        assert!(read(&path).is_err());
+        std::fs::write(&path, "something else\n").unwrap();
+        assert_eq!(
+            read(&path).unwrap_err().to_string(),
+            "Invalid snapshot header"
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use super::{
+    blocking, limit::UserLimit, paged, unix_now, Session, SessionIter, SessionStore, StoreError,
+    PAGE_SIZE,
+};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::rusqlite::{
+    self, params, ErrorCode, OptionalExtension, Transaction, TransactionBehavior,
+};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::{
+    path::Path,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tracing::{debug, error};
+
+/// Each migration brings the schema to the next version, the applied version is kept in
+/// `watchdawg_schema`
+const MIGRATIONS: &[&str] = &["CREATE TABLE watchdawg_sessions (
+        id TEXT PRIMARY KEY,
+        user_name TEXT NOT NULL,
+        expires_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX watchdawg_sessions_user ON watchdawg_sessions (user_name);
+    CREATE INDEX watchdawg_sessions_expires ON watchdawg_sessions (expires_at);"];
+
+/// Each session is a row holding the session as JSON, which expires `max_age` seconds after it was
+/// created. Expired rows are ignored and deleted periodically.
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
+    max_age: u64,
+}
+
+impl SqliteStore {
+    /// Open the database at `path` and migrate its schema, `:memory:` gives a database which only
+    /// lives as long as the store
+    pub fn new(
+        path: impl AsRef<Path>,
+        max_age: u64,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let path = path.as_ref();
+        let init = |conn: &mut rusqlite::Connection| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+        };
+        // every connection to `:memory:` is a database of its own
+        let pool = match path == Path::new(":memory:") {
+            true => Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory().with_init(init))?,
+            false => Pool::new(SqliteConnectionManager::file(path).with_init(init))?,
+        };
+        let store = Self { pool, max_age };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&self) -> Result<(), StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "CREATE TABLE IF NOT EXISTS watchdawg_schema (version INTEGER NOT NULL)",
+                [],
+            )?;
+            let version = schema_version(&tx)?;
+            for (version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                debug!("Migrating SQLite session schema to version {}", version + 1);
+                tx.execute_batch(migration)?;
+            }
+            tx.execute("DELETE FROM watchdawg_schema", [])?;
+            tx.execute(
+                "INSERT INTO watchdawg_schema (version) VALUES (?1)",
+                [MIGRATIONS.len() as i64],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Run `f` with a pooled connection, the worker thread is handed over to other tasks while
+    /// SQLite blocks
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut PooledConnection<SqliteConnectionManager>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        blocking(|| f(&mut self.pool.get()?))
+    }
+
+    /// Delete the expired sessions, return the number of sessions deleted
+    pub fn delete_expired(&self) -> Result<usize, StoreError> {
+        self.with_conn(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM watchdawg_sessions WHERE expires_at <= ?1",
+                [unix_now() as i64],
+            )?)
+        })
+    }
+
+    /// Delete the expired sessions every `interval` in a background thread, the thread stops once
+    /// the store is dropped
+    pub fn spawn_cleanup_task(self: &Arc<Self>, interval: Duration) {
+        let store: Weak<Self> = Arc::downgrade(self);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            match store.delete_expired() {
+                Ok(deleted) => debug!("Deleted {} expired sessions", deleted),
+                Err(err) => error!("Failed to delete expired sessions: {}", err),
+            }
+        });
+    }
+
+    fn query_sessions(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<(String, Session)>, StoreError> {
+        let rows = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(sql)?;
+            let rows = stmt
+                .query_map(params, |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+            Ok(rows)
+        })?;
+        // skip records which cannot be read rather than failing the whole listing
+        Ok(rows
+            .into_iter()
+            .filter_map(|(session_id, data)| Some((session_id, serde_json::from_str(&data).ok()?)))
+            .collect())
+    }
+}
+
+fn schema_version(tx: &Transaction) -> rusqlite::Result<usize> {
+    let version = tx
+        .query_row("SELECT version FROM watchdawg_schema", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .optional()?;
+    Ok(version.unwrap_or(0) as usize)
+}
+
+impl SessionStore for SqliteStore {
+    fn load(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let data = self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT data FROM watchdawg_sessions WHERE id = ?1 AND expires_at > ?2",
+                    params![session_id, unix_now() as i64],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()?)
+        })?;
+        match data {
+            Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+            None => Ok(None),
+        }
+    }
+    fn save(&self, session_id: &str, session: &Session) -> Result<(), StoreError> {
+        let expires_at = session.created_at.saturating_add(self.max_age);
+        let data = serde_json::to_string(session)?;
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO watchdawg_sessions (id, user_name, expires_at, data)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (id) DO UPDATE SET
+                user_name = excluded.user_name,
+                expires_at = excluded.expires_at,
+                data = excluded.data",
+                params![session_id, session.user, expires_at as i64, data],
+            )?;
+            Ok(())
+        })
+    }
+    fn update(&self, session_id: &str, session: &Session) -> Result<bool, StoreError> {
+        let data = serde_json::to_string(session)?;
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "UPDATE watchdawg_sessions SET user_name = ?2, data = ?3
+            WHERE id = ?1 AND expires_at > ?4",
+                params![session_id, session.user, data, unix_now() as i64],
+            )?)
+        })?;
+        Ok(updated > 0)
+    }
+    fn create(
+        &self,
+        session_id: &str,
+        session: &Session,
+        limit: Option<&UserLimit>,
+    ) -> Result<Option<Vec<String>>, StoreError> {
+        let data = serde_json::to_string(session)?;
+        self.with_conn(|conn| {
+            let now = unix_now();
+            // the write lock is taken up front, so logins are counted one after another
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let evicted = match limit {
+                Some(limit) => {
+                    let sessions = tx
+                        .prepare(
+                            "SELECT id, data FROM watchdawg_sessions
+                        WHERE user_name = ?1 AND expires_at > ?2",
+                        )?
+                        .query_map(params![session.user, now as i64], |row| {
+                            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?
+                        .into_iter()
+                        .filter_map(|(session_id, data)| {
+                            Some((session_id, serde_json::from_str(&data).ok()?))
+                        })
+                        .collect();
+                    match limit.evictions(sessions, now) {
+                        Some(evicted) => evicted,
+                        None => return Ok(None),
+                    }
+                }
+                None => Vec::new(),
+            };
+            for session_id in &evicted {
+                tx.execute("DELETE FROM watchdawg_sessions WHERE id = ?1", [session_id])?;
+            }
+            tx.execute(
+                "INSERT INTO watchdawg_sessions (id, user_name, expires_at, data)
+            VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    session_id,
+                    session.user,
+                    session.created_at.saturating_add(self.max_age) as i64,
+                    data
+                ],
+            )?;
+            tx.commit()?;
+            Ok(Some(evicted))
+        })
+    }
+    fn delete(&self, session_id: &str) -> Result<Option<Session>, StoreError> {
+        let data = self.with_conn(|conn| {
+            Ok(conn
+                .query_row(
+                    "DELETE FROM watchdawg_sessions WHERE id = ?1 RETURNING data, expires_at",
+                    [session_id],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()?)
+        })?;
+        match data {
+            Some((data, expires_at)) if expires_at as u64 > unix_now() => {
+                Ok(Some(serde_json::from_str(&data)?))
+            }
+            _ => Ok(None),
+        }
+    }
+    fn list(&self) -> Result<Vec<(String, Session)>, StoreError> {
+        self.query_sessions(
+            "SELECT id, data FROM watchdawg_sessions WHERE expires_at > ?1",
+            [unix_now() as i64],
+        )
+    }
+    fn iter(&self) -> Result<SessionIter<'_>, StoreError> {
+        Ok(paged(|after| {
+            self.with_conn(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, data FROM watchdawg_sessions WHERE expires_at > ?1 AND id > ?2
+                ORDER BY id LIMIT ?3",
+                )?;
+                let records = stmt
+                    .query_map(params![unix_now() as i64, after, PAGE_SIZE as i64], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?
+                    .collect::<Result<_, _>>()?;
+                Ok(records)
+            })
+        }))
+    }
+    fn user_sessions(&self, user: &str) -> Result<Vec<(String, Session)>, StoreError> {
+        self.query_sessions(
+            "SELECT id, data FROM watchdawg_sessions WHERE user_name = ?1 AND expires_at > ?2",
+            params![user, unix_now() as i64],
+        )
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    /// Only a database which is locked or cannot be opened or read is unavailable, other errors
+    /// such as a constraint violation or a read-only database do not go away by waiting
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(
+                ErrorCode::DatabaseBusy
+                | ErrorCode::DatabaseLocked
+                | ErrorCode::CannotOpen
+                | ErrorCode::SystemIoFailure,
+            ) => Self::Unavailable(err.into()),
+            _ => Self::Failed(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::{check_limit, check_store};
+    use rusqlite::ffi;
+    use uuid::Uuid;
+
+    #[test]
+    fn error_classes() {
+        let busy = rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_BUSY), None);
+        assert!(StoreError::from(busy).is_unavailable());
+        let store = SqliteStore::new(":memory:", 3600).unwrap();
+        let err = store
+            .with_conn(|conn| Ok(conn.execute("INSERT INTO missing VALUES (1)", [])?))
+            .unwrap_err();
+        assert!(!err.is_unavailable());
+    }
+
+    #[test]
+    fn in_memory() {
+        let store = SqliteStore::new(":memory:", 3600).unwrap();
+        check_store(&store);
+        check_limit(&store);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("watchdawg-{}.db", Uuid::new_v4()));
+        let session = Session::new("alice", None, unix_now());
+        SqliteStore::new(&path, 3600)
+            .unwrap()
+            .save("kept", &session)
+            .unwrap();
+        // the schema is already there when the database is opened again
+        let store = SqliteStore::new(&path, 3600).unwrap();
+        assert!(store.load("kept").unwrap().is_some());
+        check_store(&store);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn on_server_runtime() {
+        let store = SqliteStore::new(":memory:", 3600).unwrap();
+        check_store(&store);
+    }
+}
@@ -1,12 +1,25 @@
-use crate::{auth::Authenticator, session::SessionManager};
-use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use crate::{
+    auth::Authenticator,
+    forwarded::TrustedProxies,
+    session::{
+        binding::ClientInfo, health::OutagePolicy, SessionError, SessionManager, StoreError,
+        ValidSession,
+    },
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{
     body::Bytes,
-    header::{AUTHORIZATION, COOKIE, WWW_AUTHENTICATE},
-    HeaderMap, Response, StatusCode,
+    header::{
+        HeaderValue, AUTHORIZATION, CONTENT_TYPE, COOKIE, SET_COOKIE, USER_AGENT, WWW_AUTHENTICATE,
+    },
+    HeaderMap, Request, Response, StatusCode,
 };
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use std::{io::BufReader, path::Path};
+use sha2::{Digest, Sha256};
+use std::{io::BufReader, net::SocketAddr, path::Path, sync::Arc};
+use tracing::{error, warn};
 
 pub fn req_auth() -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
@@ -16,6 +29,69 @@ pub fn req_auth() -> Response<BoxBody<Bytes, hyper::Error>> {
         .unwrap()
 }
 
+/// Request authentication again and clear the session cookie
+pub fn logout(session_manager: &SessionManager) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = req_auth();
+    if let Ok(cookie) = session_manager.cookie.clear().parse() {
+        resp.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    resp
+}
+
+/// Refuse the login since the user has reached the maximum number of sessions
+pub fn too_many_sessions() -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(full("Too many active sessions"))
+        .unwrap()
+}
+
+/// The session store is unavailable and the request cannot be authenticated
+pub fn service_unavailable() -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(full("Session store unavailable"))
+        .unwrap()
+}
+
+/// The session store failed in a way retrying does not fix
+pub fn internal_error() -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(full("Session store error"))
+        .unwrap()
+}
+
+/// No route matches the request
+pub fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(empty())
+        .unwrap()
+}
+
+/// The request could not be forwarded, answered with `page` if given, where `{status}` is
+/// replaced with the status
+pub fn gateway_error(
+    status: StatusCode,
+    page: Option<&str>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let text = format!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    let (content_type, body) = match page {
+        Some(page) => ("text/html; charset=utf-8", page.replace("{status}", &text)),
+        None => ("text/plain; charset=utf-8", text),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(full(body))
+        .unwrap()
+}
+
 pub fn ok_empty() -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
         .status(StatusCode::OK)
@@ -27,6 +103,10 @@ pub fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new().map_err(infallible_to_err).boxed()
 }
 
+pub fn full(chunk: impl Into<Bytes>) -> BoxBody<Bytes, hyper::Error> {
+    Full::new(chunk.into()).map_err(infallible_to_err).boxed()
+}
+
 /// Cast [`std::convert::Infallible`] to [`hyper::Error`]
 fn infallible_to_err(_: std::convert::Infallible) -> hyper::Error {
     unreachable!()
@@ -50,51 +130,479 @@ pub fn load_private_key(path: impl AsRef<Path>) -> std::io::Result<PrivateKeyDer
     ))
 }
 
-/// Get the session id with the provide cookie name
+/// Build the TLS config of the server, client certificates signed by `client_ca` are requested
+/// but not required if it is given
+pub fn tls_server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_ca: Option<Vec<CertificateDer<'static>>>,
+) -> Result<ServerConfig, rustls::Error> {
+    let builder = ServerConfig::builder();
+    let mut server_config = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in client_ca {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|err| rustls::Error::General(err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_single_cert(certs, key)?;
+
+    server_config.alpn_protocols.push(b"h2".to_vec());
+    server_config.alpn_protocols.push(b"http/1.1".to_vec());
+    server_config.alpn_protocols.push(b"http/1.0".to_vec());
+    Ok(server_config)
+}
+
+/// SHA-256 fingerprint of the certificate in hex
+pub fn cert_fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Collect what is known about the client sending the request
+pub fn client_info(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    client_cert: Option<&str>,
+    trusted_proxies: &TrustedProxies,
+) -> ClientInfo {
+    ClientInfo {
+        ip: peer.map(|peer| trusted_proxies.client_ip(peer.ip(), headers)),
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        client_cert: client_cert.map(str::to_string),
+    }
+}
+
+/// Iterate the cookie pairs in all `Cookie` headers, HTTP/2 clients may send one header per
+/// cookie. Pairs are separated by `;` and split at the first `=` as in RFC 6265, surrounding
+/// whitespace is ignored, and a value in double quotes is unquoted.
+pub fn cookies(headers: &HeaderMap) -> impl Iterator<Item = (&[u8], &[u8])> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|header| header.as_bytes().split(|&byte| byte == b';'))
+        .filter_map(parse_cookie_pair)
+}
+
+fn parse_cookie_pair(pair: &[u8]) -> Option<(&[u8], &[u8])> {
+    let separator = pair.iter().position(|&byte| byte == b'=')?;
+    let name = pair[..separator].trim_ascii();
+    if name.is_empty() {
+        return None;
+    }
+    let value = pair[separator + 1..].trim_ascii();
+    let value = match value {
+        [b'"', quoted @ .., b'"'] => quoted,
+        value => value,
+    };
+    Some((name, value))
+}
+
+/// Get the values of the cookies with the provided name, in the order the client sent them
 pub fn get_session_from_cookie<'a>(
-    cookie_name: &[u8],
-    cookie_header: &'a [u8],
-) -> Option<&'a [u8]> {
-    for cookie in cookie_header.split(|&part| part == b';') {
-        let trimed = if cookie.starts_with(b" ") {
-            &cookie[1..]
-        } else {
-            &cookie[0..]
-        };
+    cookie_name: &'a str,
+    headers: &'a HeaderMap,
+) -> impl Iterator<Item = &'a str> {
+    cookies(headers)
+        .filter(move |(name, _)| *name == cookie_name.as_bytes())
+        .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+}
 
-        if trimed.starts_with(cookie_name) {
-            let mut splited = cookie.splitn(2, |&byte| byte == b'=').skip(1); // the first one is cookie name
-            return splited.next();
+/// Remove the cookies with the provided name, the other cookies are merged into a single `Cookie`
+/// header, which is required when forwarding HTTP/2 requests to HTTP/1.1
+pub fn strip_cookie(headers: &mut HeaderMap, cookie_name: &str) {
+    if !cookies(headers).any(|(name, _)| name == cookie_name.as_bytes()) {
+        return;
+    }
+    let mut kept = Vec::new();
+    let pairs = headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|header| header.as_bytes().split(|&byte| byte == b';'))
+        .map(<[u8]>::trim_ascii)
+        .filter(|pair| !pair.is_empty());
+    for pair in pairs {
+        if parse_cookie_pair(pair).is_some_and(|(name, _)| name == cookie_name.as_bytes()) {
+            continue;
+        }
+        // keep the pair as sent, so quoted values reach the upstream unchanged
+        if !kept.is_empty() {
+            kept.extend_from_slice(b"; ");
+        }
+        kept.extend_from_slice(pair);
+    }
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::from_bytes(&kept) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, value);
         }
     }
-    None
 }
 
-
-/// Return the session id if there is a valid session
+/// Return the session id and the session if there is a valid session, every cookie with the
+/// session cookie name is tried since a stale cookie with another path or domain may come first
 pub fn headers_has_valid_session<'a>(
     headers: &'a HeaderMap,
+    session_manager: &'a SessionManager,
+    client: &ClientInfo,
+) -> Result<Option<(&'a str, ValidSession)>, StoreError> {
+    for session_id in get_session_from_cookie(&session_manager.cookie.name, headers) {
+        if let Some(session) = session_manager.validate_session(session_id, client)? {
+            return Ok(Some((session_id, session)));
+        }
+    }
+    Ok(None)
+}
+
+/// The result of a request passed authentication
+pub struct Authenticated {
+    pub user: String,
+    /// The `Set-Cookie` value to send if a session was created or its ID rotated
+    pub set_cookie: Option<String>,
+}
+
+/// Authenticate the request by its session or its `Authorization` header, a new session is
+/// created on login, or when the credentials of another user are presented with an existing
+/// session. Return the response to send instead if the request is not allowed through.
+pub fn authenticate<B>(
+    req: &Request<B>,
+    session_manager: &SessionManager,
+    authenticator: &dyn Authenticator,
+    client: &ClientInfo,
+) -> Result<Authenticated, Box<Response<BoxBody<Bytes, hyper::Error>>>> {
+    let headers = req.headers();
+
+    let session = match headers_has_valid_session(headers, session_manager, client) {
+        Ok(session) => session,
+        Err(err) => return store_unavailable(headers, session_manager, authenticator, err),
+    };
+    if let Some((session_id, valid)) = session {
+        if is_logout(req, session_manager) {
+            // never tell the client it is logged out while the session is still valid
+            if let Err(err) = session_manager.logout(session_id) {
+                error!("Failed to log out: {}", err);
+                return Err(match err.is_unavailable() {
+                    true => service_unavailable(),
+                    false => internal_error(),
+                }
+                .into());
+            }
+            return Err(logout(session_manager).into());
+        }
+
+        // a different user logging in on the same client is a privilege change, the old session
+        // is dropped so its ID can never carry the new privilege
+        let switched = auth_header_user(headers)
+            .filter(|user| *user != valid.session.user)
+            .and_then(|_| header_has_valid_auth(headers, authenticator));
+        let Some(user) = switched else {
+            let renewed = match valid.renewed {
+                Some(renewed) => Some(renewed),
+                None if is_reauth(req, session_manager) => {
+                    if header_has_valid_auth(headers, authenticator).as_ref()
+                        != Some(&valid.session.user)
+                    {
+                        return Err(req_auth().into());
+                    }
+                    // re-authentication is a privilege change, the session gets a new ID
+                    match session_manager.rotate(session_id) {
+                        Ok(Some(renewed)) => Some(renewed),
+                        // revoked meanwhile, the credentials were just verified though
+                        Ok(None) => {
+                            return login(
+                                headers,
+                                session_manager,
+                                authenticator,
+                                &valid.session.user,
+                                client,
+                            )
+                        }
+                        Err(err) => {
+                            return store_unavailable(headers, session_manager, authenticator, err)
+                        }
+                    }
+                }
+                None => None,
+            };
+            return Ok(Authenticated {
+                user: valid.session.user,
+                set_cookie: renewed.map(|session_id| session_manager.cookie.set(&session_id)),
+            });
+        };
+        if let Err(err) = session_manager.logout(session_id) {
+            return store_unavailable(headers, session_manager, authenticator, err);
+        }
+        return login(headers, session_manager, authenticator, &user, client);
+    }
+
+    // if there's not valid session, then check if there's valid authentication
+    match header_has_valid_auth(headers, authenticator) {
+        Some(user) => login(headers, session_manager, authenticator, &user, client),
+        // a session cookie which is no longer valid is cleared, so the client logs in afresh
+        None if get_session_from_cookie(&session_manager.cookie.name, headers)
+            .next()
+            .is_some() =>
+        {
+            Err(logout(session_manager).into())
+        }
+        // else return unauthroized and reqest authentication
+        None => Err(req_auth().into()),
+    }
+}
+
+/// Give a new session to the user, unless the user has too many sessions
+fn login(
+    headers: &HeaderMap,
     session_manager: &SessionManager,
-) -> Option<&'a str> {
-    let session_id = headers
-        .get(COOKIE)
-        .and_then(|cookie_header| {
-            get_session_from_cookie(
-                session_manager.cookie_name.as_bytes(),
-                cookie_header.as_bytes(),
-            )
-        })
-        .and_then(|session_bytes| std::str::from_utf8(session_bytes).ok())?;
-    match session_manager.is_session_valid(session_id) {
-        true => Some(session_id),
-        false => None,
-    }
-}
-
-/// Return true if there is a valid authentication
-pub fn header_has_valid_auth(headers: &HeaderMap, authenticator: &dyn Authenticator) -> bool {
+    authenticator: &dyn Authenticator,
+    user: &str,
+    client: &ClientInfo,
+) -> Result<Authenticated, Box<Response<BoxBody<Bytes, hyper::Error>>>> {
+    match session_manager.create_session(user, client) {
+        Ok(session_id) => Ok(Authenticated {
+            user: user.to_string(),
+            set_cookie: Some(session_manager.cookie.set(&session_id)),
+        }),
+        Err(SessionError::Store(err)) => {
+            store_unavailable(headers, session_manager, authenticator, err)
+        }
+        Err(_) => Err(too_many_sessions().into()),
+    }
+}
+
+/// Handle the request according to the outage policy when the session store is unavailable,
+/// other store errors are answered with `500 Internal Server Error`
+fn store_unavailable(
+    headers: &HeaderMap,
+    session_manager: &SessionManager,
+    authenticator: &dyn Authenticator,
+    err: StoreError,
+) -> Result<Authenticated, Box<Response<BoxBody<Bytes, hyper::Error>>>> {
+    if !err.is_unavailable() {
+        error!("{}", err);
+        return Err(internal_error().into());
+    }
+    warn!("{}", err);
+    match session_manager.outage_policy {
+        // without a session the browser sends the credentials again on every request
+        OutagePolicy::BasicAuth => match header_has_valid_auth(headers, authenticator) {
+            Some(user) => Ok(Authenticated {
+                user,
+                set_cookie: None,
+            }),
+            None => Err(req_auth().into()),
+        },
+        OutagePolicy::FailClosed | OutagePolicy::Memory => Err(service_unavailable().into()),
+    }
+}
+
+/// Return the user name in the `Authorization: Basic` header without verifying the password
+fn auth_header_user(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .as_bytes()
+        .strip_prefix(b"Basic ")?;
+    let credentials = BASE64_STANDARD.decode(encoded).ok()?;
+    let user = credentials.split(|&byte| byte == b':').next()?;
+    String::from_utf8(user.to_vec()).ok()
+}
+
+/// Return true if the request is for the logout path
+pub fn is_logout<B>(req: &Request<B>, session_manager: &SessionManager) -> bool {
+    session_manager
+        .logout_path
+        .as_deref()
+        .map(|path| req.uri().path() == path)
+        .unwrap_or(false)
+}
+
+/// Return true if the request is for the re-authentication path
+pub fn is_reauth<B>(req: &Request<B>, session_manager: &SessionManager) -> bool {
+    session_manager
+        .reauth_path
+        .as_deref()
+        .is_some_and(|path| req.uri().path() == path)
+}
+
+/// Return the user name if there is a valid authentication
+pub fn header_has_valid_auth(
+    headers: &HeaderMap,
+    authenticator: &dyn Authenticator,
+) -> Option<String> {
     headers
         .get(AUTHORIZATION)
-        .map(|auth_header| authenticator.is_auth_header_valid(auth_header.as_bytes()))
-        .unwrap_or(false)
+        .and_then(|auth_header| authenticator.authenticate(auth_header.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(COOKIE, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+        cookies(headers)
+            .map(|(name, value)| {
+                (
+                    String::from_utf8_lossy(name).into_owned(),
+                    String::from_utf8_lossy(value).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn quoted_values() {
+        let headers = headers(&[r#"a="x y"; b=""; c="; d="e"f; e="i"j"; g = "h" "#]);
+        assert_eq!(
+            pairs(&headers),
+            [
+                pair("a", "x y"),
+                pair("b", ""),
+                pair("c", "\""),
+                pair("d", "\"e\"f"),
+                pair("e", "i\"j"),
+                pair("g", "h"),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let headers = headers(&[
+            "session_id=first; other=1; session_id=second",
+            "session_id=third",
+        ]);
+        let sessions = get_session_from_cookie("session_id", &headers).collect::<Vec<_>>();
+        assert_eq!(sessions, ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn empty_pairs() {
+        let headers = headers(&[";; a=1 ; ;=x; b; =; c=", ""]);
+        assert_eq!(pairs(&headers), [pair("a", "1"), pair("c", "")]);
+    }
+
+    #[test]
+    fn strip_merges_the_rest() {
+        let mut headers = headers(&[r#"a="x y"; session_id=1"#, "session_id=2", " b=2 ;; c"]);
+        strip_cookie(&mut headers, "session_id");
+        let stripped = headers.get_all(COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(stripped, [r#"a="x y"; b=2; c"#]);
+    }
+
+    #[test]
+    fn strip_only_cookie() {
+        let mut headers = headers(&["session_id=1", "session_id=\"2\""]);
+        strip_cookie(&mut headers, "session_id");
+        assert!(headers.get(COOKIE).is_none());
+    }
+
+    #[test]
+    fn strip_leaves_other_cookies_alone() {
+        let mut headers = headers(&["a=1;b=2", "c=3"]);
+        strip_cookie(&mut headers, "session_id");
+        let kept = headers.get_all(COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(kept, ["a=1;b=2", "c=3"]);
+    }
+
+    /// Bytes a `Cookie` header value may hold
+    fn header_value() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(
+            prop_oneof![
+                Just(b';'),
+                Just(b'='),
+                Just(b'"'),
+                Just(b' '),
+                Just(b'\t'),
+                0x21u8..0x7f,
+                0x80u8..=0xff,
+            ],
+            0..64,
+        )
+    }
+
+    fn token() -> impl Strategy<Value = String> {
+        "[!#$%&'*+.^_`|~0-9A-Za-z-]{1,12}"
+    }
+
+    /// Values as in RFC 6265, optionally quoted
+    fn cookie_value() -> impl Strategy<Value = (String, bool)> {
+        ("[!#-+\\--:<-\\[\\]-~]{0,12}", any::<bool>())
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_headers_never_panic(
+            values in proptest::collection::vec(header_value(), 0..4),
+            name in token(),
+        ) {
+            let mut headers = HeaderMap::new();
+            for value in &values {
+                headers.append(COOKIE, HeaderValue::from_bytes(value).unwrap());
+            }
+            let before = cookies(&headers)
+                .filter(|(cookie, _)| *cookie != name.as_bytes())
+                .map(|(cookie, value)| (cookie.to_vec(), value.to_vec()))
+                .collect::<Vec<_>>();
+            get_session_from_cookie(&name, &headers).count();
+
+            strip_cookie(&mut headers, &name);
+            prop_assert!(headers.get_all(COOKIE).iter().count() <= values.len());
+            prop_assert_eq!(get_session_from_cookie(&name, &headers).count(), 0);
+            let after = cookies(&headers)
+                .map(|(cookie, value)| (cookie.to_vec(), value.to_vec()))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(before, after);
+        }
+
+        #[test]
+        fn pairs_round_trip(
+            cookies in proptest::collection::vec((token(), cookie_value()), 1..8),
+            split in 0usize..8,
+            spaces in 0usize..3,
+        ) {
+            let separator = format!(";{}", " ".repeat(spaces));
+            let encoded = cookies
+                .iter()
+                .map(|(name, (value, quoted))| match quoted {
+                    true => format!("{}=\"{}\"", name, value),
+                    false => format!("{}={}", name, value),
+                })
+                .collect::<Vec<_>>();
+            // the pairs are spread over two headers as HTTP/2 clients do
+            let split = split.min(encoded.len());
+            let values = [encoded[..split].join(&separator), encoded[split..].join(&separator)];
+            let headers = headers(&values.iter().map(String::as_str).collect::<Vec<_>>());
+
+            let expected = cookies
+                .iter()
+                .map(|(name, (value, _))| pair(name, value))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(pairs(&headers), expected);
+        }
+    }
 }